@@ -0,0 +1,286 @@
+use ring::{digest, hmac, signature};
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use untrusted;
+use super::Identity;
+
+/// A Public (or for HS256 shared) key from the JWKS file.
+#[derive(Clone, Debug)]
+pub enum Jwk {
+    /// A `kty: oct` key, used for HS256.
+    Hmac { kid: Option<String>, key: Vec<u8> },
+    /// A `kty: RSA` key, used for RS256. Stored as a DER RSAPublicKey.
+    Rsa { kid: Option<String>, der: Vec<u8> },
+    /// A `kty: EC`, `crv: P-256` key, used for ES256. Stored as an uncompressed point.
+    Ec { kid: Option<String>, point: Vec<u8> },
+}
+
+impl Jwk {
+    fn kid(&self) -> Option<&str> {
+        match *self {
+            Jwk::Hmac { ref kid, .. } | Jwk::Rsa { ref kid, .. } | Jwk::Ec { ref kid, .. } => {
+                kid.as_ref().map(|kid| &kid[..])
+            }
+        }
+    }
+
+    /// Parses a single key out of a JWKS.
+    fn from_json(key: &Json) -> Result<Jwk, String> {
+        let field = |name: &str| -> Result<Vec<u8>, String> {
+            let value = try!(key.find(name).and_then(|value| value.as_string())
+                .ok_or(format!("JWK is missing `{}`.", name)));
+            value.from_base64().map_err(|e| format!("JWK `{}` isn't valid base64url: {}", name, e))
+        };
+        let kid = key.find("kid").and_then(|kid| kid.as_string()).map(|kid| kid.to_owned());
+
+        match key.find("kty").and_then(|kty| kty.as_string()) {
+            Some("oct") => Ok(Jwk::Hmac { kid: kid, key: try!(field("k")) }),
+            Some("RSA") => {
+                let n = try!(field("n"));
+                let e = try!(field("e"));
+                Ok(Jwk::Rsa { kid: kid, der: der_sequence(&[der_integer(&n), der_integer(&e)]) })
+            },
+            Some("EC") => {
+                if key.find("crv").and_then(|crv| crv.as_string()) != Some("P-256") {
+                    return Err("Only P-256 EC JWKs are supported.".to_owned());
+                }
+                let x = try!(field("x"));
+                let y = try!(field("y"));
+                if x.len() != 32 || y.len() != 32 {
+                    return Err("Invalid P-256 JWK coordinates.".to_owned());
+                }
+                let mut point = vec![0x04];
+                point.extend_from_slice(&x);
+                point.extend_from_slice(&y);
+                Ok(Jwk::Ec { kid: kid, point: point })
+            },
+            other => Err(format!("Unsupported JWK kty: {:?}", other)),
+        }
+    }
+
+    /// Verifies `signature` over `message` with this key, if this key is usable with `alg`.
+    fn verify(&self, alg: &str, message: &[u8], sig: &[u8]) -> bool {
+        match (alg, self) {
+            ("HS256", &Jwk::Hmac { ref key, .. }) => {
+                let key = hmac::VerificationKey::new(&digest::SHA256, key);
+                hmac::verify(&key, message, sig).is_ok()
+            },
+            ("RS256", &Jwk::Rsa { ref der, .. }) => {
+                signature::verify(&signature::RSA_PKCS1_2048_8192_SHA256, untrusted::Input::from(der),
+                    untrusted::Input::from(message), untrusted::Input::from(sig)).is_ok()
+            },
+            ("ES256", &Jwk::Ec { ref point, .. }) => {
+                // JWS uses the fixed width r || s encoding, ring wants the ASN.1 one.
+                if sig.len() != 64 {
+                    return false;
+                }
+                let asn1_sig = der_sequence(&[der_integer(&sig[..32]), der_integer(&sig[32..])]);
+                signature::verify(&signature::ECDSA_P256_SHA256_ASN1, untrusted::Input::from(point),
+                    untrusted::Input::from(message), untrusted::Input::from(&asn1_sig)).is_ok()
+            },
+            _ => false,
+        }
+    }
+}
+
+/// DER encodes a length.
+fn der_length(length: usize) -> Vec<u8> {
+    if length < 0x80 {
+        return vec![length as u8];
+    }
+    let mut bytes = Vec::new();
+    let mut remaining = length;
+    while remaining > 0 {
+        bytes.insert(0, (remaining & 0xff) as u8);
+        remaining >>= 8;
+    }
+    let mut encoded = vec![0x80 | bytes.len() as u8];
+    encoded.extend(bytes);
+    encoded
+}
+
+/// DER encodes a big-endian unsigned integer.
+fn der_integer(value: &[u8]) -> Vec<u8> {
+    let mut value: Vec<u8> = value.iter().cloned().skip_while(|byte| *byte == 0).collect();
+    if value.is_empty() || value[0] & 0x80 != 0 {
+        value.insert(0, 0);
+    }
+    let mut encoded = vec![0x02];
+    encoded.extend(der_length(value.len()));
+    encoded.extend(value);
+    encoded
+}
+
+/// DER encodes a sequence of already encoded values.
+fn der_sequence(values: &[Vec<u8>]) -> Vec<u8> {
+    let contents: Vec<u8> = values.iter().flat_map(|value| value.iter().cloned()).collect();
+    let mut encoded = vec![0x30];
+    encoded.extend(der_length(contents.len()));
+    encoded.extend(contents);
+    encoded
+}
+
+/// Verifies JWTs, and turns them into Identities.
+#[derive(Clone, Debug)]
+pub struct JwtVerifier {
+    pub keys: Vec<Jwk>,
+    pub audience: String,
+    pub issuer: Option<String>,
+    pub topic_claim: String,
+    pub leeway: i64,
+}
+
+impl JwtVerifier {
+    /// Creates a verifier with the keys from a JWKS file.
+    ///
+    /// * `jwks_path` - The path to the JWKS file.
+    /// * `audience` - The audience tokens must be issued for.
+    /// * `issuer` - The issuer tokens must come from, if any.
+    /// * `topic_claim` - The claim containing the topics a token may write to.
+    /// * `leeway` - How many seconds of clock skew to allow when checking `exp`, and `nbf`.
+    pub fn load(jwks_path: &path::Path, audience: String, issuer: Option<String>,
+                topic_claim: String, leeway: i64) -> Result<JwtVerifier, String> {
+        let mut contents = String::new();
+        try!(File::open(jwks_path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read JWKS [ {:?} ]: {}", jwks_path, e)));
+        let jwks = try!(Json::from_str(&contents)
+            .map_err(|e| format!("Failed to parse JWKS [ {:?} ]: {}", jwks_path, e)));
+        let json_keys = try!(jwks.find("keys").and_then(|keys| keys.as_array())
+            .ok_or(format!("JWKS [ {:?} ] has no `keys`.", jwks_path)));
+
+        let mut keys = Vec::new();
+        for key in json_keys.iter() {
+            keys.push(try!(Jwk::from_json(key).map_err(|e| format!("Invalid key in JWKS [ {:?} ]: {}", jwks_path, e))));
+        }
+
+        Ok(JwtVerifier {
+            keys: keys,
+            audience: audience,
+            issuer: issuer,
+            topic_claim: topic_claim,
+            leeway: leeway,
+        })
+    }
+
+    /// Verifies a token, returning the identity it grants.
+    ///
+    /// * `token` - The compact serialized JWT.
+    /// * `now` - The current time, in seconds since the unix epoch.
+    pub fn verify(&self, token: &str, now: i64) -> Result<Identity, String> {
+        let parts: Vec<&str> = token.split('.').collect();
+        if parts.len() != 3 {
+            return Err("Malformed JWT.".to_owned());
+        }
+        let header = try!(decode_json(parts[0]));
+        let claims = try!(decode_json(parts[1]));
+        let sig = try!(parts[2].from_base64().map_err(|_| "Malformed JWT signature.".to_owned()));
+
+        let alg = try!(header.find("alg").and_then(|alg| alg.as_string()).ok_or("JWT has no `alg`.".to_owned()));
+        let kid = header.find("kid").and_then(|kid| kid.as_string());
+        let message = &token[..parts[0].len() + 1 + parts[1].len()];
+        let verified = self.keys.iter()
+            .filter(|key| kid.is_none() || key.kid() == kid)
+            .any(|key| key.verify(alg, message.as_bytes(), &sig));
+        if !verified {
+            return Err("Invalid JWT signature.".to_owned());
+        }
+
+        let expires = try!(claims.find("exp").and_then(|exp| exp.as_i64()).ok_or("JWT has no `exp`.".to_owned()));
+        if now > expires + self.leeway {
+            return Err("JWT has expired.".to_owned());
+        }
+        if let Some(not_before) = claims.find("nbf").and_then(|nbf| nbf.as_i64()) {
+            if now + self.leeway < not_before {
+                return Err("JWT isn't valid yet.".to_owned());
+            }
+        }
+
+        let audience_matches = match claims.find("aud") {
+            Some(&Json::String(ref audience)) => *audience == self.audience,
+            Some(&Json::Array(ref audiences)) => audiences.iter().any(|audience| audience.as_string() == Some(&self.audience)),
+            _ => false,
+        };
+        if !audience_matches {
+            return Err("JWT wasn't issued for this audience.".to_owned());
+        }
+
+        if let Some(ref issuer) = self.issuer {
+            if claims.find("iss").and_then(|iss| iss.as_string()) != Some(issuer) {
+                return Err("JWT wasn't issued by the expected issuer.".to_owned());
+            }
+        }
+
+        let topics = match claims.find(&self.topic_claim) {
+            Some(&Json::Array(ref topics)) => topics.iter().filter_map(|topic| topic.as_string()).map(|topic| topic.to_owned()).collect(),
+            Some(&Json::String(ref topics)) => topics.split_whitespace().map(|topic| topic.to_owned()).collect(),
+            _ => Vec::new(),
+        };
+
+        Ok(Identity {
+            name: claims.find("sub").and_then(|sub| sub.as_string()).unwrap_or("jwt").to_owned(),
+            topics: topics,
+        })
+    }
+}
+
+/// Whether a credential looks like a JWT rather than an API Key.
+pub fn is_jwt(credential: &str) -> bool {
+    credential.split('.').count() == 3
+}
+
+fn decode_json(part: &str) -> Result<Json, String> {
+    let bytes = try!(part.from_base64().map_err(|_| "Malformed JWT.".to_owned()));
+    let text = try!(String::from_utf8(bytes).map_err(|_| "Malformed JWT.".to_owned()));
+    Json::from_str(&text).map_err(|_| "Malformed JWT.".to_owned())
+}
+
+#[cfg(test)]
+fn test_verifier() -> JwtVerifier {
+    JwtVerifier {
+        keys: vec![Jwk::Hmac {
+            kid: Some("test".to_owned()),
+            key: b"kafka-proxy-test-secret".to_vec(),
+        }],
+        audience: "kafka-proxy".to_owned(),
+        issuer: None,
+        topic_claim: "kafka_topics".to_owned(),
+        leeway: 30,
+    }
+}
+
+#[cfg(test)]
+const TEST_TOKEN: &'static str = "eyJhbGciOiJIUzI1NiIsInR5cCI6IkpXVCIsImtpZCI6InRlc3QifQ.\
+    eyJzdWIiOiJiaWxsaW5nIiwiYXVkIjoia2Fma2EtcHJveHkiLCJleHAiOjQxMDI0NDQ4MDAsImthZmthX3RvcGljcyI6WyJvcmRlcnMuKiJdfQ.\
+    bA-0vOSQ2xMaPxDn7SyssdlsCh_xpV4wzQOcPai4514";
+
+#[test]
+fn test_jwt_verification() {
+    let identity = test_verifier().verify(TEST_TOKEN, 1500000000).unwrap();
+
+    assert!(identity.name == "billing".to_string());
+    assert!(identity.may_write("orders.created"));
+    assert!(!identity.may_write("audit"));
+}
+
+#[test]
+fn test_jwt_rejections() {
+    let verifier = test_verifier();
+
+    // Expired (exp is 2100-01-01).
+    assert!(verifier.verify(TEST_TOKEN, 4102444800 + 31).is_err());
+    // Tampered signature.
+    assert!(verifier.verify(&TEST_TOKEN.replace("bA-0", "bA-1"), 1500000000).is_err());
+
+    let mut wrong_audience = test_verifier();
+    wrong_audience.audience = "someone-else".to_owned();
+    assert!(wrong_audience.verify(TEST_TOKEN, 1500000000).is_err());
+}
+
+#[test]
+fn test_der_integer() {
+    assert!(der_integer(&[0x00, 0x01]) == vec![0x02, 0x01, 0x01]);
+    assert!(der_integer(&[0x80]) == vec![0x02, 0x02, 0x00, 0x80]);
+}
@@ -0,0 +1,309 @@
+use iron::prelude::*;
+use iron::{status, typemap, BeforeMiddleware};
+use iron::headers::{Authorization, Bearer};
+use openssl::crypto::hash::{hash, Type};
+use router::Router;
+use rustc_serialize::hex::{FromHex, ToHex};
+use rustc_serialize::json;
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::time::{SystemTime, UNIX_EPOCH};
+use ::listener::CLIENT_SUBJECT_HEADER;
+use ::stats::Stat;
+use ::utils::split_topics;
+
+pub mod jwt;
+
+use self::jwt::JwtVerifier;
+
+/// A single API Key in the keystore.
+/// `key_sha256` - The hex encoded SHA-256 of the key, so the keystore never contains the keys themselves.
+/// `identity` - Who the key belongs to.
+/// `topics` - The topics the key may write to. A `*` matches any run of characters, so `orders.*`
+///   matches `orders.created`.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct KeyEntry {
+    pub key_sha256: String,
+    pub identity: String,
+    pub topics: Vec<String>,
+}
+
+/// A client certificate subject in the keystore.
+/// `subject` - The common name of the client certificate.
+/// `identity` - Who the certificate belongs to.
+/// `topics` - The topics the certificate may write to.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct SubjectEntry {
+    pub subject: String,
+    pub identity: String,
+    pub topics: Vec<String>,
+}
+
+/// The keystore. Loaded from a JSON file of the form:
+/// `{ "keys": [ { "key_sha256": "...", "identity": "...", "topics": ["..."] } ],
+///    "subjects": [ { "subject": "...", "identity": "...", "topics": ["..."] } ] }`
+/// Where "subjects" is optional, and only used when serving HTTPS with client certificates.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct KeyStore {
+    pub keys: Vec<KeyEntry>,
+    pub subjects: Option<Vec<SubjectEntry>>,
+}
+
+impl KeyStore {
+    /// Loads the keystore from a file, making sure every hash is valid hex.
+    pub fn load(path: &path::Path) -> Result<KeyStore, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read keystore [ {:?} ]: {}", path, e)));
+        let keystore: KeyStore = try!(json::decode(&contents)
+            .map_err(|e| format!("Failed to parse keystore [ {:?} ]: {}", path, e)));
+
+        for entry in keystore.keys.iter() {
+            match entry.key_sha256.from_hex() {
+                Ok(ref digest) if digest.len() == 32 => {},
+                _ => return Err(format!("Invalid key_sha256 for [ {} ] in keystore [ {:?} ].", entry.identity, path)),
+            }
+        }
+
+        Ok(keystore)
+    }
+
+    /// Finds the entry for a key, if there is one.
+    pub fn find(&self, key: &str) -> Option<&KeyEntry> {
+        let digest = hash(Type::SHA256, key.as_bytes()).to_hex();
+        self.keys.iter().find(|entry| constant_time_eq(entry.key_sha256.to_lowercase().as_bytes(), digest.as_bytes()))
+    }
+
+    /// Finds the entry for a client certificate subject, if there is one.
+    pub fn find_subject(&self, subject: &str) -> Option<&SubjectEntry> {
+        self.subjects.as_ref().and_then(|subjects| subjects.iter().find(|entry| entry.subject == subject))
+    }
+}
+
+/// Compares two byte strings without short circuiting, so the time taken
+/// doesn't leak how much of a key was correct.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b.iter()).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+/// Checks whether a topic matches a pattern, where `*` matches any run of characters.
+pub fn topic_matches(pattern: &str, topic: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or("");
+    if !topic.starts_with(first) {
+        return false;
+    }
+    let mut remaining = &topic[first.len()..];
+
+    let parts: Vec<&str> = parts.collect();
+    if parts.is_empty() {
+        return remaining.is_empty();
+    }
+    for (index, part) in parts.iter().enumerate() {
+        if index == parts.len() - 1 {
+            return remaining.ends_with(part);
+        }
+        match remaining.find(part) {
+            Some(position) => remaining = &remaining[position + part.len()..],
+            None => return false,
+        }
+    }
+    true
+}
+
+/// Who sent a request, and what they're allowed to write to.
+/// Inserted into the request extensions once a request is authenticated.
+#[derive(Clone, Debug)]
+pub struct Identity {
+    pub name: String,
+    pub topics: Vec<String>,
+}
+
+impl Identity {
+    /// Whether this identity may write to `topic`.
+    pub fn may_write(&self, topic: &str) -> bool {
+        self.topics.iter().any(|pattern| topic_matches(pattern, topic))
+    }
+}
+
+impl typemap::Key for Identity {
+    type Value = Identity;
+}
+
+/// The error returned when a request fails authentication, or authorization.
+#[derive(Debug)]
+pub struct AuthError {
+    pub message: String,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for AuthError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// An Iron Middleware that authenticates requests with an API Key, a JWT, or a client certificate.
+/// The credential is read from the `Authorization: Bearer <credential>` header, or the
+/// `X-Api-Key` header. Credentials that look like a JWT are verified as one when a JWKS
+/// is configured, anything else is looked up in the keystore. Requests without a credential
+/// fall back to the subject of their verified client certificate. Requests without a valid
+/// credential get a 401, and requests for a topic the credential can't write to get a 403.
+pub struct Authenticator {
+    keystore: Option<KeyStore>,
+    jwt: Option<JwtVerifier>,
+    stats: Option<Arc<Mutex<Sender<Stat>>>>,
+}
+
+impl Authenticator {
+    /// Creates a new Authenticator.
+    ///
+    /// * `keystore` - The API Keys to accept, if any.
+    /// * `jwt` - The verifier for JWTs, if they're accepted.
+    /// * `stats` - Where to report authentication failures, `None` to not report them.
+    pub fn new(keystore: Option<KeyStore>, jwt: Option<JwtVerifier>,
+               stats: Option<Arc<Mutex<Sender<Stat>>>>) -> Authenticator {
+        Authenticator {
+            keystore: keystore,
+            jwt: jwt,
+            stats: stats,
+        }
+    }
+
+    /// Turns a credential into an Identity, if it's valid.
+    fn authenticate(&self, credential: &str) -> Option<Identity> {
+        if let Some(ref verifier) = self.jwt {
+            if jwt::is_jwt(credential) {
+                let now = SystemTime::now().duration_since(UNIX_EPOCH).map(|now| now.as_secs() as i64).unwrap_or(0);
+                return match verifier.verify(credential, now) {
+                    Ok(identity) => Some(identity),
+                    Err(e) => {
+                        debug!("Rejected JWT: {}", e);
+                        None
+                    }
+                };
+            }
+        }
+
+        self.keystore.as_ref()
+            .and_then(|keystore| keystore.find(credential))
+            .map(|entry| Identity {
+                name: entry.identity.clone(),
+                topics: entry.topics.clone(),
+            })
+    }
+
+    /// Turns a verified client certificate subject into an Identity, if it's in the keystore.
+    fn authenticate_subject(&self, subject: &str) -> Option<Identity> {
+        self.keystore.as_ref()
+            .and_then(|keystore| keystore.find_subject(subject))
+            .map(|entry| Identity {
+                name: entry.identity.clone(),
+                topics: entry.topics.clone(),
+            })
+    }
+
+    fn fail(&self, status: status::Status, message: &str) -> IronResult<()> {
+        if let Some(ref stats) = self.stats {
+            let _ = stats.lock().unwrap().send(Stat::AuthFailure {
+                was_authenticated: status == status::Forbidden
+            });
+        }
+
+        let mut response = Response::with((status, message));
+        if status == status::Unauthorized {
+            response.headers.set_raw("WWW-Authenticate", vec![b"Bearer".to_vec()]);
+        }
+        Err(IronError {
+            error: Box::new(AuthError { message: message.to_owned() }),
+            response: response,
+        })
+    }
+}
+
+/// Reads the credential a request was sent with, if any.
+pub fn credential(req: &Request) -> Option<String> {
+    if let Some(&Authorization(Bearer { ref token })) = req.headers.get::<Authorization<Bearer>>() {
+        return Some(token.clone());
+    }
+    req.headers.get_raw("X-Api-Key")
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+/// Reads the subject of the verified client certificate a request was sent with, if any.
+pub fn client_subject(req: &Request) -> Option<String> {
+    req.headers.get_raw(CLIENT_SUBJECT_HEADER)
+        .and_then(|values| values.first())
+        .and_then(|value| String::from_utf8(value.clone()).ok())
+}
+
+impl BeforeMiddleware for Authenticator {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let identity = match credential(req) {
+            Some(credential) => self.authenticate(&credential),
+            None => client_subject(req).and_then(|subject| self.authenticate_subject(&subject)),
+        };
+        let identity = match identity {
+            Some(identity) => identity,
+            None => return self.fail(status::Unauthorized, "A valid API Key, or token is required."),
+        };
+
+        // `/route` has no topic yet, its topics are checked once they're known.
+        let topics = req.extensions.get::<Router>().and_then(|params| params.find("topic")).map(split_topics).unwrap_or(Vec::new());
+        if let Some(topic) = topics.iter().find(|topic| !identity.may_write(topic)) {
+            warn!("[ {} ] isn't allowed to write to [ {} ].", identity.name, topic);
+            return self.fail(status::Forbidden, "These credentials aren't allowed to write to this topic.");
+        }
+
+        req.extensions.insert::<Identity>(identity);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_topic_matching() {
+    assert!(topic_matches("orders", "orders"));
+    assert!(!topic_matches("orders", "orders.created"));
+    assert!(topic_matches("orders.*", "orders.created"));
+    assert!(!topic_matches("orders.*", "audit"));
+    assert!(topic_matches("*", "anything"));
+    assert!(topic_matches("*.created", "orders.created"));
+    assert!(topic_matches("orders.*.v1", "orders.created.v1"));
+    assert!(!topic_matches("orders.*.v1", "orders.created.v2"));
+}
+
+#[test]
+fn test_keystore_lookup() {
+    let keystore = KeyStore {
+        keys: vec![KeyEntry {
+            // echo -n "secret" | sha256sum
+            key_sha256: "2bb80d537b1da3e38bd30361aa855686bde0eacd7162fef6a25fe97bf527a25b".to_owned(),
+            identity: "billing".to_owned(),
+            topics: vec!["orders.*".to_owned()],
+        }],
+        subjects: Some(vec![SubjectEntry {
+            subject: "billing-service".to_owned(),
+            identity: "billing".to_owned(),
+            topics: vec!["orders.*".to_owned()],
+        }]),
+    };
+
+    assert!(keystore.find("secret").map(|entry| &entry.identity[..]) == Some("billing"));
+    assert!(keystore.find("not-the-secret").is_none());
+    assert!(keystore.find_subject("billing-service").map(|entry| &entry.identity[..]) == Some("billing"));
+    assert!(keystore.find_subject("someone-else").is_none());
+}
@@ -0,0 +1,395 @@
+use rustc_serialize::json::Json;
+use std::collections::HashMap;
+
+pub mod registry;
+
+/// An Avro Schema.
+/// Named types (records, enums, and fixed) are referred to by their full name
+/// everywhere but their definition, which lets schemas be recursive.
+#[derive(Clone, Debug, PartialEq)]
+pub enum AvroSchema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record { name: String, fields: Vec<AvroField> },
+    Enum { name: String, symbols: Vec<String> },
+    Array(Box<AvroSchema>),
+    Map(Box<AvroSchema>),
+    Union(Vec<AvroSchema>),
+    Fixed { name: String, size: usize },
+    Named(String),
+}
+
+/// A field of an Avro Record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AvroField {
+    pub name: String,
+    pub schema: AvroSchema,
+    pub default: Option<Json>,
+}
+
+/// A parsed Avro Schema, with every named type it defines.
+#[derive(Clone, Debug)]
+pub struct Avro {
+    root: AvroSchema,
+    names: HashMap<String, AvroSchema>,
+}
+
+impl Avro {
+    /// Parses an Avro Schema from its JSON form.
+    pub fn parse(schema: &str) -> Result<Avro, String> {
+        let json = try!(Json::from_str(schema).map_err(|e| format!("Invalid Avro schema: {}", e)));
+        let mut names = HashMap::new();
+        let root = try!(parse_schema(&json, None, &mut names));
+        Ok(Avro {
+            root: root,
+            names: names,
+        })
+    }
+
+    /// Encodes a JSON value as Avro binary.
+    /// Unions accept either the Avro JSON encoding (`{"string": "value"}`), or
+    /// a plain value, which is encoded as the first branch it fits.
+    pub fn encode(&self, value: &Json) -> Result<Vec<u8>, String> {
+        let mut buffer = Vec::new();
+        try!(self.encode_value(&self.root, value, "", &mut buffer));
+        Ok(buffer)
+    }
+
+    fn lookup<'a>(&'a self, schema: &'a AvroSchema) -> Result<&'a AvroSchema, String> {
+        match *schema {
+            AvroSchema::Named(ref name) => self.names.get(name).ok_or(format!("Unknown Avro type [ {} ].", name)),
+            _ => Ok(schema),
+        }
+    }
+
+    fn encode_value(&self, schema: &AvroSchema, value: &Json, path: &str, buffer: &mut Vec<u8>) -> Result<(), String> {
+        let schema = try!(self.lookup(schema));
+        let mismatch = || format!("{}: expected {}, found {}", display(path), type_name(schema), value);
+
+        match (schema, value) {
+            (&AvroSchema::Null, &Json::Null) => {},
+            (&AvroSchema::Boolean, &Json::Boolean(boolean)) => buffer.push(if boolean { 1 } else { 0 }),
+            (&AvroSchema::Int, _) => {
+                let number = try!(value.as_i64().ok_or_else(&mismatch));
+                if number < i32::min_value() as i64 || number > i32::max_value() as i64 {
+                    return Err(format!("{}: [ {} ] is out of range for an int", display(path), number));
+                }
+                write_long(number, buffer);
+            },
+            (&AvroSchema::Long, _) => write_long(try!(value.as_i64().ok_or_else(&mismatch)), buffer),
+            (&AvroSchema::Float, _) => {
+                let number = try!(value.as_f64().ok_or_else(&mismatch)) as f32;
+                write_little_endian(number.to_bits() as u64, 4, buffer);
+            },
+            (&AvroSchema::Double, _) => {
+                let number = try!(value.as_f64().ok_or_else(&mismatch));
+                write_little_endian(number.to_bits(), 8, buffer);
+            },
+            (&AvroSchema::Bytes, &Json::String(ref string)) => {
+                let bytes = try!(latin1_bytes(string).ok_or_else(&mismatch));
+                write_long(bytes.len() as i64, buffer);
+                buffer.extend(bytes);
+            },
+            (&AvroSchema::String, &Json::String(ref string)) => {
+                write_long(string.len() as i64, buffer);
+                buffer.extend_from_slice(string.as_bytes());
+            },
+            (&AvroSchema::Record { ref fields, .. }, &Json::Object(ref object)) => {
+                for field in fields.iter() {
+                    let field_path = format!("{}/{}", path, field.name);
+                    match (object.get(&field.name), field.default.as_ref()) {
+                        (Some(field_value), _) => try!(self.encode_value(&field.schema, field_value, &field_path, buffer)),
+                        (None, Some(default)) => try!(self.encode_default(&field.schema, default, &field_path, buffer)),
+                        (None, None) => return Err(format!("{}: missing required field [ {} ]", display(path), field.name)),
+                    }
+                }
+            },
+            (&AvroSchema::Enum { ref symbols, .. }, &Json::String(ref symbol)) => {
+                let index = try!(symbols.iter().position(|known| known == symbol)
+                    .ok_or(format!("{}: [ {} ] isn't one of {:?}", display(path), symbol, symbols)));
+                write_long(index as i64, buffer);
+            },
+            (&AvroSchema::Array(ref items), &Json::Array(ref array)) => {
+                if !array.is_empty() {
+                    write_long(array.len() as i64, buffer);
+                    for (index, item) in array.iter().enumerate() {
+                        try!(self.encode_value(items, item, &format!("{}/{}", path, index), buffer));
+                    }
+                }
+                write_long(0, buffer);
+            },
+            (&AvroSchema::Map(ref values), &Json::Object(ref object)) => {
+                if !object.is_empty() {
+                    write_long(object.len() as i64, buffer);
+                    for (key, item) in object.iter() {
+                        write_long(key.len() as i64, buffer);
+                        buffer.extend_from_slice(key.as_bytes());
+                        try!(self.encode_value(values, item, &format!("{}/{}", path, key), buffer));
+                    }
+                }
+                write_long(0, buffer);
+            },
+            (&AvroSchema::Union(ref branches), _) => try!(self.encode_union(branches, value, path, buffer)),
+            (&AvroSchema::Fixed { size, .. }, &Json::String(ref string)) => {
+                let bytes = try!(latin1_bytes(string).ok_or_else(&mismatch));
+                if bytes.len() != size {
+                    return Err(format!("{}: expected {} bytes, found {}", display(path), size, bytes.len()));
+                }
+                buffer.extend(bytes);
+            },
+            _ => return Err(mismatch()),
+        }
+        Ok(())
+    }
+
+    fn encode_union(&self, branches: &[AvroSchema], value: &Json, path: &str, buffer: &mut Vec<u8>) -> Result<(), String> {
+        // The Avro JSON encoding wraps non-null union values in an object naming the branch.
+        if let Json::Object(ref object) = *value {
+            if object.len() == 1 {
+                let (branch_name, branch_value) = object.iter().next().unwrap();
+                let position = branches.iter().position(|branch| self.branch_name(branch) == *branch_name);
+                if let Some(index) = position {
+                    let mut encoded = Vec::new();
+                    if self.encode_value(&branches[index], branch_value, path, &mut encoded).is_ok() {
+                        write_long(index as i64, buffer);
+                        buffer.extend(encoded);
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        for (index, branch) in branches.iter().enumerate() {
+            let mut encoded = Vec::new();
+            if self.encode_value(branch, value, path, &mut encoded).is_ok() {
+                write_long(index as i64, buffer);
+                buffer.extend(encoded);
+                return Ok(());
+            }
+        }
+        Err(format!("{}: [ {} ] doesn't match any of the union's types", display(path), value))
+    }
+
+    /// Encodes a field default. Defaults for unions are always for the first branch.
+    fn encode_default(&self, schema: &AvroSchema, default: &Json, path: &str, buffer: &mut Vec<u8>) -> Result<(), String> {
+        match *try!(self.lookup(schema)) {
+            AvroSchema::Union(ref branches) if !branches.is_empty() => {
+                write_long(0, buffer);
+                self.encode_value(&branches[0], default, path, buffer)
+            },
+            _ => self.encode_value(schema, default, path, buffer),
+        }
+    }
+
+    fn branch_name(&self, schema: &AvroSchema) -> String {
+        match *schema {
+            AvroSchema::Named(ref name) => name.clone(),
+            AvroSchema::Record { ref name, .. } | AvroSchema::Enum { ref name, .. } | AvroSchema::Fixed { ref name, .. } => name.clone(),
+            _ => type_name(schema).to_owned(),
+        }
+    }
+}
+
+fn type_name(schema: &AvroSchema) -> &'static str {
+    match *schema {
+        AvroSchema::Null => "null",
+        AvroSchema::Boolean => "boolean",
+        AvroSchema::Int => "int",
+        AvroSchema::Long => "long",
+        AvroSchema::Float => "float",
+        AvroSchema::Double => "double",
+        AvroSchema::Bytes => "bytes",
+        AvroSchema::String => "string",
+        AvroSchema::Record { .. } => "record",
+        AvroSchema::Enum { .. } => "enum",
+        AvroSchema::Array(_) => "array",
+        AvroSchema::Map(_) => "map",
+        AvroSchema::Union(_) => "union",
+        AvroSchema::Fixed { .. } => "fixed",
+        AvroSchema::Named(_) => "named type",
+    }
+}
+
+fn display(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+/// Avro's JSON encoding for bytes maps each code point 0-255 to a byte.
+fn latin1_bytes(string: &str) -> Option<Vec<u8>> {
+    string.chars().map(|character| if (character as u32) < 256 { Some(character as u8) } else { None }).collect()
+}
+
+/// Writes an int or long, zig-zag, and variable length encoded.
+pub fn write_long(value: i64, buffer: &mut Vec<u8>) {
+    let mut zigzag = ((value << 1) ^ (value >> 63)) as u64;
+    while zigzag & !0x7f != 0 {
+        buffer.push(((zigzag & 0x7f) | 0x80) as u8);
+        zigzag >>= 7;
+    }
+    buffer.push(zigzag as u8);
+}
+
+/// Writes the low `width` bytes of `bits`, little-endian, as floats and doubles are.
+fn write_little_endian(bits: u64, width: usize, buffer: &mut Vec<u8>) {
+    for index in 0..width {
+        buffer.push((bits >> (index * 8)) as u8);
+    }
+}
+
+fn full_name(name: &str, namespace: Option<&str>) -> String {
+    match namespace {
+        Some(namespace) if !name.contains('.') && !namespace.is_empty() => format!("{}.{}", namespace, name),
+        _ => name.to_owned(),
+    }
+}
+
+fn parse_schema(json: &Json, namespace: Option<&str>, names: &mut HashMap<String, AvroSchema>) -> Result<AvroSchema, String> {
+    match *json {
+        Json::String(ref name) => Ok(match &name[..] {
+            "null" => AvroSchema::Null,
+            "boolean" => AvroSchema::Boolean,
+            "int" => AvroSchema::Int,
+            "long" => AvroSchema::Long,
+            "float" => AvroSchema::Float,
+            "double" => AvroSchema::Double,
+            "bytes" => AvroSchema::Bytes,
+            "string" => AvroSchema::String,
+            _ => {
+                let name = if names.contains_key(&name[..]) { name.clone() } else { full_name(name, namespace) };
+                if !names.contains_key(&name) {
+                    return Err(format!("Unknown Avro type [ {} ].", name));
+                }
+                AvroSchema::Named(name)
+            },
+        }),
+        Json::Array(ref branches) => {
+            let mut parsed = Vec::new();
+            for branch in branches.iter() {
+                parsed.push(try!(parse_schema(branch, namespace, names)));
+            }
+            Ok(AvroSchema::Union(parsed))
+        },
+        Json::Object(ref object) => {
+            let kind = try!(object.get("type").ok_or("Avro schema is missing `type`.".to_owned()));
+            let kind = match *kind {
+                Json::String(ref kind) => kind.clone(),
+                // `{"type": {...}}`, and `{"type": [...]}` just wrap another schema.
+                _ => return parse_schema(kind, namespace, names),
+            };
+            let name = object.get("name").and_then(|name| name.as_string());
+            let namespace = object.get("namespace").and_then(|namespace| namespace.as_string()).or(namespace);
+
+            match &kind[..] {
+                "record" | "error" => {
+                    let name = full_name(try!(name.ok_or("Avro record is missing `name`.".to_owned())), namespace);
+                    // Registered before the fields are parsed, so they can refer to the record.
+                    names.insert(name.clone(), AvroSchema::Record { name: name.clone(), fields: Vec::new() });
+                    let record_namespace = name.rsplitn(2, '.').nth(1).map(|namespace| namespace.to_owned());
+
+                    let json_fields = try!(object.get("fields").and_then(|fields| fields.as_array())
+                        .ok_or(format!("Avro record [ {} ] is missing `fields`.", name)));
+                    let mut fields = Vec::new();
+                    for field in json_fields.iter() {
+                        let field_name = try!(field.find("name").and_then(|name| name.as_string())
+                            .ok_or(format!("A field of Avro record [ {} ] is missing `name`.", name)));
+                        let field_type = try!(field.find("type")
+                            .ok_or(format!("Field [ {} ] of Avro record [ {} ] is missing `type`.", field_name, name)));
+                        fields.push(AvroField {
+                            name: field_name.to_owned(),
+                            schema: try!(parse_schema(field_type, record_namespace.as_ref().map(|namespace| &namespace[..]), names)),
+                            default: field.find("default").cloned(),
+                        });
+                    }
+
+                    let record = AvroSchema::Record { name: name.clone(), fields: fields };
+                    names.insert(name.clone(), record);
+                    Ok(AvroSchema::Named(name))
+                },
+                "enum" => {
+                    let name = full_name(try!(name.ok_or("Avro enum is missing `name`.".to_owned())), namespace);
+                    let symbols = try!(object.get("symbols").and_then(|symbols| symbols.as_array())
+                        .ok_or(format!("Avro enum [ {} ] is missing `symbols`.", name)));
+                    let symbols = symbols.iter().filter_map(|symbol| symbol.as_string()).map(|symbol| symbol.to_owned()).collect();
+                    names.insert(name.clone(), AvroSchema::Enum { name: name.clone(), symbols: symbols });
+                    Ok(AvroSchema::Named(name))
+                },
+                "fixed" => {
+                    let name = full_name(try!(name.ok_or("Avro fixed is missing `name`.".to_owned())), namespace);
+                    let size = try!(object.get("size").and_then(|size| size.as_u64())
+                        .ok_or(format!("Avro fixed [ {} ] is missing `size`.", name)));
+                    names.insert(name.clone(), AvroSchema::Fixed { name: name.clone(), size: size as usize });
+                    Ok(AvroSchema::Named(name))
+                },
+                "array" => {
+                    let items = try!(object.get("items").ok_or("Avro array is missing `items`.".to_owned()));
+                    Ok(AvroSchema::Array(Box::new(try!(parse_schema(items, namespace, names)))))
+                },
+                "map" => {
+                    let values = try!(object.get("values").ok_or("Avro map is missing `values`.".to_owned()));
+                    Ok(AvroSchema::Map(Box::new(try!(parse_schema(values, namespace, names)))))
+                },
+                // Primitives, possibly with a logicalType, which is encoded as the primitive.
+                _ => parse_schema(&Json::String(kind.clone()), namespace, names),
+            }
+        },
+        _ => Err(format!("Invalid Avro schema [ {} ].", json)),
+    }
+}
+
+/// Prefixes an Avro payload with the Confluent wire format header:
+/// a zero magic byte, and the big-endian schema id.
+pub fn confluent_wire_format(schema_id: u32, avro: &[u8]) -> Vec<u8> {
+    let mut framed = vec![0];
+    framed.extend_from_slice(&[(schema_id >> 24) as u8, (schema_id >> 16) as u8, (schema_id >> 8) as u8, schema_id as u8]);
+    framed.extend_from_slice(avro);
+    framed
+}
+
+#[test]
+fn test_avro_encoding() {
+    let avro = Avro::parse(r#"{
+        "type": "record", "name": "Order", "namespace": "com.example",
+        "fields": [
+            {"name": "id", "type": "long"},
+            {"name": "note", "type": ["null", "string"], "default": null},
+            {"name": "status", "type": {"type": "enum", "name": "Status", "symbols": ["CREATED", "SHIPPED"]}},
+            {"name": "tags", "type": {"type": "array", "items": "string"}},
+            {"name": "parent", "type": ["null", "Order"], "default": null}
+        ]
+    }"#).unwrap();
+
+    let encoded = avro.encode(&Json::from_str(r#"{"id": 1, "note": "hi", "status": "SHIPPED", "tags": ["a"]}"#).unwrap()).unwrap();
+    assert!(encoded == vec![
+        0x02,                   // id: 1
+        0x02, 0x04, b'h', b'i', // note: union branch 1, "hi"
+        0x02,                   // status: SHIPPED
+        0x02, 0x02, b'a', 0x00, // tags: one item "a", then the end of the array
+        0x00,                   // parent: the default, null
+    ]);
+
+    let nested = avro.encode(&Json::from_str(
+        r#"{"id": 2, "note": {"string": "x"}, "status": "CREATED", "tags": [], "parent": {"id": 1, "status": "CREATED", "tags": []}}"#
+    ).unwrap()).unwrap();
+    assert!(nested == vec![0x04, 0x02, 0x02, b'x', 0x00, 0x00, 0x02, 0x02, 0x00, 0x00, 0x00, 0x00]);
+
+    assert!(avro.encode(&Json::from_str(r#"{"id": "one", "status": "CREATED", "tags": []}"#).unwrap()).is_err());
+    assert!(avro.encode(&Json::from_str(r#"{"id": 1, "status": "LOST", "tags": []}"#).unwrap()).is_err());
+    assert!(avro.encode(&Json::from_str(r#"{"status": "CREATED", "tags": []}"#).unwrap()).is_err());
+}
+
+#[test]
+fn test_write_long() {
+    let encode = |value: i64| { let mut buffer = Vec::new(); write_long(value, &mut buffer); buffer };
+
+    assert!(encode(0) == vec![0x00]);
+    assert!(encode(-1) == vec![0x01]);
+    assert!(encode(64) == vec![0x80, 0x01]);
+    assert!(encode(-65) == vec![0x81, 0x01]);
+    assert!(confluent_wire_format(258, &[0x02]) == vec![0x00, 0x00, 0x00, 0x01, 0x02, 0x02]);
+}
@@ -0,0 +1,245 @@
+use hyper::Client;
+use hyper::header::Headers;
+use rustc_serialize::json::{self, Json};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use super::{confluent_wire_format, Avro};
+
+/// The Content-Type the Schema Registry expects.
+const REGISTRY_CONTENT_TYPE: &'static [u8] = b"application/vnd.schemaregistry.v1+json";
+
+/// Why a body couldn't be encoded as Avro.
+/// `Invalid` - The body isn't JSON, or doesn't fit the topic's schema.
+/// `Unavailable` - The topic's schema couldn't be fetched from, or registered with the Schema Registry.
+#[derive(Debug, PartialEq)]
+pub enum EncodeError {
+    Invalid(String),
+    Unavailable(String),
+}
+
+struct CachedSchema {
+    id: u32,
+    avro: Arc<Avro>,
+    fetched: Instant,
+}
+
+/// A Confluent Schema Registry client, that encodes JSON bodies in the Confluent wire format.
+/// Topics use the `<topic>-value` subject. When `schema_dir` has a `<topic>.avsc` schema it's
+/// registered (which gives back the existing id when it's already registered), otherwise the
+/// latest version of the subject is used. Schemas are cached for `cache_ttl`.
+pub struct SchemaRegistry {
+    url: String,
+    schema_dir: Option<path::PathBuf>,
+    cache_ttl: Duration,
+    client: Client,
+    cache: Mutex<HashMap<String, CachedSchema>>,
+}
+
+impl SchemaRegistry {
+    /// Creates a new SchemaRegistry client.
+    ///
+    /// * `url` - The base URL of the Schema Registry, e.g. `http://registry:8081`.
+    /// * `schema_dir` - A directory of `<topic>.avsc` schemas to register, if any.
+    /// * `cache_ttl` - How long to use a schema before checking the registry again.
+    pub fn new(url: &str, schema_dir: Option<path::PathBuf>, cache_ttl: Duration) -> SchemaRegistry {
+        let mut client = Client::new();
+        client.set_read_timeout(Some(Duration::from_secs(10)));
+        client.set_write_timeout(Some(Duration::from_secs(10)));
+
+        SchemaRegistry {
+            url: url.trim_right_matches('/').to_owned(),
+            schema_dir: schema_dir,
+            cache_ttl: cache_ttl,
+            client: client,
+            cache: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The subject a topic's schema is registered under.
+    pub fn subject(topic: &str) -> String {
+        format!("{}-value", topic)
+    }
+
+    /// The schema id, and schema for a topic. From the cache when it's fresh enough.
+    pub fn schema_for(&self, topic: &str) -> Result<(u32, Arc<Avro>), String> {
+        if let Some(cached) = self.cache.lock().unwrap().get(topic) {
+            if cached.fetched.elapsed() < self.cache_ttl {
+                return Ok((cached.id, cached.avro.clone()));
+            }
+        }
+
+        let (id, avro) = try!(self.fetch(topic));
+        let avro = Arc::new(avro);
+        self.cache.lock().unwrap().insert(topic.to_owned(), CachedSchema {
+            id: id,
+            avro: avro.clone(),
+            fetched: Instant::now(),
+        });
+        Ok((id, avro))
+    }
+
+    /// Encodes a JSON body as Avro with the topic's schema, in the Confluent wire format.
+    pub fn encode(&self, topic: &str, body: &[u8]) -> Result<Vec<u8>, EncodeError> {
+        let (id, avro) = try!(self.schema_for(topic).map_err(EncodeError::Unavailable));
+        try!(::utils::check_json_depth(body).map_err(EncodeError::Invalid));
+        let text = try!(::std::str::from_utf8(body).map_err(|_| EncodeError::Invalid("The body isn't valid UTF-8.".to_owned())));
+        let value = try!(Json::from_str(text).map_err(|e| EncodeError::Invalid(format!("The body isn't valid JSON: {}", e))));
+        let encoded = try!(avro.encode(&value).map_err(EncodeError::Invalid));
+        Ok(confluent_wire_format(id, &encoded))
+    }
+
+    fn local_schema(&self, topic: &str) -> Result<Option<String>, String> {
+        let schema_path = match self.schema_dir {
+            Some(ref schema_dir) => schema_dir.join(format!("{}.avsc", topic)),
+            None => return Ok(None),
+        };
+        if !schema_path.exists() {
+            return Ok(None);
+        }
+
+        let mut contents = String::new();
+        try!(File::open(&schema_path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read Avro schema [ {:?} ]: {}", schema_path, e)));
+        Ok(Some(contents))
+    }
+
+    fn fetch(&self, topic: &str) -> Result<(u32, Avro), String> {
+        let subject = SchemaRegistry::subject(topic);
+        let (id, schema) = match try!(self.local_schema(topic)) {
+            Some(schema) => {
+                let mut request = BTreeMap::new();
+                request.insert("schema".to_owned(), Json::String(schema.clone()));
+                let url = format!("{}/subjects/{}/versions", self.url, subject);
+                let response = try!(self.request(&url, Some(json::encode(&Json::Object(request)).unwrap())));
+                info!("Registered the schema for [ {} ].", subject);
+                (try!(schema_id(&response, &subject)), schema)
+            },
+            None => {
+                let url = format!("{}/subjects/{}/versions/latest", self.url, subject);
+                let response = try!(self.request(&url, None));
+                let schema = try!(response.find("schema").and_then(|schema| schema.as_string())
+                    .ok_or(format!("The Schema Registry gave no schema for [ {} ].", subject)));
+                (try!(schema_id(&response, &subject)), schema.to_owned())
+            },
+        };
+
+        let avro = try!(Avro::parse(&schema).map_err(|e| format!("The schema for [ {} ] is invalid: {}", subject, e)));
+        Ok((id, avro))
+    }
+
+    /// Sends a GET (or a POST when there's a body) to the registry, and parses the JSON response.
+    fn request(&self, url: &str, body: Option<String>) -> Result<Json, String> {
+        let mut headers = Headers::new();
+        headers.set_raw("Accept", vec![REGISTRY_CONTENT_TYPE.to_vec()]);
+        let result = match body {
+            Some(ref body) => {
+                headers.set_raw("Content-Type", vec![REGISTRY_CONTENT_TYPE.to_vec()]);
+                self.client.post(url).headers(headers).body(&body[..]).send()
+            },
+            None => self.client.get(url).headers(headers).send(),
+        };
+        let mut response = try!(result.map_err(|e| format!("Failed to reach the Schema Registry [ {} ]: {}", url, e)));
+
+        let mut contents = String::new();
+        try!(response.read_to_string(&mut contents)
+            .map_err(|e| format!("Failed to read the Schema Registry response [ {} ]: {}", url, e)));
+        if !response.status.is_success() {
+            return Err(format!("The Schema Registry [ {} ] answered {}: {}", url, response.status, contents));
+        }
+        Json::from_str(&contents).map_err(|e| format!("Invalid Schema Registry response [ {} ]: {}", url, e))
+    }
+}
+
+fn schema_id(response: &Json, subject: &str) -> Result<u32, String> {
+    response.find("id").and_then(|id| id.as_u64()).map(|id| id as u32)
+        .ok_or(format!("The Schema Registry gave no schema id for [ {} ].", subject))
+}
+
+/// A stand-in Schema Registry for the tests. Answers every request with `response`,
+/// and records the request lines it was sent.
+#[cfg(test)]
+fn stand_in_registry(response: &'static str) -> (String, Arc<Mutex<Vec<String>>>) {
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}", listener.local_addr().unwrap());
+    let requests = Arc::new(Mutex::new(Vec::new()));
+    let recorded = requests.clone();
+
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            let mut stream = stream.unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0; 4096];
+            // Read the headers, then however much body they say there is.
+            loop {
+                let read = stream.read(&mut buffer).unwrap();
+                if read == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..read]);
+                let text = String::from_utf8_lossy(&received).into_owned();
+                if let Some(end) = text.find("\r\n\r\n") {
+                    let length = text.lines()
+                        .find(|line| line.to_lowercase().starts_with("content-length:"))
+                        .and_then(|line| line[15..].trim().parse::<usize>().ok())
+                        .unwrap_or(0);
+                    if received.len() >= end + 4 + length {
+                        recorded.lock().unwrap().push(text.lines().next().unwrap_or("").to_owned());
+                        break;
+                    }
+                }
+            }
+            let _ = write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/vnd.schemaregistry.v1+json\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n{}", response.len(), response);
+        }
+    });
+
+    (url, requests)
+}
+
+#[test]
+fn test_registry_lookup() {
+    let (url, requests) = stand_in_registry(
+        r#"{"subject": "orders-value", "version": 3, "id": 7, "schema": "{\"type\": \"record\", \"name\": \"Order\", \"fields\": [{\"name\": \"id\", \"type\": \"long\"}]}"}"#
+    );
+    let registry = SchemaRegistry::new(&url, None, Duration::from_secs(300));
+
+    assert!(registry.encode("orders", br#"{"id": 1}"#).unwrap() == vec![0x00, 0x00, 0x00, 0x00, 0x07, 0x02]);
+    assert!(registry.encode("orders", br#"{"id": 2}"#).unwrap() == vec![0x00, 0x00, 0x00, 0x00, 0x07, 0x04]);
+    // The second encode came from the cache.
+    assert!(*requests.lock().unwrap() == vec!["GET /subjects/orders-value/versions/latest HTTP/1.1".to_owned()]);
+
+    match registry.encode("orders", br#"{"id": "one"}"#) {
+        Err(EncodeError::Invalid(_)) => {},
+        other => panic!("Expected an invalid body, got: {:?}", other),
+    }
+}
+
+#[test]
+fn test_registry_registration() {
+    use std::fs;
+    use std::io::Write;
+
+    let schema_dir = ::std::env::temp_dir().join("kafka-proxy-registry-test");
+    fs::create_dir_all(&schema_dir).unwrap();
+    File::create(schema_dir.join("audit.avsc")).unwrap().write_all(br#""string""#).unwrap();
+
+    let (url, requests) = stand_in_registry(r#"{"id": 12}"#);
+    let registry = SchemaRegistry::new(&url, Some(schema_dir), Duration::from_secs(300));
+
+    assert!(registry.encode("audit", br#""hi""#).unwrap() == vec![0x00, 0x00, 0x00, 0x00, 0x0c, 0x04, b'h', b'i']);
+    assert!(*requests.lock().unwrap() == vec!["POST /subjects/audit-value/versions HTTP/1.1".to_owned()]);
+
+    let unreachable = SchemaRegistry::new("http://127.0.0.1:1", None, Duration::from_secs(300));
+    match unreachable.encode("audit", br#""hi""#) {
+        Err(EncodeError::Unavailable(_)) => {},
+        other => panic!("Expected the registry to be unavailable, got: {:?}", other),
+    }
+}
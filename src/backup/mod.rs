@@ -0,0 +1,92 @@
+pub mod wal;
+
+use jfs::Store;
+use models::{BackupBackend, BackupConfig, MessagePayload};
+use std::fs;
+use std::path::Path;
+
+/// What to do with a backup once it's been replayed.
+/// `Remove` - It's been dealt with (sent, dead-lettered, or dropped).
+/// `Keep` - It still needs sending, with its updated attempts, and error.
+pub enum Replayed {
+    Remove,
+    Keep(MessagePayload),
+}
+
+/// Where messages kafka failed on are kept until they can be resent.
+pub trait BackupStore: Send {
+    /// Backs up a message.
+    fn save(&mut self, message: &MessagePayload) -> Result<(), String>;
+
+    /// Calls `resend` with every backup, oldest first where the store knows the order,
+    /// removing or keeping each one as it says.
+    fn replay(&mut self, resend: &mut FnMut(MessagePayload) -> Replayed) -> Result<(), String>;
+
+    /// Called regularly by the producer, so the store can do what's due (like syncing)
+    /// even when nothing new is being backed up.
+    fn tick(&mut self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// A JSON file per message, named by a random id.
+pub struct JfsStore {
+    store: Store,
+}
+
+impl JfsStore {
+    pub fn open(dir: &str) -> Result<JfsStore, String> {
+        Store::new(dir)
+            .map(|store| JfsStore { store: store })
+            .map_err(|e| format!("Failed to open the backup directory [ {} ]: {:?}", dir, e))
+    }
+}
+
+impl BackupStore for JfsStore {
+    fn save(&mut self, message: &MessagePayload) -> Result<(), String> {
+        self.store.save(message).map(|_| ()).map_err(|e| format!("{:?}", e))
+    }
+
+    /// Every backup is loaded at once, so a long outage can mean a lot of memory.
+    fn replay(&mut self, resend: &mut FnMut(MessagePayload) -> Replayed) -> Result<(), String> {
+        let backups = try!(self.store.get_all::<MessagePayload>().map_err(|e| format!("Failed to read the backups: {:?}", e)));
+        for (id, message) in backups {
+            match resend(message) {
+                Replayed::Remove => {
+                    let _ = self.store.delete(&id);
+                },
+                Replayed::Keep(message) => {
+                    let _ = self.store.save_with_id(&message, &id);
+                },
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Whether a directory has any `jfs` backups (`<id>.json` files) in it.
+fn has_jfs_backups(dir: &Path) -> Result<bool, String> {
+    let entries = try!(fs::read_dir(dir).map_err(|e| format!("Failed to read the backup directory [ {:?} ]: {}", dir, e)));
+    Ok(entries.filter_map(|entry| entry.ok())
+        .any(|entry| entry.path().extension().and_then(|extension| extension.to_str()) == Some("json")))
+}
+
+/// Opens the configured backup store.
+pub fn open(config: &BackupConfig) -> Result<Box<BackupStore>, String> {
+    match config.backend {
+        BackupBackend::Jfs => {
+            let dir = try!(config.dir.to_str().ok_or(format!("Invalid backup directory: [ {:?} ].", config.dir)));
+            Ok(Box::new(try!(JfsStore::open(dir))))
+        },
+        BackupBackend::Wal { segment_size, ref fsync } => {
+            let mut store = try!(wal::WalStore::open(&config.dir, segment_size, fsync.clone()));
+            // Backups left by the `jfs` store are moved into the log, rather than never being resent.
+            if try!(has_jfs_backups(&config.dir)) {
+                let dir = try!(config.dir.to_str().ok_or(format!("Invalid backup directory: [ {:?} ].", config.dir)));
+                let imported = try!(store.import(&mut try!(JfsStore::open(dir))));
+                info!("Moved {} backups from the jfs store into the write-ahead log.", imported);
+            }
+            Ok(Box::new(store))
+        },
+    }
+}
@@ -0,0 +1,447 @@
+use models::{FsyncPolicy, MessagePayload};
+use rustc_serialize::json;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use super::{BackupStore, Replayed};
+
+/// Why a record couldn't be read.
+/// `Truncated` - The segment ends part way through the record, as it does after a crash mid-write.
+/// `Corrupt` - The record is there, but isn't what was written.
+#[derive(Debug, PartialEq)]
+enum RecordError {
+    Truncated,
+    Corrupt(String),
+}
+
+/// The segment being appended to, and how much has been written to it.
+struct Segment {
+    file: File,
+    written: u64,
+}
+
+/// A write-ahead log of backups, as numbered, append-only segment files (`<number>.log`).
+/// Every record is its length, the CRC32 of the length, and the CRC32 of the backup JSON (all little
+/// endian `u32`s), then the backup JSON. The length has its own CRC, so a damaged length is found
+/// as corruption, rather than read past the rest of the segment as a message cut short.
+///
+/// A new segment is started once the current one reaches `segment_size`, and on every open,
+/// so a segment is never appended to after a crash. Segments are read one record at a time
+/// when replaying, and removed once replayed, with the messages that are kept written again
+/// to a new segment.
+pub struct WalStore {
+    dir: PathBuf,
+    segment_size: u64,
+    fsync: FsyncPolicy,
+    active: Option<Segment>,
+    next_segment: u64,
+    last_sync: Instant,
+    unsynced: bool,
+}
+
+/// The CRC32 (IEEE) of some bytes.
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 { (crc >> 1) ^ 0xedb88320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn u32_to_bytes(value: u32) -> [u8; 4] {
+    [value as u8, (value >> 8) as u8, (value >> 16) as u8, (value >> 24) as u8]
+}
+
+fn u32_from_bytes(bytes: &[u8]) -> u32 {
+    bytes[0] as u32 | (bytes[1] as u32) << 8 | (bytes[2] as u32) << 16 | (bytes[3] as u32) << 24
+}
+
+/// How long a record's header (its length, the length's CRC, and the record's CRC) is.
+const HEADER_LENGTH: usize = 12;
+
+fn encode_record(record: &[u8]) -> Vec<u8> {
+    let length = u32_to_bytes(record.len() as u32);
+    let mut encoded = Vec::with_capacity(record.len() + HEADER_LENGTH);
+    encoded.extend_from_slice(&length);
+    encoded.extend_from_slice(&u32_to_bytes(crc32(&length)));
+    encoded.extend_from_slice(&u32_to_bytes(crc32(record)));
+    encoded.extend_from_slice(record);
+    encoded
+}
+
+/// Reads the next record, `None` at the end of the segment.
+fn read_record<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, RecordError> {
+    let mut header = Vec::with_capacity(HEADER_LENGTH);
+    try!(reader.take(HEADER_LENGTH as u64).read_to_end(&mut header).map_err(|e| RecordError::Corrupt(e.to_string())));
+    if header.is_empty() {
+        return Ok(None);
+    }
+    if header.len() < HEADER_LENGTH {
+        return Err(RecordError::Truncated);
+    }
+
+    let length_crc = u32_from_bytes(&header[4..8]);
+    if crc32(&header[0..4]) != length_crc {
+        return Err(RecordError::Corrupt(format!("Expected a length CRC of {:08x}, found {:08x}.", length_crc, crc32(&header[0..4]))));
+    }
+    let length = u32_from_bytes(&header[0..4]) as u64;
+    let expected_crc = u32_from_bytes(&header[8..12]);
+    // Read through `take`, so a corrupt length can't allocate more than the segment has.
+    let mut record = Vec::new();
+    try!(reader.take(length).read_to_end(&mut record).map_err(|e| RecordError::Corrupt(e.to_string())));
+    if (record.len() as u64) < length {
+        return Err(RecordError::Truncated);
+    }
+    if crc32(&record) != expected_crc {
+        return Err(RecordError::Corrupt(format!("Expected a CRC of {:08x}, found {:08x}.", expected_crc, crc32(&record))));
+    }
+    Ok(Some(record))
+}
+
+impl WalStore {
+    /// Opens (creating it if needed) the log in `dir`.
+    ///
+    /// * `segment_size` - How large a segment can get (in bytes) before a new one is started.
+    /// * `fsync` - When appends are synced to disk.
+    pub fn open(dir: &Path, segment_size: u64, fsync: FsyncPolicy) -> Result<WalStore, String> {
+        try!(fs::create_dir_all(dir).map_err(|e| format!("Failed to create the backup directory [ {:?} ]: {}", dir, e)));
+        let mut store = WalStore {
+            dir: dir.to_path_buf(),
+            segment_size: segment_size,
+            fsync: fsync,
+            active: None,
+            next_segment: 0,
+            last_sync: Instant::now(),
+            unsynced: false,
+        };
+        store.next_segment = try!(store.segments()).last().map(|last| last + 1).unwrap_or(0);
+        Ok(store)
+    }
+
+    /// The numbers of every segment, oldest first.
+    fn segments(&self) -> Result<Vec<u64>, String> {
+        let entries = try!(fs::read_dir(&self.dir).map_err(|e| format!("Failed to read the backup directory [ {:?} ]: {}", self.dir, e)));
+        let mut segments: Vec<u64> = entries.filter_map(|entry| entry.ok())
+            .filter_map(|entry| entry.file_name().to_str().map(|name| name.to_owned()))
+            .filter(|name| name.ends_with(".log"))
+            .filter_map(|name| name.trim_right_matches(".log").parse::<u64>().ok())
+            .collect();
+        segments.sort();
+        Ok(segments)
+    }
+
+    fn segment_path(&self, segment: u64) -> PathBuf {
+        self.dir.join(format!("{:020}.log", segment))
+    }
+
+    /// Syncs the current segment to disk, if there is one.
+    fn sync(&mut self) -> Result<(), String> {
+        if let Some(ref active) = self.active {
+            try!(active.file.sync_data().map_err(|e| format!("Failed to sync the backups: {}", e)));
+        }
+        self.last_sync = Instant::now();
+        self.unsynced = false;
+        Ok(())
+    }
+
+    /// Finishes the current segment, so the next append starts a new one.
+    fn roll(&mut self) -> Result<(), String> {
+        if self.fsync != FsyncPolicy::Never {
+            try!(self.sync());
+        }
+        self.active = None;
+        Ok(())
+    }
+
+    fn append(&mut self, record: &[u8]) -> Result<(), String> {
+        let encoded = encode_record(record);
+        let is_full = self.active.as_ref()
+            .map(|active| active.written > 0 && active.written + encoded.len() as u64 > self.segment_size)
+            .unwrap_or(false);
+        if is_full {
+            try!(self.roll());
+        }
+
+        if self.active.is_none() {
+            let path = self.segment_path(self.next_segment);
+            let file = try!(OpenOptions::new().append(true).create_new(true).open(&path)
+                .map_err(|e| format!("Failed to create the backup segment [ {:?} ]: {}", path, e)));
+            if self.fsync == FsyncPolicy::Always {
+                // So the new segment itself survives a crash, not just what's written to it.
+                try!(File::open(&self.dir).and_then(|dir| dir.sync_all())
+                    .map_err(|e| format!("Failed to sync the backup directory [ {:?} ]: {}", self.dir, e)));
+            }
+            self.next_segment += 1;
+            self.active = Some(Segment { file: file, written: 0 });
+        }
+
+        {
+            let active = self.active.as_mut().unwrap();
+            try!(active.file.write_all(&encoded).map_err(|e| format!("Failed to write the backup: {}", e)));
+            active.written += encoded.len() as u64;
+        }
+        self.unsynced = true;
+
+        let should_sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => self.last_sync.elapsed() >= interval,
+            FsyncPolicy::Never => false,
+        };
+        if should_sync {
+            self.sync()
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Moves every backup from another store into the log, returning how many were moved.
+    /// Backups are only removed from the other store once they've been written (and synced, unless
+    /// `fsync` is `Never`) here.
+    pub fn import(&mut self, other: &mut BackupStore) -> Result<usize, String> {
+        let mut imported = 0;
+        try!(other.replay(&mut |message: MessagePayload| {
+            let saved = self.save(&message).and_then(|_| {
+                if self.fsync != FsyncPolicy::Never { self.sync() } else { Ok(()) }
+            });
+            match saved {
+                Ok(()) => {
+                    imported += 1;
+                    Replayed::Remove
+                },
+                Err(e) => {
+                    error!("Failed to move a backup into the write-ahead log, leaving it where it was: {}", e);
+                    Replayed::Keep(message)
+                },
+            }
+        }));
+        Ok(imported)
+    }
+
+    /// Replays a single segment, returning whether every record in it could be read.
+    /// Nothing after a record that can't be read is replayed, so the segment is kept for whoever looks into it.
+    fn replay_segment(&mut self, path: &Path, resend: &mut FnMut(MessagePayload) -> Replayed) -> Result<bool, String> {
+        let file = try!(File::open(path).map_err(|e| format!("Failed to open the backup segment [ {:?} ]: {}", path, e)));
+        let mut reader = BufReader::new(file);
+
+        loop {
+            let record = match read_record(&mut reader) {
+                Ok(Some(record)) => record,
+                Ok(None) => return Ok(true),
+                Err(RecordError::Truncated) => {
+                    error!("The backup segment [ {:?} ] ends part way through a message.", path);
+                    return Ok(false);
+                },
+                Err(RecordError::Corrupt(e)) => {
+                    error!("The backup segment [ {:?} ] is corrupt: {}", path, e);
+                    return Ok(false);
+                },
+            };
+
+            let message = match String::from_utf8(record).ok().and_then(|text| json::decode::<MessagePayload>(&text).ok()) {
+                Some(message) => message,
+                None => {
+                    error!("The backup segment [ {:?} ] has a message that isn't a backup.", path);
+                    return Ok(false);
+                },
+            };
+            if let Replayed::Keep(message) = resend(message) {
+                try!(self.save(&message));
+            }
+        }
+    }
+}
+
+impl BackupStore for WalStore {
+    fn save(&mut self, message: &MessagePayload) -> Result<(), String> {
+        let record = try!(json::encode(message).map_err(|e| format!("Failed to encode the backup: {}", e)));
+        self.append(record.as_bytes())
+    }
+
+    /// Compacts the log as it goes: kept messages are written to a new segment, and each replayed
+    /// segment is removed once they're synced. Segments with corrupt, or cut short records are renamed
+    /// to `<number>.log.corrupt` rather than removed, as the messages after them can't be read.
+    /// A crash part way through means the messages of the segment being replayed are replayed again.
+    fn replay(&mut self, resend: &mut FnMut(MessagePayload) -> Replayed) -> Result<(), String> {
+        let segments = try!(self.segments());
+        try!(self.roll());
+
+        for segment in segments {
+            let path = self.segment_path(segment);
+            let readable = try!(self.replay_segment(&path, resend));
+            try!(self.sync());
+
+            if readable {
+                try!(fs::remove_file(&path).map_err(|e| format!("Failed to remove the backup segment [ {:?} ]: {}", path, e)));
+            } else {
+                let corrupt_path = path.with_extension("log.corrupt");
+                try!(fs::rename(&path, &corrupt_path)
+                    .map_err(|e| format!("Failed to move the corrupt backup segment [ {:?} ]: {}", path, e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Syncs once the interval has passed since the last sync, so a backup written just before
+    /// things go quiet doesn't wait for the next one to be synced.
+    fn tick(&mut self) -> Result<(), String> {
+        match self.fsync {
+            FsyncPolicy::Interval(interval) if self.unsynced && self.last_sync.elapsed() >= interval => self.sync(),
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Drop for WalStore {
+    fn drop(&mut self) {
+        if self.fsync != FsyncPolicy::Never {
+            let _ = self.sync();
+        }
+    }
+}
+
+#[cfg(test)]
+fn test_dir(name: &str) -> PathBuf {
+    let dir = ::std::env::temp_dir().join(format!("kafka_proxy_test_wal_{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    dir
+}
+
+#[cfg(test)]
+fn test_message(topic: &str) -> MessagePayload {
+    MessagePayload {
+        topic: topic.to_owned(),
+        payload: vec![0x00, 0xff],
+        key: None,
+        metadata: None,
+        attempts: 1,
+        error: Some("BrokerNotAvailable".to_owned()),
+    }
+}
+
+#[test]
+fn test_wal_records() {
+    assert!(crc32(b"123456789") == 0xcbf43926);
+    assert!(crc32(b"") == 0);
+
+    let encoded = encode_record(b"hello");
+    assert!(read_record(&mut &encoded[..]) == Ok(Some(b"hello".to_vec())));
+    assert!(read_record(&mut &b""[..]) == Ok(None));
+    assert!(read_record(&mut &encoded[..6]) == Err(RecordError::Truncated));
+    assert!(read_record(&mut &encoded[..14]) == Err(RecordError::Truncated));
+
+    let mut corrupt = encoded.clone();
+    corrupt[13] ^= 0xff;
+    assert!(read_record(&mut &corrupt[..]).is_err());
+    assert!(read_record(&mut &corrupt[..]) != Err(RecordError::Truncated));
+
+    // A damaged length would otherwise read to the end of the segment, as if the record was cut short.
+    let mut segment = encode_record(b"hello");
+    segment.extend(encode_record(b"world"));
+    segment[1] ^= 0x01;
+    assert!(read_record(&mut &segment[..]).is_err());
+    assert!(read_record(&mut &segment[..]) != Err(RecordError::Truncated));
+}
+
+#[test]
+fn test_wal_replay() {
+    let dir = test_dir("replay");
+    {
+        // Small enough that every message gets its own segment.
+        let mut store = WalStore::open(&dir, 16, FsyncPolicy::Never).unwrap();
+        for topic in ["orders", "audit", "search"].iter() {
+            store.save(&test_message(topic)).unwrap();
+        }
+        assert!(store.segments().unwrap().len() == 3);
+    }
+
+    let mut store = WalStore::open(&dir, 1024 * 1024, FsyncPolicy::Always).unwrap();
+    let mut replayed = Vec::new();
+    store.replay(&mut |message: MessagePayload| {
+        replayed.push(message.topic.clone());
+        if message.topic == "audit" {
+            Replayed::Keep(message.failed("RequestTimedOut".to_owned()))
+        } else {
+            Replayed::Remove
+        }
+    }).unwrap();
+    assert!(replayed == vec!["orders".to_owned(), "audit".to_owned(), "search".to_owned()]);
+    // Only the kept message is left, in a new segment.
+    assert!(store.segments().unwrap().len() == 1);
+
+    let mut kept = Vec::new();
+    store.replay(&mut |message: MessagePayload| {
+        kept.push(message);
+        Replayed::Remove
+    }).unwrap();
+    assert!(kept.len() == 1 && kept[0].topic == "audit" && kept[0].attempts == 2);
+    assert!(store.segments().unwrap().is_empty());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_wal_damaged_segments() {
+    let dir = test_dir("damaged");
+    {
+        let mut store = WalStore::open(&dir, 1024 * 1024, FsyncPolicy::Never).unwrap();
+        store.save(&test_message("orders")).unwrap();
+        store.save(&test_message("audit")).unwrap();
+    }
+    // A crash part way through writing a message.
+    OpenOptions::new().append(true).open(dir.join(format!("{:020}.log", 0))).unwrap().write_all(&[42, 0, 0]).unwrap();
+    {
+        let mut store = WalStore::open(&dir, 1024 * 1024, FsyncPolicy::Never).unwrap();
+        store.save(&test_message("search")).unwrap();
+    }
+    // Corrupt the message in the second segment.
+    let second = dir.join(format!("{:020}.log", 1));
+    let mut contents = Vec::new();
+    File::open(&second).unwrap().read_to_end(&mut contents).unwrap();
+    let last = contents.len() - 1;
+    contents[last] ^= 0xff;
+    File::create(&second).unwrap().write_all(&contents).unwrap();
+
+    let mut store = WalStore::open(&dir, 1024 * 1024, FsyncPolicy::Never).unwrap();
+    let mut replayed = Vec::new();
+    store.replay(&mut |message: MessagePayload| {
+        replayed.push(message.topic.clone());
+        Replayed::Remove
+    }).unwrap();
+    assert!(replayed == vec!["orders".to_owned(), "audit".to_owned()]);
+    assert!(store.segments().unwrap().is_empty());
+    assert!(dir.join(format!("{:020}.log.corrupt", 0)).exists());
+    assert!(second.with_extension("log.corrupt").exists());
+    let _ = fs::remove_dir_all(&dir);
+}
+
+#[test]
+fn test_wal_interval_sync() {
+    let dir = test_dir("interval");
+    let mut store = WalStore::open(&dir, 1024 * 1024, FsyncPolicy::Interval(::std::time::Duration::from_millis(10))).unwrap();
+    store.save(&test_message("orders")).unwrap();
+    assert!(store.unsynced);
+    store.tick().unwrap();
+    assert!(store.unsynced);
+
+    // Nothing else is saved, so it's the tick that syncs it.
+    ::std::thread::sleep(::std::time::Duration::from_millis(20));
+    store.tick().unwrap();
+    assert!(!store.unsynced);
+
+    // Importing from another store moves its backups over.
+    let mut other = WalStore::open(&test_dir("import"), 1024 * 1024, FsyncPolicy::Never).unwrap();
+    other.save(&test_message("audit")).unwrap();
+    assert!(store.import(&mut other).unwrap() == 1);
+    assert!(other.segments().unwrap().is_empty());
+    let mut replayed = Vec::new();
+    store.replay(&mut |message: MessagePayload| {
+        replayed.push(message.topic.clone());
+        Replayed::Remove
+    }).unwrap();
+    assert!(replayed == vec!["orders".to_owned(), "audit".to_owned()]);
+    let _ = fs::remove_dir_all(&dir);
+    let _ = fs::remove_dir_all(test_dir("import"));
+}
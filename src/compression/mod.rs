@@ -0,0 +1,126 @@
+use flate2::read::{DeflateDecoder, GzDecoder, ZlibDecoder};
+use std::io;
+use ::utils;
+
+#[cfg(feature = "encoding-zstd")]
+use zstd;
+#[cfg(feature = "encoding-brotli")]
+use brotli;
+
+/// Why a compressed body couldn't be decoded.
+/// `Unsupported` - The Content-Encoding isn't one we know (or was built without).
+/// `TooLarge` - The body is larger than the limit once decompressed.
+/// `Invalid` - The body isn't valid for its Content-Encoding.
+#[derive(Debug, PartialEq)]
+pub enum DecodeError {
+    Unsupported(String),
+    TooLarge,
+    Invalid(String),
+}
+
+/// Reads the Content-Encodings of a request, in the order they were applied.
+/// `identity` is dropped, as it doesn't change anything.
+pub fn parse_content_encoding(header: &str) -> Vec<String> {
+    header.split(',')
+        .map(|encoding| encoding.trim().to_lowercase())
+        .filter(|encoding| !encoding.is_empty() && encoding != "identity")
+        .collect()
+}
+
+/// Decodes a body with a single Content-Encoding, reading at most `limit` bytes of output.
+fn decode_one(encoding: &str, body: &[u8], limit: usize) -> Result<Vec<u8>, DecodeError> {
+    let decoded: io::Result<Option<Vec<u8>>> = match encoding {
+        "gzip" | "x-gzip" => GzDecoder::new(body).and_then(|mut decoder| utils::read_limited(&mut decoder, limit)),
+        // `deflate` is meant to be zlib wrapped, but plenty of clients send raw deflate.
+        "deflate" => utils::read_limited(&mut ZlibDecoder::new(body), limit)
+            .or_else(|_| utils::read_limited(&mut DeflateDecoder::new(body), limit)),
+        "zstd" => decode_zstd(body, limit),
+        "br" => decode_brotli(body, limit),
+        other => return Err(DecodeError::Unsupported(other.to_owned())),
+    };
+
+    match decoded {
+        Ok(Some(decoded)) => Ok(decoded),
+        Ok(None) => Err(DecodeError::TooLarge),
+        Err(e) => Err(DecodeError::Invalid(format!("Invalid {} body: {}", encoding, e))),
+    }
+}
+
+#[cfg(feature = "encoding-zstd")]
+fn decode_zstd(body: &[u8], limit: usize) -> io::Result<Option<Vec<u8>>> {
+    zstd::stream::Decoder::new(body).and_then(|mut decoder| utils::read_limited(&mut decoder, limit))
+}
+
+#[cfg(not(feature = "encoding-zstd"))]
+fn decode_zstd(_: &[u8], _: usize) -> io::Result<Option<Vec<u8>>> {
+    Err(io::Error::new(io::ErrorKind::Other, "zstd is only supported with the encoding-zstd feature"))
+}
+
+#[cfg(feature = "encoding-brotli")]
+fn decode_brotli(body: &[u8], limit: usize) -> io::Result<Option<Vec<u8>>> {
+    utils::read_limited(&mut brotli::Decompressor::new(body, 4096), limit)
+}
+
+#[cfg(not(feature = "encoding-brotli"))]
+fn decode_brotli(_: &[u8], _: usize) -> io::Result<Option<Vec<u8>>> {
+    Err(io::Error::new(io::ErrorKind::Other, "br is only supported with the encoding-brotli feature"))
+}
+
+/// Whether an encoding can be decoded by this build.
+pub fn is_supported(encoding: &str) -> bool {
+    match encoding {
+        "gzip" | "x-gzip" | "deflate" => true,
+        "zstd" => cfg!(feature = "encoding-zstd"),
+        "br" => cfg!(feature = "encoding-brotli"),
+        _ => false,
+    }
+}
+
+/// Decodes a body with every Content-Encoding it was sent with.
+///
+/// * `encodings` - The encodings, in the order they were applied (as from `parse_content_encoding`).
+/// * `body` - The body as it was received.
+/// * `limit` - The largest the body may be once decompressed, so a small
+///   compressed body can't expand into an enormous one.
+pub fn decode(encodings: &[String], body: Vec<u8>, limit: usize) -> Result<Vec<u8>, DecodeError> {
+    if let Some(encoding) = encodings.iter().find(|encoding| !is_supported(encoding)) {
+        return Err(DecodeError::Unsupported(encoding.clone()));
+    }
+
+    let mut body = body;
+    for encoding in encodings.iter().rev() {
+        body = try!(decode_one(encoding, &body, limit));
+    }
+    Ok(body)
+}
+
+/// Gzips a body, for the tests.
+#[cfg(test)]
+fn compress_gzip(body: &[u8]) -> Vec<u8> {
+    use flate2::Compression;
+    use flate2::write::GzEncoder;
+    use std::io::Write;
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::Default);
+    encoder.write_all(body).unwrap();
+    encoder.finish().unwrap()
+}
+
+#[test]
+fn test_content_encoding_parsing() {
+    assert!(parse_content_encoding("gzip") == vec!["gzip".to_owned()]);
+    assert!(parse_content_encoding("Deflate, gzip") == vec!["deflate".to_owned(), "gzip".to_owned()]);
+    assert!(parse_content_encoding("identity").is_empty());
+}
+
+#[test]
+fn test_gzip_decoding() {
+    let compressed = compress_gzip(b"hello, hello, hello, hello");
+    let encodings = vec!["gzip".to_owned()];
+
+    assert!(decode(&encodings, compressed.clone(), 1024).unwrap() == b"hello, hello, hello, hello".to_vec());
+    // Small when compressed, but over the limit once it isn't.
+    assert!(decode(&encodings, compressed, 10) == Err(DecodeError::TooLarge));
+    assert!(decode(&encodings, b"not gzip".to_vec(), 1024).is_err());
+    assert!(decode(&vec!["compress".to_owned()], b"anything".to_vec(), 1024) == Err(DecodeError::Unsupported("compress".to_owned())));
+}
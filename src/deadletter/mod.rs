@@ -0,0 +1,109 @@
+use jfs::Store;
+use kafka::producer::{Producer, Record};
+use models::{DeadLetterConfig, MessagePayload};
+use rustc_serialize::json;
+use std::sync::{Arc, Mutex};
+
+/// Where messages go once kafka has failed on them too many times, so they stop
+/// being retried on every boot.
+pub struct DeadLetters {
+    max_attempts: u32,
+    topic: Option<String>,
+    quarantine: Option<Store>,
+}
+
+impl DeadLetters {
+    /// Opens the quarantine directory, when there is one.
+    pub fn new(config: &DeadLetterConfig) -> Result<DeadLetters, String> {
+        let quarantine = match config.quarantine_dir {
+            Some(ref dir) => {
+                let dir = try!(dir.to_str().ok_or(format!("Invalid quarantine directory: [ {:?} ].", dir)));
+                Some(try!(Store::new(dir).map_err(|e| format!("Failed to open the quarantine directory [ {} ]: {:?}", dir, e))))
+            },
+            None => None,
+        };
+
+        Ok(DeadLetters {
+            max_attempts: config.max_attempts,
+            topic: config.topic.clone(),
+            quarantine: quarantine,
+        })
+    }
+
+    /// Whether kafka has failed on a message enough times to give up on it.
+    pub fn is_exhausted(&self, message: &MessagePayload) -> bool {
+        message.attempts >= self.max_attempts
+    }
+
+    /// Sends a message to the dead-letter topic, or when there's no topic (or kafka fails on it too)
+    /// saves it in the quarantine directory. The dead-letter topic gets the message as its backup JSON,
+    /// so the original topic, attempts, and last error are kept with it. Messages kafka refused as
+    /// too large (`too_large`) go straight to the quarantine, since their backup JSON is larger still.
+    ///
+    /// Returns why it couldn't go to either.
+    pub fn dead_letter(&self, producer: Option<&Arc<Mutex<Producer>>>, message: &MessagePayload, too_large: bool) -> Result<(), String> {
+        let mut errors = Vec::new();
+
+        if let (Some(topic), Some(producer), false) = (self.topic.as_ref(), producer, too_large) {
+            let attempt_to_send = producer.lock().unwrap().send(&Record {
+                topic: topic,
+                partition: -1,
+                key: message.key.clone().unwrap_or(Vec::new()),
+                value: json::encode(message).unwrap().into_bytes(),
+            });
+            match attempt_to_send {
+                Ok(_) => return Ok(()),
+                Err(e) => errors.push(format!("Failed to send to the dead-letter topic [ {} ]: {:?}", topic, e)),
+            }
+        }
+
+        if let Some(ref quarantine) = self.quarantine {
+            match quarantine.save(message) {
+                Ok(_) => return Ok(()),
+                Err(e) => errors.push(format!("Failed to quarantine: {:?}", e)),
+            }
+        }
+
+        if errors.is_empty() {
+            errors.push("There's nowhere to dead-letter it.".to_owned());
+        }
+        Err(errors.join(", "))
+    }
+}
+
+#[test]
+fn test_dead_letters() {
+    let quarantine_dir = ::std::env::temp_dir().join("kafka_proxy_test_quarantine");
+    let dead_letters = DeadLetters::new(&DeadLetterConfig {
+        max_attempts: 2,
+        topic: Some("dead-letters".to_owned()),
+        quarantine_dir: Some(quarantine_dir.clone()),
+    }).unwrap();
+
+    let message = MessagePayload {
+        topic: "orders".to_owned(),
+        payload: b"{}".to_vec(),
+        key: None,
+        metadata: None,
+        attempts: 0,
+        error: None,
+    };
+    let message = message.failed("RequestTimedOut".to_owned());
+    assert!(!dead_letters.is_exhausted(&message));
+    let message = message.failed("MessageSizeTooLarge".to_owned());
+    assert!(dead_letters.is_exhausted(&message));
+
+    // Without a producer it can only be quarantined, with the error it failed with.
+    dead_letters.dead_letter(None, &message, false).unwrap();
+    let quarantined = dead_letters.quarantine.as_ref().unwrap().get_all::<MessagePayload>().unwrap();
+    assert!(quarantined.values().any(|quarantined| *quarantined == message));
+    let _ = ::std::fs::remove_dir_all(&quarantine_dir);
+
+    let topic_only = DeadLetters::new(&DeadLetterConfig {
+        max_attempts: 2,
+        topic: Some("dead-letters".to_owned()),
+        quarantine_dir: None,
+    }).unwrap();
+    assert!(topic_only.dead_letter(None, &message, false).is_err());
+    assert!(topic_only.dead_letter(None, &message, true).unwrap_err() == "There's nowhere to dead-letter it.");
+}
@@ -0,0 +1,133 @@
+use models::MessageMetadata;
+use ring::rand::SecureRandom;
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json};
+use std::collections::BTreeMap;
+use std::env;
+use std::fs::File;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// The hostname of the machine the proxy is running on. From `HOSTNAME` when it's set,
+/// otherwise from the kernel, or `/etc/hostname`. `unknown` if none of those work.
+pub fn local_hostname() -> String {
+    if let Ok(hostname) = env::var("HOSTNAME") {
+        if !hostname.trim().is_empty() {
+            return hostname.trim().to_owned();
+        }
+    }
+
+    for path in ["/proc/sys/kernel/hostname", "/etc/hostname"].iter() {
+        let mut contents = String::new();
+        if File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).is_ok() && !contents.trim().is_empty() {
+            return contents.trim().to_owned();
+        }
+    }
+    "unknown".to_owned()
+}
+
+/// A random (version 4) UUID, for message ids.
+pub fn generate_message_id(random: &SecureRandom) -> Result<String, String> {
+    let mut bytes = [0u8; 16];
+    try!(random.fill(&mut bytes).map_err(|_| "Failed to generate a message id.".to_owned()));
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+
+    let hex = bytes.to_hex();
+    Ok(format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32]))
+}
+
+/// Formats a time as an RFC 3339 UTC timestamp with milliseconds, e.g. `2017-01-18T13:04:05.123Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or(Duration::from_secs(0));
+    let seconds = since_epoch.as_secs() as i64;
+    let (year, month, day) = civil_from_days(seconds / 86400);
+    let seconds_of_day = seconds % 86400;
+
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z", year, month, day, seconds_of_day / 3600,
+        seconds_of_day / 60 % 60, seconds_of_day % 60, since_epoch.subsec_nanos() / 1000000)
+}
+
+/// The date in the proleptic gregorian calendar a number of days after the unix epoch.
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = if days >= 0 { days } else { days - 146096 } / 146097;
+    let day_of_era = days - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// Builds the metadata for a message as it's received.
+///
+/// * `random` - Where the message id comes from.
+/// * `hostname` - The hostname of this proxy.
+/// * `client` - The identity the request was authenticated as, if any.
+/// * `source_ip` - The IP the request came from.
+pub fn metadata(random: &SecureRandom, hostname: &str, client: Option<String>, source_ip: String) -> Result<MessageMetadata, String> {
+    Ok(MessageMetadata {
+        received_at: format_timestamp(SystemTime::now()),
+        hostname: hostname.to_owned(),
+        message_id: try!(generate_message_id(random)),
+        client: client,
+        source_ip: source_ip,
+    })
+}
+
+/// Wraps a JSON body in an envelope with its metadata:
+/// `{"metadata": {"received_at": ..., "hostname": ..., ...}, "payload": <the body>}`.
+pub fn wrap(metadata: &MessageMetadata, body: &[u8]) -> Result<Vec<u8>, String> {
+    try!(::utils::check_json_depth(body));
+    let text = try!(::std::str::from_utf8(body).map_err(|_| "The body isn't valid UTF-8.".to_owned()));
+    let payload = try!(Json::from_str(text).map_err(|e| format!("The body isn't valid JSON: {}", e)));
+    let metadata = try!(Json::from_str(&json::encode(metadata).unwrap()).map_err(|e| e.to_string()));
+
+    let mut envelope = BTreeMap::new();
+    envelope.insert("metadata".to_owned(), metadata);
+    envelope.insert("payload".to_owned(), payload);
+    Ok(json::encode(&Json::Object(envelope)).unwrap().into_bytes())
+}
+
+/// A SecureRandom that always gives the same bytes, for the tests.
+#[cfg(test)]
+struct FixedRandom(u8);
+
+#[cfg(test)]
+impl SecureRandom for FixedRandom {
+    fn fill(&self, dest: &mut [u8]) -> Result<(), ::ring::error::Unspecified> {
+        for byte in dest.iter_mut() {
+            *byte = self.0;
+        }
+        Ok(())
+    }
+}
+
+#[test]
+fn test_timestamp_formatting() {
+    assert!(format_timestamp(UNIX_EPOCH) == "1970-01-01T00:00:00.000Z".to_owned());
+    assert!(format_timestamp(UNIX_EPOCH + Duration::new(1484744645, 123000000)) == "2017-01-18T13:04:05.123Z".to_owned());
+    assert!(format_timestamp(UNIX_EPOCH + Duration::from_secs(951782400)) == "2000-02-29T00:00:00.000Z".to_owned());
+}
+
+#[test]
+fn test_envelope_wrapping() {
+    let id = generate_message_id(&FixedRandom(0xff)).unwrap();
+    assert!(id == "ffffffff-ffff-4fff-bfff-ffffffffffff".to_owned());
+    assert!(generate_message_id(&::ring::rand::SystemRandom::new()).unwrap() != generate_message_id(&::ring::rand::SystemRandom::new()).unwrap());
+
+    let metadata = metadata(&FixedRandom(0), "proxy-1", Some("billing".to_owned()), "10.0.0.7".to_owned()).unwrap();
+    let wrapped = wrap(&metadata, br#"{"id": 1}"#).unwrap();
+    let envelope = Json::from_str(&String::from_utf8(wrapped).unwrap()).unwrap();
+
+    assert!(envelope.find("payload") == Some(&Json::from_str(r#"{"id": 1}"#).unwrap()));
+    assert!(envelope.find_path(&["metadata", "hostname"]).and_then(|value| value.as_string()) == Some("proxy-1"));
+    assert!(envelope.find_path(&["metadata", "client"]).and_then(|value| value.as_string()) == Some("billing"));
+    assert!(envelope.find_path(&["metadata", "source_ip"]).and_then(|value| value.as_string()) == Some("10.0.0.7"));
+    assert!(envelope.find_path(&["metadata", "message_id"]).and_then(|value| value.as_string())
+        == Some("00000000-0000-4000-8000-000000000000"));
+    assert!(wrap(&metadata, b"not json").is_err());
+}
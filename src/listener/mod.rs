@@ -0,0 +1,152 @@
+use hyper::net::{Fresh, HttpStream, Openssl};
+use hyper::server::{Handler as HyperHandler, Listening, Request as HyperRequest, Response as HyperResponse, Server};
+use hbs::{HandlebarsEngine, MemorySource};
+use iron::prelude::*;
+use iron::{Handler, Protocol};
+use openssl::ssl::SslStream;
+use router::Router;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::path::Path;
+use std::sync::Arc;
+use ::models::{HttpsConfig, ListenerRole};
+use ::{tls, utils};
+
+#[cfg(unix)]
+pub mod unix;
+
+/// The prefix marking a listener address as a Unix Socket path.
+pub const UNIX_SOCKET_PREFIX: &'static str = "unix:";
+
+/// The header the verified client certificate subject is passed to Iron in.
+/// Any copy of it sent by the caller is removed before the request is handled,
+/// so handlers can trust it.
+pub const CLIENT_SUBJECT_HEADER: &'static str = "X-Client-Cert-Subject";
+
+/// Wraps Iron so we can see the connection before Iron turns it into a request.
+/// Iron doesn't expose the TLS session, so this copies the common name of the
+/// verified client certificate (if any) into `CLIENT_SUBJECT_HEADER`.
+pub struct ClientCertHandler<H: Handler> {
+    iron: Iron<H>,
+}
+
+impl<H: Handler> HyperHandler for ClientCertHandler<H> {
+    fn handle(&self, mut req: HyperRequest, res: HyperResponse<Fresh>) {
+        req.headers.remove_raw(CLIENT_SUBJECT_HEADER);
+
+        let subject = req.ssl::<SslStream<HttpStream>>()
+            .and_then(|stream| stream.ssl().peer_certificate())
+            .and_then(|certificate| tls::subject_common_name(&certificate));
+        if let Some(subject) = subject {
+            req.headers.set_raw(CLIENT_SUBJECT_HEADER, vec![subject.into_bytes()]);
+        }
+
+        self.iron.handle(req, res)
+    }
+}
+
+/// Starts serving `handler` on `addr`.
+///
+/// * `handler` - The Iron Handler (usually a Chain) to serve.
+/// * `addr` - The address to listen on, in IP:PORT form, or `unix:<path>` for a Unix Socket.
+/// * `https` - The HTTPS Configuration, or None to serve plain HTTP. Ignored for Unix Sockets.
+/// * `unix_socket_mode` - The permissions to give a Unix Socket.
+///
+/// Returns the Listening guard, which blocks until the server stops when dropped.
+pub fn serve<H: Handler>(handler: H, addr: &str, https: Option<&HttpsConfig>,
+                         unix_socket_mode: u32) -> Result<Listening, String> {
+    if addr.starts_with(UNIX_SOCKET_PREFIX) {
+        return serve_unix(handler, &addr[UNIX_SOCKET_PREFIX.len()..], unix_socket_mode);
+    }
+
+    let sock_addr: SocketAddr = try!(try!(addr.to_socket_addrs()
+        .map_err(|e| format!("Invalid listen address [ {} ]: {}", addr, e)))
+        .next().ok_or(format!("Invalid listen address [ {} ]", addr)));
+
+    let mut iron = Iron::new(handler);
+    iron.addr = Some(sock_addr);
+
+    match https {
+        Some(https) => {
+            let context = try!(tls::build_server_context(https));
+            iron.protocol = Some(Protocol::Https {
+                certificate: https.cert_path.clone(),
+                key: https.key_path.clone(),
+            });
+            let server = try!(Server::https(sock_addr, Openssl { context: Arc::new(context) })
+                .map_err(|e| format!("Failed to listen on [ {} ]: {}", addr, e)));
+            server.handle(ClientCertHandler { iron: iron })
+                .map_err(|e| format!("Failed to serve on [ {} ]: {}", addr, e))
+        },
+        None => {
+            iron.protocol = Some(Protocol::Http);
+            let server = try!(Server::http(sock_addr)
+                .map_err(|e| format!("Failed to listen on [ {} ]: {}", addr, e)));
+            server.handle(ClientCertHandler { iron: iron })
+                .map_err(|e| format!("Failed to serve on [ {} ]: {}", addr, e))
+        }
+    }
+}
+
+/// Starts serving `handler` on a Unix Socket at `socket_path`.
+/// Always plain HTTP, access is controlled by the permissions on the socket.
+#[cfg(unix)]
+fn serve_unix<H: Handler>(handler: H, socket_path: &str, mode: u32) -> Result<Listening, String> {
+    let listener = try!(unix::UnixSocketListener::bind(Path::new(socket_path), mode));
+
+    let mut iron = Iron::new(handler);
+    iron.addr = Some(unix::unix_socket_addr());
+    iron.protocol = Some(Protocol::Http);
+    Server::new(listener).handle(ClientCertHandler { iron: iron })
+        .map_err(|e| format!("Failed to serve on [ {} ]: {}", socket_path, e))
+}
+
+#[cfg(not(unix))]
+fn serve_unix<H: Handler>(_: H, socket_path: &str, _: u32) -> Result<Listening, String> {
+    Err(format!("Unix Sockets aren't supported on this platform: [ {} ]", socket_path))
+}
+
+/// The handlers every listener serves a subset of, based on its roles.
+/// Each is shared between listeners, so they're behind an Arc.
+#[derive(Clone)]
+pub struct Handlers {
+    pub ingest: Arc<Box<Handler>>,
+    pub ui: Arc<Box<Handler>>,
+    pub metrics: Arc<Box<Handler>>,
+}
+
+/// A Handler that is shared between listeners.
+struct SharedHandler(Arc<Box<Handler>>);
+
+impl Handler for SharedHandler {
+    fn handle(&self, req: &mut Request) -> IronResult<Response> {
+        self.0.handle(req)
+    }
+}
+
+/// Builds the Chain for a listener, with a route for each of its roles.
+///
+/// * `roles` - The roles of the listener.
+/// * `handlers` - The handlers to route to.
+///
+/// Returns the Chain, or why the web UI templates couldn't be loaded.
+pub fn build_chain(roles: &[ListenerRole], handlers: &Handlers) -> Result<Chain, String> {
+    let mut router = Router::new();
+    if roles.contains(&ListenerRole::Ingest) || roles.contains(&ListenerRole::Admin) {
+        router.post("/kafka/:topic", SharedHandler(handlers.ingest.clone()));
+        router.post("/route", SharedHandler(handlers.ingest.clone()));
+    }
+    if roles.contains(&ListenerRole::Admin) {
+        router.get("/", SharedHandler(handlers.ui.clone()));
+    }
+    if roles.contains(&ListenerRole::Metrics) {
+        router.get("/metrics", SharedHandler(handlers.metrics.clone()));
+    }
+
+    let mut hbse = HandlebarsEngine::new();
+    hbse.add(Box::new(MemorySource(utils::get_mem_templates())));
+    try!(hbse.reload().map_err(|e| format!("Failed to load HandlebarsEngine templates: {}", e)));
+
+    let mut chain = Chain::new(router);
+    chain.link_after(hbse);
+    Ok(chain)
+}
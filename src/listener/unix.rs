@@ -0,0 +1,95 @@
+use hyper;
+use hyper::net::{NetworkListener, NetworkStream};
+use std::fs;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// The address reported for Unix Socket connections.
+/// Unix Sockets don't have an IP:PORT, but hyper, and iron both expect one.
+pub fn unix_socket_addr() -> SocketAddr {
+    SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), 0)
+}
+
+/// A Unix Socket Listener hyper can serve from.
+#[derive(Clone)]
+pub struct UnixSocketListener(Arc<UnixListener>);
+
+impl UnixSocketListener {
+    /// Binds a Unix Socket at `path`, and sets its permissions to `mode`.
+    /// A socket left behind by a previous run is removed first, anything
+    /// else already at `path` is left alone, and is an error.
+    pub fn bind(path: &path::Path, mode: u32) -> Result<UnixSocketListener, String> {
+        if let Ok(metadata) = fs::symlink_metadata(path) {
+            if !metadata.file_type().is_socket() {
+                return Err(format!("[ {:?} ] already exists, and isn't a socket.", path));
+            }
+            try!(fs::remove_file(path).map_err(|e| format!("Failed to remove stale socket [ {:?} ]: {}", path, e)));
+        }
+
+        let listener = try!(UnixListener::bind(path).map_err(|e| format!("Failed to listen on [ {:?} ]: {}", path, e)));
+        try!(fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .map_err(|e| format!("Failed to set the permissions of [ {:?} ]: {}", path, e)));
+        Ok(UnixSocketListener(Arc::new(listener)))
+    }
+}
+
+impl NetworkListener for UnixSocketListener {
+    type Stream = UnixSocketStream;
+
+    fn accept(&mut self) -> hyper::Result<UnixSocketStream> {
+        let (stream, _) = try!(self.0.accept());
+        Ok(UnixSocketStream(stream))
+    }
+
+    fn local_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unix_socket_addr())
+    }
+}
+
+/// A connection accepted on a Unix Socket.
+pub struct UnixSocketStream(UnixStream);
+
+impl Clone for UnixSocketStream {
+    fn clone(&self) -> UnixSocketStream {
+        UnixSocketStream(self.0.try_clone().unwrap())
+    }
+}
+
+impl Read for UnixSocketStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl Write for UnixSocketStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+impl NetworkStream for UnixSocketStream {
+    fn peer_addr(&mut self) -> io::Result<SocketAddr> {
+        Ok(unix_socket_addr())
+    }
+
+    fn set_read_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_read_timeout(dur)
+    }
+
+    fn set_write_timeout(&self, dur: Option<Duration>) -> io::Result<()> {
+        self.0.set_write_timeout(dur)
+    }
+
+    fn close(&mut self, how: Shutdown) -> io::Result<()> {
+        self.0.shutdown(how)
+    }
+}
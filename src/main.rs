@@ -1,7 +1,6 @@
-extern crate bodyparser;
 extern crate clap;
 extern crate iron;
-#[macro_use]
+extern crate hyper;
 extern crate router;
 extern crate handlebars;
 extern crate handlebars_iron as hbs;
@@ -12,11 +11,15 @@ extern crate openssl;
 extern crate jfs;
 #[macro_use]
 extern crate lazy_static;
+extern crate regex;
 extern crate rustc_serialize;
+extern crate ring;
+extern crate untrusted;
 
 #[macro_use]
 extern crate log;
 extern crate env_logger;
+extern crate flate2;
 
 #[cfg(feature = "stats-prometheus")]
 #[macro_use]
@@ -25,76 +28,305 @@ extern crate prometheus;
 extern crate cadence;
 #[cfg(feature = "reporter-slack")]
 extern crate slack_hook;
+#[cfg(feature = "encoding-zstd")]
+extern crate zstd;
+#[cfg(feature = "encoding-brotli")]
+extern crate brotli;
 
+mod auth;
+mod avro;
+mod backup;
+mod compression;
+mod deadletter;
+mod envelope;
+mod listener;
 mod models;
+mod protobuf;
+mod ratelimit;
+mod redaction;
 mod reporter;
+mod routing;
+mod schema;
 mod stats;
+mod tls;
+mod transform;
 mod utils;
 
 use iron::prelude::*;
-use iron::status;
-use hbs::{Template, HandlebarsEngine, MemorySource};
-use jfs::Store;
+use iron::{status, Handler};
+use iron::headers::{ContentLength, ContentType};
+use iron::modifiers::Header;
+use hbs::Template;
 use kafka::client::{SecurityConfig, KafkaClient};
 use kafka::producer::{Producer, Record};
-use models::MessagePayload;
-use openssl::ssl::{SslContext, SslMethod};
-use openssl::x509::X509FileType;
+use auth::Identity;
+use models::{Configuration, ListenerRole, MessagePayload, SecurityMode, TopicResult};
+use ring::rand::SystemRandom;
 use router::Router;
-use std::{env, path, thread};
+use rustc_serialize::json::{self, Json};
+use std::collections::HashMap;
+use std::{process, thread};
 use std::sync::{Arc, Mutex, mpsc};
+use std::time::{Duration, Instant};
 
-/// Loads a Secure Kafka Client.
+/// Loads a Kafka Client. Secure unless the security mode is "plaintext".
 ///
-/// * `cert_path` - The path to the certificate to use.
-/// * `key_path` - The path to the key to use.
-/// * `brokers` - A list of brokers in IP:PORT configuration.
+/// * `config` - The parsed configuration, containing the security mode,
+///   certificate, key, brokers, and TLS options to use.
 ///
-/// Returns a Kafka Client.
-fn load_kafka_client(cert_path: path::PathBuf, key_path: path::PathBuf, brokers: Vec<String>) -> KafkaClient {
+/// Returns a Kafka Client, or why the TLS options couldn't be applied.
+fn load_kafka_client(config: &Configuration) -> Result<KafkaClient, String> {
     debug!("Initializing Kafka Client.");
-    if env::var("NO_SSL").is_err() {
-        let mut context = SslContext::new(SslMethod::Tlsv1).unwrap();
-        context.set_cipher_list("DEFAULT").unwrap();
-        context.set_certificate_file(&cert_path, X509FileType::PEM).unwrap();
-        context.set_private_key_file(&key_path, X509FileType::PEM).unwrap();
+    if config.security != SecurityMode::Plaintext {
+        let context = try!(tls::build_context(config));
+        if let Some(broker) = config.brokers.first() {
+            tls::log_negotiated(&context, broker);
+        }
 
-        KafkaClient::new_secure(brokers, SecurityConfig::new(context))
+        Ok(KafkaClient::new_secure(config.brokers.clone(), SecurityConfig::new(context)))
     } else {
-        KafkaClient::new(brokers)
+        Ok(KafkaClient::new(config.brokers.clone()))
     }
 }
 
+/// Loads a Kafka Client, and creates a Producer from it.
+fn load_producer(config: &Configuration) -> Result<Producer, String> {
+    let kafka_client = try!(load_kafka_client(config));
+    Producer::from_client(kafka_client).create()
+        .map_err(|e| format!("Failed to create a kafka producer: {:?}", e))
+}
+
+/// Spawns a thread that watches the client certificate, and key.
+/// When they change a new producer is created with them, and swapped in
+/// for the current one. Messages waiting to be sent stay queued in the
+/// channel while this happens, so nothing is dropped. If the new producer
+/// can't be created we keep using the old one.
+/// Also reports how long the certificate has left before it expires.
+fn start_cert_reloader(config: Configuration, producer: Arc<Mutex<Producer>>,
+                       stats_reporter: Arc<Mutex<mpsc::Sender<stats::Stat>>>) {
+    let (cert_path, key_path) = match (config.cert_path.clone(), config.key_path.clone()) {
+        (Some(cert_path), Some(key_path)) => (cert_path, key_path),
+        _ => return,
+    };
+    let interval = Duration::from_secs(config.cert_reload_interval);
+
+    thread::spawn(move || {
+        let mut watcher = tls::CertWatcher::new(cert_path, key_path);
+        loop {
+            match watcher.seconds_until_expiry() {
+                Ok(seconds) => {
+                    let _ = stats_reporter.lock().unwrap().send(stats::Stat::CertificateExpiry(seconds));
+                },
+                Err(e) => warn!("{}", e),
+            }
+
+            thread::sleep(interval);
+
+            if watcher.changed() {
+                info!("Kafka client certificate changed, reloading.");
+                match load_producer(&config) {
+                    Ok(new_producer) => {
+                        *producer.lock().unwrap() = new_producer;
+                        watcher.mark_loaded();
+                        info!("Reloaded the kafka client certificate.");
+                    },
+                    Err(e) => {
+                        error!("Failed to reload the kafka client certificate, keeping the old one, and retrying: {}", e);
+                    }
+                }
+            }
+        }
+    });
+}
+
 fn main() {
     env_logger::init().unwrap();
 
     info!("Starting Kafka-Proxy.");
     let matches = utils::initialize_app().get_matches();
     info!("Parsing Options...");
-    let config = utils::get_args(matches);
+    let config = match utils::get_args(matches) {
+        Ok(config) => config,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
     let copied_dry_run = config.dry_run;
     let copied_panic = config.panic_on_backup;
     info!("Done Parsing Options.");
 
+    let keystore = match config.keystore_path {
+        Some(ref keystore_path) => {
+            info!("Loading the API Keystore.");
+            match auth::KeyStore::load(keystore_path) {
+                Ok(keystore) => Some(keystore),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    let jwt_verifier = match config.jwt {
+        Some(ref jwt) => {
+            info!("Loading the JWKS.");
+            match auth::jwt::JwtVerifier::load(&jwt.jwks_path, jwt.audience.clone(), jwt.issuer.clone(),
+                                               jwt.topic_claim.clone(), jwt.leeway) {
+                Ok(verifier) => Some(verifier),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    if keystore.is_none() && jwt_verifier.is_none() {
+        warn!("No API Keystore, or JWKS configured, anyone who can reach the proxy can write to any topic.");
+    }
+
+    let rate_limits = match config.rate_limits_path {
+        Some(ref rate_limits_path) => {
+            info!("Loading the Rate Limits.");
+            match ratelimit::RateLimits::load(rate_limits_path) {
+                Ok(rate_limits) => Some(rate_limits),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    let schemas = match config.schema_dir {
+        Some(ref schema_dir) => {
+            info!("Loading the Topic Schemas.");
+            match schema::SchemaStore::load(schema_dir) {
+                Ok(schemas) => Some(schemas),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    let schema_registry = config.schema_registry.as_ref().map(|registry_config| {
+        info!("Using the Schema Registry at: [ {} ].", registry_config.url);
+        (registry_config.avro_topics.clone(), avro::registry::SchemaRegistry::new(&registry_config.url,
+            registry_config.schema_dir.clone(), Duration::from_secs(registry_config.cache_seconds)))
+    });
+
+    let protobuf_topics = match config.protobuf {
+        Some(ref protobuf_config) => {
+            info!("Loading the Protobuf Descriptor Set.");
+            match protobuf::ProtobufTopics::load(&protobuf_config.descriptor_set, protobuf_config.topics.clone()) {
+                Ok(protobuf_topics) => Some(protobuf_topics),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    let transforms = match config.transforms_path {
+        Some(ref transforms_path) => {
+            info!("Loading the Transforms.");
+            match transform::Transforms::load(transforms_path) {
+                Ok(transforms) => Some(transforms),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    let redactor = match config.redaction_rules_path {
+        Some(ref redaction_rules_path) => {
+            info!("Loading the Redaction Rules.");
+            match redaction::Redactor::load(redaction_rules_path) {
+                Ok(redactor) => Some(redactor),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    let routing_table = match config.routes_path {
+        Some(ref routes_path) => {
+            info!("Loading the Routing Table.");
+            match routing::RoutingTable::load(routes_path) {
+                Ok(routing_table) => Some(routing_table),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
+    info!("Initializing Failure Reporter.");
+    let failure_reporter = match reporter::Reporter::new() {
+        Ok(failure_reporter) => failure_reporter,
+        Err(e) => {
+            error!("{}", e);
+            process::exit(1);
+        }
+    };
+    info!("Done.");
+
     let (tx, rx) = mpsc::channel();
     let original_tx = Arc::new(Mutex::new(tx));
     let new_tx = original_tx.clone();
 
     info!("Loading a Backup Store.");
-    let db = Store::new("kafka_rust");
-    if db.is_err() {
-        error!("{:?}", db.err().unwrap());
-        panic!("Failed to create Backup Store!");
-    }
-    let db = db.unwrap();
+    let mut db = match backup::open(&config.backup) {
+        Ok(db) => db,
+        Err(e) => {
+            error!("{}", e);
+            panic!("Failed to create Backup Store!");
+        }
+    };
     info!("Done.");
 
-    let kafka_client: KafkaClient;
+    let dead_letters = match config.dead_letter {
+        Some(ref dead_letter) => {
+            info!("Loading the Dead-Letter Options.");
+            match deadletter::DeadLetters::new(dead_letter) {
+                Ok(dead_letters) => Some(dead_letters),
+                Err(e) => {
+                    error!("{}", e);
+                    process::exit(1);
+                }
+            }
+        },
+        None => None,
+    };
+
     let producer;
 
     if !copied_dry_run {
-        kafka_client = load_kafka_client(config.cert_path, config.key_path, config.brokers);
-        producer = Some(Producer::from_client(kafka_client).create().unwrap());
+        producer = match load_producer(&config) {
+            Ok(producer) => Some(producer),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        };
     } else {
         producer = None;
     }
@@ -107,7 +339,7 @@ fn main() {
 
     if !copied_dry_run {
         info!("Resending Failed Messages...");
-        utils::resend_failed_messages(&db, arcd_producer.clone());
+        utils::resend_failed_messages(&mut *db, arcd_producer.clone(), dead_letters.as_ref());
         info!("Done Resending.");
     }
 
@@ -119,52 +351,308 @@ fn main() {
     let kafka_reporter = reporter_tx.clone();
     info!("Done.");
 
-    info!("Initializing Failure Reporter.");
-    let failure_reporter = reporter::Reporter{};
+    if !copied_dry_run && config.security == SecurityMode::MutualTls && config.cert_reload_interval > 0 {
+        info!("Starting Certificate Reloader.");
+        start_cert_reloader(config.clone(), arcd_producer.clone().unwrap(), reporter_tx.clone());
+        info!("Done.");
+    }
+
     info!("Starting Failure Reporter.");
     let failed_tx = failure_reporter.start_reporting();
     info!("Done.");
 
-    info!("Initalizing Web UI.");
-    let mut hbse = HandlebarsEngine::new();
-    hbse.add(Box::new(MemorySource(utils::get_mem_templates())));
+    let payload_limits = config.payload_limits.clone();
+    let compressed_passthrough = config.compressed_passthrough;
+    let envelope_topics = config.envelope_topics.clone();
+    let mirror_topics = config.mirror_topics.clone();
+    let hostname = envelope::local_hostname();
+    let random = SystemRandom::new();
+    // Shared with the handler, as the topics `/route` and `from` rewrites send to aren't known before the body is read.
+    let rate_limiter = rate_limits.map(|rate_limits| {
+        let rate_limit_stats = if copied_dry_run { None } else { Some(reporter_tx.clone()) };
+        Arc::new(ratelimit::RateLimiter::new(rate_limits, rate_limit_stats))
+    });
+    let routed_rate_limiter = rate_limiter.clone();
+    let kafka_proxy = move |ref mut req: &mut Request| -> IronResult<Response> {
+        let reject = |status: status::Status, message: String| -> IronResult<Response> {
+            if !copied_dry_run {
+                let _ = http_reporter.lock().unwrap().send(stats::Stat::new(true, false));
+            }
+            Ok(Response::with((status, message)))
+        };
 
-    if let Err(r) = hbse.reload() {
-        error!("{}", r);
-        panic!("Failed to load HandlebarsEngine templates.");
-    }
+        // `/route` has no topic, the routing table picks them once the body has been read.
+        // Otherwise the path has one or more comma seperated topics.
+        let posted_topics = req.extensions.get::<Router>().and_then(|params| params.find("topic")).map(utils::split_topics);
+        if posted_topics.as_ref().map(|topics| topics.is_empty()).unwrap_or(false) {
+            return reject(status::BadRequest, "No topic was given.".to_owned());
+        }
+        let routed = match (routing_table.as_ref(), posted_topics.as_ref()) {
+            (Some(routing_table), Some(topics)) => topics.iter().any(|topic| routing_table.applies_to(Some(topic))),
+            (Some(_), None) => true,
+            (None, Some(_)) => false,
+            (None, None) => return reject(status::NotFound,
+                "There's no routing table, post to /kafka/<topic> instead.".to_owned()),
+        };
+        let destination = posted_topics.as_ref().map(|topics| topics.join(",")).unwrap_or("/route".to_owned());
+        // Until the topics are known, the largest limit any of them could have applies.
+        let limit = match posted_topics {
+            Some(ref topics) if !routed => topics.iter().map(|topic| payload_limits.for_topic(topic)).max().unwrap(),
+            _ => payload_limits.largest(),
+        };
 
-    let kafka_proxy = move |ref mut req: &mut Request| -> IronResult<Response> {
-        let body = req.get::<bodyparser::Raw>();
-        let topic = req.extensions.get::<Router>().unwrap().find("topic").unwrap();
-        match body {
-            Ok(Some(body)) => {
-                &new_tx.lock().unwrap().send(MessagePayload {
-                    topic: String::from(topic),
-                    payload: body
-                }).unwrap();
-                if !copied_dry_run {
-                    let _ = http_reporter.lock().unwrap().send(stats::Stat::new(true, true));
-                }
-                Ok(Response::with(status::Ok))
+        let client = req.extensions.get::<Identity>().map(|identity| identity.name.clone());
+        let metadata = match envelope::metadata(&random, &hostname, client, req.remote_addr.ip().to_string()) {
+            Ok(metadata) => metadata,
+            Err(e) => {
+                error!("{}", e);
+                return reject(status::InternalServerError, e);
             },
-            Ok(None) => {
-                if !copied_dry_run {
-                    let _ = http_reporter.lock().unwrap().send(stats::Stat::new(true, false));
+        };
+
+        // Reject what we can from the Content-Length, without reading the body at all.
+        let declared_length = req.headers.get::<ContentLength>().map(|length| length.0);
+        let body = match declared_length {
+            Some(length) if length > limit as u64 => Ok(None),
+            _ => utils::read_limited(&mut req.body, limit),
+        };
+        let body = match body {
+            Ok(Some(body)) => body,
+            Ok(None) => return reject(status::PayloadTooLarge,
+                format!("Payloads for [ {} ] can be at most {} bytes.", destination, limit)),
+            Err(e) => return reject(status::BadRequest, format!("Failed to read the body: {}", e)),
+        };
+        let encodings = req.headers.get_raw("Content-Encoding")
+            .map(|values| values.iter().map(|value| String::from_utf8_lossy(value).into_owned()).collect::<Vec<String>>().join(","))
+            .map(|header| compression::parse_content_encoding(&header))
+            .unwrap_or(Vec::new());
+        let is_redacted = |topic: &str| redactor.as_ref().map(|redactor| redactor.applies_to(topic)).unwrap_or(false);
+        // Compressed bodies can't be validated, redacted, transformed, wrapped, or encoded,
+        // so topics that do any of those are always decompressed.
+        let reads_body = |topic: &str| {
+            is_redacted(topic) ||
+                schemas.as_ref().map(|schemas| schemas.get(topic).is_some()).unwrap_or(false) ||
+                transforms.as_ref().map(|transforms| transforms.get(topic).is_some()).unwrap_or(false) ||
+                envelope_topics.iter().any(|pattern| auth::topic_matches(pattern, topic)) ||
+                schema_registry.as_ref()
+                    .map(|&(ref avro_topics, _)| avro_topics.iter().any(|pattern| auth::topic_matches(pattern, topic)))
+                    .unwrap_or(false) ||
+                protobuf_topics.as_ref().map(|protobuf_topics| protobuf_topics.applies_to(topic)).unwrap_or(false)
+        };
+        let is_passthrough = |topic: &str| !encodings.is_empty() && compressed_passthrough && !reads_body(topic);
+        // Routing looks inside the body, so it's decompressed even for passthrough topics.
+        let needs_decoding = !encodings.is_empty() &&
+            (routed || posted_topics.as_ref().map(|topics| topics.iter().any(|topic| !is_passthrough(topic))).unwrap_or(true));
+        let decoded = if needs_decoding {
+            match compression::decode(&encodings, body.clone(), limit) {
+                Ok(decoded) => Some(decoded),
+                Err(compression::DecodeError::TooLarge) => return reject(status::PayloadTooLarge,
+                    format!("Payloads for [ {} ] can be at most {} bytes once decompressed.", destination, limit)),
+                Err(compression::DecodeError::Unsupported(encoding)) => return reject(status::UnsupportedMediaType,
+                    format!("Unsupported Content-Encoding: [ {} ].", encoding)),
+                Err(compression::DecodeError::Invalid(e)) => return reject(status::BadRequest, e),
+            }
+        } else {
+            None
+        };
+
+        let topics = match routing_table {
+            Some(ref routing_table) if routed => {
+                let content = decoded.as_ref().unwrap_or(&body);
+                if let Err(e) = utils::check_json_depth(content) {
+                    return reject(status::UnprocessableEntity, e);
+                }
+                let json_body = ::std::str::from_utf8(content).ok().and_then(|text| Json::from_str(text).ok());
+                let headers: HashMap<String, String> = routing_table.header_names().into_iter()
+                    .filter_map(|name| {
+                        let value = req.headers.get_raw(&name)
+                            .map(|values| values.iter().map(|value| String::from_utf8_lossy(value).into_owned()).collect::<Vec<String>>().join(","));
+                        value.map(|value| (name, value))
+                    })
+                    .collect();
+                let posted: Vec<Option<&str>> = match posted_topics {
+                    Some(ref topics) => topics.iter().map(|topic| Some(&topic[..])).collect(),
+                    None => vec![None],
+                };
+
+                let mut topics: Vec<String> = Vec::new();
+                for posted in posted {
+                    match routing_table.route(posted, json_body.as_ref(), &headers) {
+                        Ok(routed_topics) => {
+                            for topic in routed_topics {
+                                if !topics.contains(&topic) {
+                                    topics.push(topic);
+                                }
+                            }
+                        },
+                        Err(e) => return reject(status::UnprocessableEntity, e),
+                    }
                 }
-                Ok(Response::with(status::BadRequest))
+                topics
             },
-            Err(_) => {
-                if !copied_dry_run {
-                    let _ = http_reporter.lock().unwrap().send(stats::Stat::new(true, false));
+            _ => posted_topics.clone().unwrap_or(Vec::new()),
+        };
+
+        // The Authenticator only knows the posted topics, so routed messages are checked once their topics are known.
+        if routed {
+            if let Some(identity) = req.extensions.get::<Identity>() {
+                if let Some(topic) = topics.iter().find(|topic| !identity.may_write(topic)) {
+                    warn!("[ {} ] isn't allowed to write to [ {} ].", identity.name, topic);
+                    if !copied_dry_run {
+                        let _ = http_reporter.lock().unwrap().send(stats::Stat::AuthFailure { was_authenticated: true });
+                    }
+                    return reject(status::Forbidden, format!("These credentials aren't allowed to write to [ {} ].", topic));
+                }
+            }
+        }
+        // The posted topics and the client were limited before routing, so only the topics routing added are limited here.
+        if routed {
+            if let Some(ref rate_limiter) = routed_rate_limiter {
+                let added: Vec<&str> = topics.iter()
+                    .map(|topic| &topic[..])
+                    .filter(|topic| !posted_topics.as_ref().map(|posted| posted.iter().any(|p| p == topic)).unwrap_or(false))
+                    .collect();
+                if !added.is_empty() {
+                    try!(rate_limiter.limit(None, &added, Instant::now()));
                 }
-                Ok(Response::with(status::BadRequest))
             }
         }
+        // Mirrors are the proxy's configuration rather than the client's choice, so aren't authorized.
+        let topics = routing::mirror(topics, &mirror_topics);
+
+        // Everything a single topic's message goes through. Each topic is prepared on its own,
+        // so a message that doesn't fit one topic is still sent to the rest.
+        let prepare = |topic: &str, body: Vec<u8>| -> Result<MessagePayload, (status::Status, String)> {
+            if body.is_empty() {
+                return Err((status::BadRequest, "The body is empty.".to_owned()));
+            }
+            let limit = payload_limits.for_topic(topic);
+            if body.len() > limit {
+                return Err((status::PayloadTooLarge, format!("Payloads for [ {} ] can be at most {} bytes.", topic, limit)));
+            }
+
+            if let Some(schema) = schemas.as_ref().and_then(|schemas| schemas.get(topic)) {
+                if let Err(errors) = schema::validate_body(schema, &body) {
+                    if !copied_dry_run {
+                        let _ = http_reporter.lock().unwrap().send(stats::Stat::ValidationFailure { topic: topic.to_owned() });
+                    }
+                    return Err((status::UnprocessableEntity, errors.join("\n")));
+                }
+            }
+
+            // Redacted before the transforms, so a `key_from` can't copy PII into the key,
+            // and before the producer, so nothing unredacted can be backed up.
+            let body = match redactor {
+                Some(ref redactor) if is_redacted(topic) => {
+                    let redacted = match redactor.redact(topic, body) {
+                        Ok(redacted) => redacted,
+                        Err(e) => return Err((status::UnprocessableEntity, e)),
+                    };
+                    if !copied_dry_run {
+                        for (rule, hits) in redacted.hits {
+                            let _ = http_reporter.lock().unwrap().send(stats::Stat::Redaction { topic: topic.to_owned(), rule: rule, hits: hits });
+                        }
+                    }
+                    redacted.body
+                },
+                _ => body,
+            };
+
+            let (body, key) = match transforms.as_ref().and_then(|transforms| transforms.get(topic)) {
+                Some(pipeline) => match pipeline.apply(&body) {
+                    Ok(transformed) => (transformed.body, transformed.key),
+                    Err(e) => return Err((status::UnprocessableEntity, e)),
+                },
+                None => (body, None),
+            };
+
+            let body = if envelope_topics.iter().any(|pattern| auth::topic_matches(pattern, topic)) {
+                match envelope::wrap(&metadata, &body) {
+                    Ok(wrapped) => wrapped,
+                    Err(e) => return Err((status::UnprocessableEntity, e)),
+                }
+            } else {
+                body
+            };
+
+            let body = match schema_registry {
+                Some((ref avro_topics, ref registry)) if avro_topics.iter().any(|pattern| auth::topic_matches(pattern, topic)) => {
+                    match registry.encode(topic, &body) {
+                        Ok(encoded) => encoded,
+                        Err(avro::registry::EncodeError::Invalid(e)) => return Err((status::UnprocessableEntity, e)),
+                        Err(avro::registry::EncodeError::Unavailable(e)) => {
+                            error!("{}", e);
+                            return Err((status::ServiceUnavailable, format!("The schema for [ {} ] isn't available right now.", topic)));
+                        },
+                    }
+                },
+                _ => body,
+            };
+
+            let body = match protobuf_topics.as_ref().and_then(|protobuf_topics| protobuf_topics.encode(topic, &body)) {
+                Some(Ok(encoded)) => encoded,
+                Some(Err(e)) => return Err((status::UnprocessableEntity, e)),
+                None => body,
+            };
+
+            Ok(MessagePayload {
+                topic: topic.to_owned(),
+                payload: body,
+                key: key,
+                metadata: Some(metadata.clone()),
+                attempts: 0,
+                error: None,
+            })
+        };
+
+        let mut payloads = Vec::new();
+        let mut results = Vec::new();
+        for topic in topics.iter() {
+            let topic_body = match decoded {
+                Some(ref decoded) if !is_passthrough(topic) => decoded.clone(),
+                _ => body.clone(),
+            };
+            match prepare(topic, topic_body) {
+                Ok(payload) => {
+                    payloads.push(payload);
+                    results.push(TopicResult { topic: topic.clone(), status: status::Ok.to_u16(), error: None });
+                },
+                Err((status, message)) => {
+                    if topics.len() == 1 {
+                        return reject(status, message);
+                    }
+                    results.push(TopicResult { topic: topic.clone(), status: status.to_u16(), error: Some(message) });
+                },
+            }
+        }
+
+        // Each topic is its own message to the producer, so a topic kafka fails on is backed up on its own.
+        let sent = payloads.len();
+        for payload in payloads {
+            &new_tx.lock().unwrap().send(payload).unwrap();
+        }
+        if !copied_dry_run {
+            let _ = http_reporter.lock().unwrap().send(stats::Stat::new(true, sent == topics.len()));
+        }
+        if topics.len() == 1 {
+            return Ok(Response::with(status::Ok));
+        }
+
+        // Several topics get a result for each, `207` when only some of them were sent.
+        let status = match sent {
+            0 => status::Status::from_u16(results[0].status),
+            sent if sent == topics.len() => status::Ok,
+            _ => status::MultiStatus,
+        };
+        Ok(Response::with((status, json::encode(&results).unwrap(), Header(ContentType::json()))))
     };
 
     thread::spawn(move || {
         loop {
+            // Lets the backup store sync on its interval, even when nothing new is backed up.
+            if let Err(e) = db.tick() {
+                error!("{}", e);
+            }
             let possible_payload = rx.try_recv();
             if possible_payload.is_ok() {
                 let message_payload = possible_payload.unwrap();
@@ -174,28 +662,45 @@ fn main() {
                     info!("{:?}", message_payload);
                 } else {
                     let arcd_producer = arcd_producer.clone().unwrap();
+                    // kafka sends an empty key as no key at all.
                     let attempt_to_send = arcd_producer.lock().unwrap().send(&Record{
                         topic: &message_payload.topic,
                         partition: -1,
-                        key: (),
+                        key: message_payload.key.unwrap_or(Vec::new()),
                         value: message_payload.payload,
                     });
 
                     if attempt_to_send.is_err() {
-                        let save_result = db.save(&cloned_object);
-                        if save_result.is_err() {
-                            if copied_panic {
-                                panic!("Failed to backup: [ {:?} ]", cloned_object);
-                            } else {
-                                error!("Failed Because: {:?}", save_result.err().unwrap());
-                                error!("Failed to backup: [ {:?} ]", cloned_object);
-                            }
-                        } else {
-                            error!("Failed Because: {:?}", attempt_to_send.err().unwrap());
-                            error!("Failed to send: [ {:?} ] to kafka, but has been backed up.", cloned_object);
+                        let attempt_error = attempt_to_send.err().unwrap();
+                        let send_error = format!("{:?}", attempt_error);
+                        let (failed_object, outcome) = utils::handle_send_failure(cloned_object.clone(), &attempt_error,
+                                                                                  Some(&arcd_producer), dead_letters.as_ref());
+                        match outcome {
+                            utils::FailureOutcome::DeadLettered => {
+                                error!("Failed Because: {}", send_error);
+                                error!("Failed to send: [ {:?} ] to kafka, so it has been dead-lettered.", failed_object);
+                            },
+                            utils::FailureOutcome::Dropped => {
+                                error!("Failed Because: {}", send_error);
+                                error!("Kafka will never accept: [ {:?} ], so it hasn't been backed up.", cloned_object);
+                            },
+                            utils::FailureOutcome::Retry => {
+                                let save_result = db.save(&failed_object);
+                                if save_result.is_err() {
+                                    if copied_panic {
+                                        panic!("Failed to backup: [ {:?} ]", failed_object);
+                                    } else {
+                                        error!("Failed Because: {}", save_result.err().unwrap());
+                                        error!("Failed to backup: [ {:?} ]", failed_object);
+                                    }
+                                } else {
+                                    error!("Failed Because: {}", send_error);
+                                    error!("Failed to send: [ {:?} ] to kafka, but has been backed up.", failed_object);
+                                }
+                            },
                         }
 
-                        let _ = failed_tx.lock().unwrap().send(());
+                        let _ = failed_tx.lock().unwrap().send(reporter::Failure::new(&cloned_object.topic, send_error));
                         let _ = kafka_reporter.lock().unwrap().send(stats::Stat::new(false, false));
                     } else {
                         let _ = kafka_reporter.lock().unwrap().send(stats::Stat::new(false, true));
@@ -211,14 +716,50 @@ fn main() {
         Ok(resp)
     };
 
-    let url = format!("0.0.0.0:{}", config.port);
+    let mut ingest_chain = Chain::new(kafka_proxy);
+    if keystore.is_some() || jwt_verifier.is_some() {
+        let auth_stats = if copied_dry_run { None } else { Some(reporter_tx.clone()) };
+        ingest_chain.link_before(auth::Authenticator::new(keystore, jwt_verifier, auth_stats));
+    }
+    if let Some(rate_limiter) = rate_limiter {
+        // Linked after the Authenticator, so clients are limited by identity rather than IP.
+        ingest_chain.link_before(ratelimit::RateLimitMiddleware::new(rate_limiter));
+    }
 
-    info!("Starting Kafka Proxy at: [ {:?} ]", url);
-    let router = router!(
-        get "/" => ui_proxy,
-        post "/kafka/:topic" => kafka_proxy
-    );
-    let mut chain = Chain::new(router);
-    chain.link_after(hbse);
-    Iron::new(chain).http(&url.as_str()).unwrap();
+    let handlers = listener::Handlers {
+        ingest: Arc::new(Box::new(ingest_chain) as Box<Handler>),
+        ui: Arc::new(Box::new(ui_proxy) as Box<Handler>),
+        metrics: Arc::new(Box::new(stats::metrics_handler) as Box<Handler>),
+    };
+
+    // Dropping a listener blocks until it stops, which keeps the proxy running.
+    let mut listening = Vec::new();
+    for listener_config in config.listeners.iter() {
+        info!("Initalizing Routes for: [ {} ].", listener_config.addr);
+        let chain = match listener::build_chain(&listener_config.roles, &handlers) {
+            Ok(chain) => chain,
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        };
+
+        // Metrics only listeners never see payloads, and Unix Sockets never leave
+        // the host, so they're always plain HTTP.
+        let is_unix_socket = listener_config.addr.starts_with(listener::UNIX_SOCKET_PREFIX);
+        let https = if listener_config.roles == vec![ListenerRole::Metrics] || is_unix_socket {
+            None
+        } else {
+            config.https.as_ref()
+        };
+        let scheme = if https.is_some() { "https://" } else if is_unix_socket { "" } else { "http://" };
+        info!("Starting Kafka Proxy at: [ {}{} ] serving {:?}", scheme, listener_config.addr, listener_config.roles);
+        match listener::serve(chain, &listener_config.addr, https, config.unix_socket_mode) {
+            Ok(guard) => listening.push(guard),
+            Err(e) => {
+                error!("{}", e);
+                process::exit(1);
+            }
+        }
+    }
 }
@@ -1,12 +1,276 @@
-use std::path;
+use rustc_serialize::{Decodable, Decoder, Encodable, Encoder};
+use rustc_serialize::base64::{FromBase64, ToBase64, STANDARD};
+use std::collections::BTreeMap;
+use std::{fmt, path};
+use std::time::Duration;
 
-#[derive(Clone, Debug, RustcEncodable, RustcDecodable)]
+#[derive(Clone, Debug, PartialEq, RustcDecodable, RustcEncodable)]
+/// What the proxy knows about a message when it's received.
+/// Stored with backups, and sent in the envelope for envelope topics.
+pub struct MessageMetadata {
+    pub received_at: String,
+    pub hostname: String,
+    pub message_id: String,
+    pub client: Option<String>,
+    pub source_ip: String,
+}
+
+#[derive(Clone, Debug, PartialEq, RustcEncodable)]
+/// How a message sent to several topics did on one of them.
+/// `status` is the HTTP status it would have had on its own, `error` is why it wasn't sent.
+pub struct TopicResult {
+    pub topic: String,
+    pub status: u16,
+    pub error: Option<String>,
+}
+
+#[derive(Clone, PartialEq)]
 /// A Message Payload.
 /// Used to simplify the passing of info from the HTTP Thread, to the thread that sends to Kafka.
 /// Rather than using some weird string concatination method.
+/// The payload is raw bytes, so binary bodies (protobuf, avro, etc.) are sent untouched.
+/// `key` is the kafka key, when the topic's transforms pick one.
+/// `metadata` is only missing for backups from before it was recorded.
+/// `attempts` is how many times kafka has failed to take it, and `error` the last reason why.
 pub struct MessagePayload {
     pub topic: String,
-    pub payload: String,
+    pub payload: Vec<u8>,
+    pub key: Option<Vec<u8>>,
+    pub metadata: Option<MessageMetadata>,
+    pub attempts: u32,
+    pub error: Option<String>,
+}
+
+impl MessagePayload {
+    /// Records another failed attempt to send the message.
+    pub fn failed(mut self, error: String) -> MessagePayload {
+        self.attempts += 1;
+        self.error = Some(error);
+        self
+    }
+}
+
+/// Backups are stored as JSON, so the payload (and key) are base64 encoded, and marked with
+/// `"encoding": "base64"`. Backups from before binary payloads have no `encoding`,
+/// and their payload is the text itself.
+impl Encodable for MessagePayload {
+    fn encode<S: Encoder>(&self, s: &mut S) -> Result<(), S::Error> {
+        s.emit_struct("MessagePayload", 7, |s| {
+            try!(s.emit_struct_field("topic", 0, |s| self.topic.encode(s)));
+            try!(s.emit_struct_field("payload", 1, |s| self.payload.to_base64(STANDARD).encode(s)));
+            try!(s.emit_struct_field("encoding", 2, |s| "base64".encode(s)));
+            try!(s.emit_struct_field("metadata", 3, |s| self.metadata.encode(s)));
+            try!(s.emit_struct_field("key", 4, |s| self.key.as_ref().map(|key| key.to_base64(STANDARD)).encode(s)));
+            try!(s.emit_struct_field("attempts", 5, |s| self.attempts.encode(s)));
+            s.emit_struct_field("error", 6, |s| self.error.encode(s))
+        })
+    }
+}
+
+impl Decodable for MessagePayload {
+    fn decode<D: Decoder>(d: &mut D) -> Result<MessagePayload, D::Error> {
+        d.read_struct("MessagePayload", 7, |d| {
+            let topic: String = try!(d.read_struct_field("topic", 0, Decodable::decode));
+            let payload: String = try!(d.read_struct_field("payload", 1, Decodable::decode));
+            let encoding: Option<String> = try!(d.read_struct_field("encoding", 2, Decodable::decode));
+            let metadata: Option<MessageMetadata> = try!(d.read_struct_field("metadata", 3, Decodable::decode));
+            let key: Option<String> = try!(d.read_struct_field("key", 4, Decodable::decode));
+            let attempts: Option<u32> = try!(d.read_struct_field("attempts", 5, Decodable::decode));
+            let error: Option<String> = try!(d.read_struct_field("error", 6, Decodable::decode));
+
+            let payload = match encoding {
+                None => payload.into_bytes(),
+                Some(ref encoding) if encoding == "base64" => {
+                    try!(payload.from_base64().map_err(|e| d.error(&format!("Invalid base64 payload: {}", e))))
+                },
+                Some(encoding) => return Err(d.error(&format!("Unknown payload encoding: [ {} ].", encoding))),
+            };
+            let key = match key {
+                Some(key) => Some(try!(key.from_base64().map_err(|e| d.error(&format!("Invalid base64 key: {}", e))))),
+                None => None,
+            };
+
+            Ok(MessagePayload {
+                topic: topic,
+                payload: payload,
+                key: key,
+                metadata: metadata,
+                attempts: attempts.unwrap_or(0),
+                error: error,
+            })
+        })
+    }
+}
+
+/// Payloads are usually text, so they're shown as text (lossily) when logged.
+impl fmt::Debug for MessagePayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("MessagePayload")
+            .field("topic", &self.topic)
+            .field("payload", &String::from_utf8_lossy(&self.payload))
+            .field("key", &self.key.as_ref().map(|key| String::from_utf8_lossy(key)))
+            .field("metadata", &self.metadata)
+            .field("attempts", &self.attempts)
+            .field("error", &self.error)
+            .finish()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// How to connect to the kafka brokers.
+/// `Plaintext` - No TLS at all.
+/// `Tls` - TLS, verifying the brokers, but without a client certificate.
+/// `MutualTls` - TLS, with a client certificate.
+pub enum SecurityMode {
+    Plaintext,
+    Tls,
+    MutualTls,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// The minimum TLS Version to negotiate with the kafka brokers.
+pub enum TlsVersion {
+    Tlsv1_2,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// How the certificate presented by the kafka brokers should be verified.
+/// `None` - Don't verify the broker at all (Not Recommended).
+/// `Peer` - Verify the broker's certificate chains back to a trusted CA.
+pub enum VerifyMode {
+    None,
+    Peer,
+}
+
+#[derive(Clone, Debug)]
+/// The TLS Configuration used when talking to the kafka brokers.
+pub struct TlsConfig {
+    pub min_version: TlsVersion,
+    pub ca_file: Option<path::PathBuf>,
+    pub ca_dir: Option<path::PathBuf>,
+    pub verify: VerifyMode,
+    pub verify_hostname: bool,
+    pub hostname: Option<String>,
+    pub ciphers: String,
+}
+
+#[derive(Clone, Debug)]
+/// The Schema Registry Configuration, for topics whose bodies are encoded as Avro.
+/// `avro_topics` - The topics to encode, a `*` matches any run of characters.
+/// `schema_dir` - A directory of `<topic>.avsc` schemas to register, rather than using the latest version.
+/// `cache_seconds` - How long to cache a topic's schema.
+pub struct SchemaRegistryConfig {
+    pub url: String,
+    pub avro_topics: Vec<String>,
+    pub schema_dir: Option<path::PathBuf>,
+    pub cache_seconds: u64,
+}
+
+#[derive(Clone, Debug)]
+/// The Protobuf Configuration, for topics whose bodies are converted from JSON to protobuf.
+/// `descriptor_set` - A compiled `FileDescriptorSet` with every message type.
+/// `topics` - The fully qualified message type (e.g. `shop.Order`) for each topic.
+pub struct ProtobufConfig {
+    pub descriptor_set: path::PathBuf,
+    pub topics: BTreeMap<String, String>,
+}
+
+#[derive(Clone, Debug)]
+/// The JWT Configuration used to authenticate HTTP callers.
+pub struct JwtConfig {
+    pub jwks_path: path::PathBuf,
+    pub audience: String,
+    pub issuer: Option<String>,
+    pub topic_claim: String,
+    pub leeway: i64,
+}
+
+#[derive(Clone, Debug)]
+/// The HTTPS Configuration for the ingestion server.
+/// When `client_ca` is set callers may present a client certificate signed by it,
+/// and when `require_client_cert` is also set they must.
+pub struct HttpsConfig {
+    pub cert_path: path::PathBuf,
+    pub key_path: path::PathBuf,
+    pub client_ca: Option<path::PathBuf>,
+    pub require_client_cert: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// What a listener serves.
+/// `Ingest` - The `/kafka/:topic` routes.
+/// `Admin` - The web UI at `/` (and the `/kafka/:topic` routes it posts to).
+/// `Metrics` - The prometheus metrics at `/metrics`.
+pub enum ListenerRole {
+    Ingest,
+    Admin,
+    Metrics,
+}
+
+#[derive(Clone, Debug)]
+/// An address to listen on, and what to serve there.
+/// The address is either IP:PORT, or `unix:<path>` for a Unix Socket.
+pub struct ListenerConfig {
+    pub addr: String,
+    pub roles: Vec<ListenerRole>,
+}
+
+#[derive(Clone, Debug)]
+/// The largest payloads (in bytes) the proxy accepts.
+/// `max_size` applies to every topic not in `topics`.
+pub struct PayloadLimits {
+    pub max_size: usize,
+    pub topics: BTreeMap<String, usize>,
+}
+
+impl PayloadLimits {
+    /// The largest payload accepted for `topic`.
+    pub fn for_topic(&self, topic: &str) -> usize {
+        self.topics.get(topic).cloned().unwrap_or(self.max_size)
+    }
+
+    /// The largest payload accepted for any topic.
+    pub fn largest(&self) -> usize {
+        self.topics.values().cloned().fold(self.max_size, ::std::cmp::max)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// When the write-ahead log syncs what's written to disk.
+/// `Always` - After every message.
+/// `Interval` - After a message, once it's been at least this long since the last sync.
+/// `Never` - Whenever the OS decides to.
+pub enum FsyncPolicy {
+    Always,
+    Interval(Duration),
+    Never,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+/// How messages kafka failed on are backed up.
+/// `Jfs` - A JSON file per message.
+/// `Wal` - An append-only write-ahead log, split into segments of at most `segment_size` bytes.
+pub enum BackupBackend {
+    Jfs,
+    Wal { segment_size: u64, fsync: FsyncPolicy },
+}
+
+#[derive(Clone, Debug)]
+/// Where, and how messages kafka failed on are backed up.
+pub struct BackupConfig {
+    pub backend: BackupBackend,
+    pub dir: path::PathBuf,
+}
+
+#[derive(Clone, Debug)]
+/// Where backups go once kafka has failed on them too many times, rather than being retried forever.
+/// `max_attempts` - How many failed sends a message gets before it's dead-lettered.
+/// `topic` - The dead-letter topic.
+/// `quarantine_dir` - A directory to move them to, when there's no topic, or kafka fails on it too.
+pub struct DeadLetterConfig {
+    pub max_attempts: u32,
+    pub topic: Option<String>,
+    pub quarantine_dir: Option<path::PathBuf>,
 }
 
 #[derive(Clone, Debug)]
@@ -14,10 +278,77 @@ pub struct MessagePayload {
 /// Conatains all possible configuration values. Either from env vars,
 /// or from CLI Opts.
 pub struct Configuration {
-    pub cert_path: path::PathBuf,
-    pub key_path: path::PathBuf,
+    pub security: SecurityMode,
+    pub cert_path: Option<path::PathBuf>,
+    pub key_path: Option<path::PathBuf>,
+    pub cert_reload_interval: u64,
     pub brokers: Vec<String>,
-    pub port: u64,
+    pub port: Option<u64>,
+    pub listeners: Vec<ListenerConfig>,
+    pub unix_socket_mode: u32,
     pub panic_on_backup: bool,
     pub dry_run: bool,
+    pub tls: TlsConfig,
+    pub keystore_path: Option<path::PathBuf>,
+    pub jwt: Option<JwtConfig>,
+    pub https: Option<HttpsConfig>,
+    pub rate_limits_path: Option<path::PathBuf>,
+    pub payload_limits: PayloadLimits,
+    pub compressed_passthrough: bool,
+    pub schema_dir: Option<path::PathBuf>,
+    pub schema_registry: Option<SchemaRegistryConfig>,
+    pub protobuf: Option<ProtobufConfig>,
+    pub envelope_topics: Vec<String>,
+    pub mirror_topics: Vec<(String, String)>,
+    pub dead_letter: Option<DeadLetterConfig>,
+    pub backup: BackupConfig,
+    pub transforms_path: Option<path::PathBuf>,
+    pub redaction_rules_path: Option<path::PathBuf>,
+    pub routes_path: Option<path::PathBuf>,
+}
+
+#[test]
+fn test_message_payload_backup_format() {
+    use rustc_serialize::json;
+
+    let binary = MessagePayload {
+        topic: "images".to_owned(),
+        payload: vec![0x00, 0xff, 0x1f, 0x8b],
+        key: None,
+        metadata: None,
+        attempts: 0,
+        error: None,
+    };
+    let encoded = json::encode(&binary).unwrap();
+    assert!(encoded == r#"{"topic":"images","payload":"AP8fiw==","encoding":"base64","metadata":null,"key":null,"attempts":0,"error":null}"#);
+    assert!(json::decode::<MessagePayload>(&encoded).unwrap() == binary);
+
+    let with_metadata = MessagePayload {
+        topic: "orders".to_owned(),
+        payload: b"{}".to_vec(),
+        key: Some(b"c-7".to_vec()),
+        metadata: Some(MessageMetadata {
+            received_at: "2017-01-18T13:04:05.123Z".to_owned(),
+            hostname: "proxy-1".to_owned(),
+            message_id: "00000000-0000-4000-8000-000000000000".to_owned(),
+            client: None,
+            source_ip: "10.0.0.7".to_owned(),
+        }),
+        attempts: 0,
+        error: None,
+    };
+    assert!(json::decode::<MessagePayload>(&json::encode(&with_metadata).unwrap()).unwrap() == with_metadata);
+
+    let failed = with_metadata.failed("BrokerNotAvailable".to_owned()).failed("RequestTimedOut".to_owned());
+    assert!(failed.attempts == 2);
+    assert!(json::decode::<MessagePayload>(&json::encode(&failed).unwrap()).unwrap() == failed);
+
+    // Backups from before binary payloads.
+    let legacy: MessagePayload = json::decode(r#"{"topic":"orders","payload":"hello"}"#).unwrap();
+    assert!(legacy.payload == b"hello".to_vec());
+    assert!(legacy.metadata == None);
+    assert!(legacy.key == None);
+    assert!(legacy.attempts == 0);
+
+    assert!(json::decode::<MessagePayload>(r#"{"topic":"orders","payload":"hello","encoding":"rot13"}"#).is_err());
 }
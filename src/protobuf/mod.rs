@@ -0,0 +1,637 @@
+use rustc_serialize::base64::FromBase64;
+use rustc_serialize::json::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::File;
+use std::io::Read;
+use std::path;
+
+/// The wire types of the protobuf encoding.
+const WIRE_VARINT: u64 = 0;
+const WIRE_FIXED64: u64 = 1;
+const WIRE_LENGTH_DELIMITED: u64 = 2;
+const WIRE_FIXED32: u64 = 5;
+
+/// The field types from `FieldDescriptorProto.Type`.
+const TYPE_DOUBLE: u64 = 1;
+const TYPE_FLOAT: u64 = 2;
+const TYPE_INT64: u64 = 3;
+const TYPE_UINT64: u64 = 4;
+const TYPE_INT32: u64 = 5;
+const TYPE_FIXED64: u64 = 6;
+const TYPE_FIXED32: u64 = 7;
+const TYPE_BOOL: u64 = 8;
+const TYPE_STRING: u64 = 9;
+const TYPE_GROUP: u64 = 10;
+const TYPE_MESSAGE: u64 = 11;
+const TYPE_BYTES: u64 = 12;
+const TYPE_UINT32: u64 = 13;
+const TYPE_ENUM: u64 = 14;
+const TYPE_SFIXED32: u64 = 15;
+const TYPE_SFIXED64: u64 = 16;
+const TYPE_SINT32: u64 = 17;
+const TYPE_SINT64: u64 = 18;
+
+/// The labels from `FieldDescriptorProto.Label`.
+const LABEL_REQUIRED: u64 = 2;
+const LABEL_REPEATED: u64 = 3;
+
+/// A single field read off the wire.
+enum WireValue<'a> {
+    Varint(u64),
+    Fixed64,
+    LengthDelimited(&'a [u8]),
+    Fixed32,
+}
+
+/// Reads a varint, advancing `position` past it.
+fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, String> {
+    let mut value = 0u64;
+    for shift in 0..10 {
+        let byte = try!(bytes.get(*position).cloned().ok_or("Truncated varint.".to_owned()));
+        *position += 1;
+        value |= ((byte & 0x7f) as u64) << (shift * 7);
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    Err("Varint is too long.".to_owned())
+}
+
+/// Splits an encoded message into its fields.
+fn read_fields(bytes: &[u8]) -> Result<Vec<(u64, WireValue)>, String> {
+    let mut fields = Vec::new();
+    let mut position = 0;
+    while position < bytes.len() {
+        let key = try!(read_varint(bytes, &mut position));
+        let value = match key & 0x07 {
+            WIRE_VARINT => WireValue::Varint(try!(read_varint(bytes, &mut position))),
+            WIRE_FIXED64 => { position += 8; WireValue::Fixed64 },
+            WIRE_LENGTH_DELIMITED => {
+                let length = try!(read_varint(bytes, &mut position)) as usize;
+                if position + length > bytes.len() {
+                    return Err("Truncated field.".to_owned());
+                }
+                position += length;
+                WireValue::LengthDelimited(&bytes[position - length..position])
+            },
+            WIRE_FIXED32 => { position += 4; WireValue::Fixed32 },
+            wire_type => return Err(format!("Unsupported wire type [ {} ].", wire_type)),
+        };
+        if position > bytes.len() {
+            return Err("Truncated field.".to_owned());
+        }
+        fields.push((key >> 3, value));
+    }
+    Ok(fields)
+}
+
+fn read_string(bytes: &[u8]) -> Result<String, String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| "Invalid UTF-8 in descriptor.".to_owned())
+}
+
+/// A field of a message type, from its `FieldDescriptorProto`.
+#[derive(Clone, Debug, Default)]
+struct FieldType {
+    name: String,
+    json_name: Option<String>,
+    number: u64,
+    label: u64,
+    kind: u64,
+    type_name: String,
+    packed: Option<bool>,
+}
+
+/// A message type, from its `DescriptorProto`.
+#[derive(Clone, Debug)]
+struct MessageType {
+    fields: Vec<FieldType>,
+    map_entry: bool,
+    proto3: bool,
+}
+
+/// Every message, and enum type in a `FileDescriptorSet`, by fully qualified name (`.package.Message`).
+#[derive(Clone, Debug)]
+pub struct DescriptorPool {
+    messages: HashMap<String, MessageType>,
+    enums: HashMap<String, HashMap<String, i32>>,
+}
+
+impl DescriptorPool {
+    /// Loads a compiled `FileDescriptorSet`, as written by `protoc --include_imports --descriptor_set_out`.
+    pub fn load(path: &path::Path) -> Result<DescriptorPool, String> {
+        let mut contents = Vec::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_end(&mut contents))
+            .map_err(|e| format!("Failed to read descriptor set [ {:?} ]: {}", path, e)));
+        DescriptorPool::parse(&contents).map_err(|e| format!("Invalid descriptor set [ {:?} ]: {}", path, e))
+    }
+
+    /// Parses an encoded `FileDescriptorSet`.
+    pub fn parse(bytes: &[u8]) -> Result<DescriptorPool, String> {
+        let mut pool = DescriptorPool {
+            messages: HashMap::new(),
+            enums: HashMap::new(),
+        };
+
+        for (number, value) in try!(read_fields(bytes)) {
+            if let (1, WireValue::LengthDelimited(file)) = (number, value) {
+                try!(pool.add_file(file));
+            }
+        }
+        Ok(pool)
+    }
+
+    fn add_file(&mut self, file: &[u8]) -> Result<(), String> {
+        let fields = try!(read_fields(file));
+        let mut package = String::new();
+        let mut proto3 = false;
+        for &(number, ref value) in fields.iter() {
+            match (number, value) {
+                (2, &WireValue::LengthDelimited(name)) => package = try!(read_string(name)),
+                (12, &WireValue::LengthDelimited(syntax)) => proto3 = syntax == b"proto3",
+                _ => {},
+            }
+        }
+
+        let scope = if package.is_empty() { String::new() } else { format!(".{}", package) };
+        for (number, value) in fields {
+            match (number, value) {
+                (4, WireValue::LengthDelimited(message)) => try!(self.add_message(message, &scope, proto3)),
+                (5, WireValue::LengthDelimited(enumeration)) => try!(self.add_enum(enumeration, &scope)),
+                _ => {},
+            }
+        }
+        Ok(())
+    }
+
+    fn add_message(&mut self, message: &[u8], scope: &str, proto3: bool) -> Result<(), String> {
+        let fields = try!(read_fields(message));
+        let mut name = String::new();
+        for &(number, ref value) in fields.iter() {
+            if let (1, &WireValue::LengthDelimited(raw_name)) = (number, value) {
+                name = format!("{}.{}", scope, try!(read_string(raw_name)));
+            }
+        }
+
+        let mut message_type = MessageType {
+            fields: Vec::new(),
+            map_entry: false,
+            proto3: proto3,
+        };
+        for (number, value) in fields {
+            match (number, value) {
+                (2, WireValue::LengthDelimited(field)) => message_type.fields.push(try!(parse_field(field))),
+                (3, WireValue::LengthDelimited(nested)) => try!(self.add_message(nested, &name, proto3)),
+                (4, WireValue::LengthDelimited(enumeration)) => try!(self.add_enum(enumeration, &name)),
+                (7, WireValue::LengthDelimited(options)) => {
+                    for (option, option_value) in try!(read_fields(options)) {
+                        if let (7, WireValue::Varint(map_entry)) = (option, option_value) {
+                            message_type.map_entry = map_entry != 0;
+                        }
+                    }
+                },
+                _ => {},
+            }
+        }
+
+        self.messages.insert(name, message_type);
+        Ok(())
+    }
+
+    fn add_enum(&mut self, enumeration: &[u8], scope: &str) -> Result<(), String> {
+        let mut name = String::new();
+        let mut values = HashMap::new();
+        for (number, value) in try!(read_fields(enumeration)) {
+            match (number, value) {
+                (1, WireValue::LengthDelimited(raw_name)) => name = format!("{}.{}", scope, try!(read_string(raw_name))),
+                (2, WireValue::LengthDelimited(enum_value)) => {
+                    let mut value_name = String::new();
+                    let mut value_number = 0;
+                    for (value_field, raw) in try!(read_fields(enum_value)) {
+                        match (value_field, raw) {
+                            (1, WireValue::LengthDelimited(raw_name)) => value_name = try!(read_string(raw_name)),
+                            (2, WireValue::Varint(raw_number)) => value_number = raw_number as i32,
+                            _ => {},
+                        }
+                    }
+                    values.insert(value_name, value_number);
+                },
+                _ => {},
+            }
+        }
+        self.enums.insert(name, values);
+        Ok(())
+    }
+
+    /// Whether the pool has a message type, by its name with, or without the leading `.`.
+    pub fn has_message(&self, name: &str) -> bool {
+        self.messages.contains_key(&qualified(name))
+    }
+
+    /// Encodes a JSON value as a protobuf message of type `message`, following the proto3 JSON mapping.
+    /// Fields can be named by their JSON (lowerCamelCase), or original names. 64 bit integers can be
+    /// numbers, or strings, enums can be names, or numbers, and bytes are base64.
+    pub fn encode(&self, message: &str, value: &Json) -> Result<Vec<u8>, String> {
+        let mut buffer = Vec::new();
+        try!(self.encode_message(&qualified(message), value, "", &mut buffer));
+        Ok(buffer)
+    }
+
+    fn encode_message(&self, message: &str, value: &Json, path: &str, buffer: &mut Vec<u8>) -> Result<(), String> {
+        let message_type = try!(self.messages.get(message).ok_or(format!("Unknown message type [ {} ].", message)));
+        let object = try!(value.as_object().ok_or(format!("{}: expected an object, found {}", display(path), value)));
+
+        for key in object.keys() {
+            let known = message_type.fields.iter()
+                .any(|field| field.name == *key || field.json_name.as_ref() == Some(key));
+            if !known {
+                return Err(format!("{}: unknown field [ {} ]", display(path), key));
+            }
+        }
+
+        for field in message_type.fields.iter() {
+            let field_value = field.json_name.as_ref().and_then(|json_name| object.get(json_name))
+                .or(object.get(&field.name));
+            let field_path = format!("{}/{}", path, field.name);
+            match field_value {
+                None | Some(&Json::Null) => {
+                    if field.label == LABEL_REQUIRED {
+                        return Err(format!("{}: missing required field [ {} ]", display(path), field.name));
+                    }
+                },
+                Some(field_value) => try!(self.encode_field(message_type, field, field_value, &field_path, buffer)),
+            }
+        }
+        Ok(())
+    }
+
+    fn encode_field(&self, message_type: &MessageType, field: &FieldType, value: &Json, path: &str,
+                    buffer: &mut Vec<u8>) -> Result<(), String> {
+        if field.label != LABEL_REPEATED {
+            return self.encode_single(field, value, path, buffer);
+        }
+
+        // Maps are repeated entries with the key as field 1, and the value as field 2.
+        if let Some(entry) = self.messages.get(&field.type_name).and_then(|entry| if entry.map_entry { Some(entry) } else { None }) {
+            let object = try!(value.as_object().ok_or(format!("{}: expected an object, found {}", display(path), value)));
+            let key_field = try!(entry.fields.iter().find(|entry_field| entry_field.number == 1).ok_or("Invalid map entry.".to_owned()));
+            let value_field = try!(entry.fields.iter().find(|entry_field| entry_field.number == 2).ok_or("Invalid map entry.".to_owned()));
+            for (key, item) in object.iter() {
+                let item_path = format!("{}/{}", path, key);
+                // Map keys are always strings in JSON, even when they're numbers, or bools.
+                let key_value = match key_field.kind {
+                    TYPE_BOOL => Json::Boolean(key == "true"),
+                    _ => Json::String(key.clone()),
+                };
+                let mut entry_buffer = Vec::new();
+                try!(self.encode_single(key_field, &key_value, &item_path, &mut entry_buffer));
+                try!(self.encode_single(value_field, item, &item_path, &mut entry_buffer));
+                write_key(field.number, WIRE_LENGTH_DELIMITED, buffer);
+                write_varint(entry_buffer.len() as u64, buffer);
+                buffer.extend(entry_buffer);
+            }
+            return Ok(());
+        }
+
+        let items = try!(value.as_array().ok_or(format!("{}: expected an array, found {}", display(path), value)));
+        let packable = field.kind != TYPE_STRING && field.kind != TYPE_BYTES && field.kind != TYPE_MESSAGE;
+        if packable && field.packed.unwrap_or(message_type.proto3) {
+            let mut packed = Vec::new();
+            for (index, item) in items.iter().enumerate() {
+                try!(self.encode_scalar(field, item, &format!("{}/{}", path, index), &mut packed));
+            }
+            write_key(field.number, WIRE_LENGTH_DELIMITED, buffer);
+            write_varint(packed.len() as u64, buffer);
+            buffer.extend(packed);
+        } else {
+            for (index, item) in items.iter().enumerate() {
+                try!(self.encode_single(field, item, &format!("{}/{}", path, index), buffer));
+            }
+        }
+        Ok(())
+    }
+
+    /// Encodes a single (not repeated) value, with its key.
+    fn encode_single(&self, field: &FieldType, value: &Json, path: &str, buffer: &mut Vec<u8>) -> Result<(), String> {
+        match field.kind {
+            TYPE_MESSAGE => {
+                let mut nested = Vec::new();
+                try!(self.encode_message(&field.type_name, value, path, &mut nested));
+                write_key(field.number, WIRE_LENGTH_DELIMITED, buffer);
+                write_varint(nested.len() as u64, buffer);
+                buffer.extend(nested);
+            },
+            TYPE_STRING => {
+                let string = try!(value.as_string().ok_or(format!("{}: expected a string, found {}", display(path), value)));
+                write_key(field.number, WIRE_LENGTH_DELIMITED, buffer);
+                write_varint(string.len() as u64, buffer);
+                buffer.extend_from_slice(string.as_bytes());
+            },
+            TYPE_BYTES => {
+                let bytes = try!(value.as_string().and_then(|string| string.from_base64().ok())
+                    .ok_or(format!("{}: expected base64, found {}", display(path), value)));
+                write_key(field.number, WIRE_LENGTH_DELIMITED, buffer);
+                write_varint(bytes.len() as u64, buffer);
+                buffer.extend(bytes);
+            },
+            TYPE_GROUP => return Err(format!("{}: groups aren't supported", display(path))),
+            kind => {
+                let wire_type = match kind {
+                    TYPE_DOUBLE | TYPE_FIXED64 | TYPE_SFIXED64 => WIRE_FIXED64,
+                    TYPE_FLOAT | TYPE_FIXED32 | TYPE_SFIXED32 => WIRE_FIXED32,
+                    _ => WIRE_VARINT,
+                };
+                write_key(field.number, wire_type, buffer);
+                try!(self.encode_scalar(field, value, path, buffer));
+            },
+        }
+        Ok(())
+    }
+
+    /// Encodes a numeric, bool, or enum value, without a key (so it can be packed).
+    fn encode_scalar(&self, field: &FieldType, value: &Json, path: &str, buffer: &mut Vec<u8>) -> Result<(), String> {
+        let mismatch = || format!("{}: expected a {}, found {}", display(path), kind_name(field.kind), value);
+        match field.kind {
+            TYPE_BOOL => write_varint(try!(value.as_boolean().ok_or_else(&mismatch)) as u64, buffer),
+            TYPE_ENUM => {
+                let number = match *value {
+                    Json::String(ref name) => try!(self.enums.get(&field.type_name)
+                        .and_then(|values| values.get(name)).cloned()
+                        .ok_or(format!("{}: [ {} ] isn't a value of {}", display(path), name, field.type_name))),
+                    _ => try!(value.as_i64().ok_or_else(&mismatch)) as i32,
+                };
+                write_varint(number as i64 as u64, buffer);
+            },
+            TYPE_DOUBLE => write_little_endian(try!(json_f64(value).ok_or_else(&mismatch)).to_bits(), 8, buffer),
+            TYPE_FLOAT => write_little_endian((try!(json_f64(value).ok_or_else(&mismatch)) as f32).to_bits() as u64, 4, buffer),
+            TYPE_INT32 | TYPE_SFIXED32 | TYPE_SINT32 => {
+                let number = try!(json_i64(value).ok_or_else(&mismatch));
+                if number < i32::min_value() as i64 || number > i32::max_value() as i64 {
+                    return Err(format!("{}: [ {} ] is out of range for a 32 bit integer", display(path), number));
+                }
+                match field.kind {
+                    TYPE_SFIXED32 => write_little_endian(number as i32 as u32 as u64, 4, buffer),
+                    TYPE_SINT32 => write_varint(((number << 1) ^ (number >> 63)) as u64, buffer),
+                    // Negative int32s are sign extended to 64 bits, like int64s.
+                    _ => write_varint(number as u64, buffer),
+                }
+            },
+            TYPE_UINT32 | TYPE_FIXED32 => {
+                let number = try!(json_u64(value).ok_or_else(&mismatch));
+                if number > u32::max_value() as u64 {
+                    return Err(format!("{}: [ {} ] is out of range for a 32 bit integer", display(path), number));
+                }
+                match field.kind {
+                    TYPE_FIXED32 => write_little_endian(number, 4, buffer),
+                    _ => write_varint(number, buffer),
+                }
+            },
+            TYPE_INT64 => write_varint(try!(json_i64(value).ok_or_else(&mismatch)) as u64, buffer),
+            TYPE_SINT64 => {
+                let number = try!(json_i64(value).ok_or_else(&mismatch));
+                write_varint(((number << 1) ^ (number >> 63)) as u64, buffer);
+            },
+            TYPE_SFIXED64 => write_little_endian(try!(json_i64(value).ok_or_else(&mismatch)) as u64, 8, buffer),
+            TYPE_UINT64 => write_varint(try!(json_u64(value).ok_or_else(&mismatch)), buffer),
+            TYPE_FIXED64 => write_little_endian(try!(json_u64(value).ok_or_else(&mismatch)), 8, buffer),
+            _ => return Err(mismatch()),
+        }
+        Ok(())
+    }
+}
+
+fn parse_field(field: &[u8]) -> Result<FieldType, String> {
+    let mut field_type = FieldType::default();
+    for (number, value) in try!(read_fields(field)) {
+        match (number, value) {
+            (1, WireValue::LengthDelimited(name)) => field_type.name = try!(read_string(name)),
+            (3, WireValue::Varint(field_number)) => field_type.number = field_number,
+            (4, WireValue::Varint(label)) => field_type.label = label,
+            (5, WireValue::Varint(kind)) => field_type.kind = kind,
+            (6, WireValue::LengthDelimited(type_name)) => field_type.type_name = try!(read_string(type_name)),
+            (10, WireValue::LengthDelimited(json_name)) => field_type.json_name = Some(try!(read_string(json_name))),
+            (8, WireValue::LengthDelimited(options)) => {
+                for (option, option_value) in try!(read_fields(options)) {
+                    if let (2, WireValue::Varint(packed)) = (option, option_value) {
+                        field_type.packed = Some(packed != 0);
+                    }
+                }
+            },
+            _ => {},
+        }
+    }
+    if field_type.json_name.is_none() {
+        field_type.json_name = Some(json_name(&field_type.name));
+    }
+    Ok(field_type)
+}
+
+/// The lowerCamelCase name protoc gives a field in JSON, for descriptor sets that don't include it.
+fn json_name(name: &str) -> String {
+    let mut json_name = String::new();
+    let mut capitalize = false;
+    for character in name.chars() {
+        if character == '_' {
+            capitalize = true;
+        } else if capitalize {
+            json_name.extend(character.to_uppercase());
+            capitalize = false;
+        } else {
+            json_name.push(character);
+        }
+    }
+    json_name
+}
+
+fn qualified(name: &str) -> String {
+    if name.starts_with('.') { name.to_owned() } else { format!(".{}", name) }
+}
+
+fn display(path: &str) -> &str {
+    if path.is_empty() { "/" } else { path }
+}
+
+fn kind_name(kind: u64) -> &'static str {
+    match kind {
+        TYPE_BOOL => "bool",
+        TYPE_ENUM => "enum",
+        TYPE_DOUBLE | TYPE_FLOAT => "number",
+        _ => "integer",
+    }
+}
+
+/// 64 bit integers are strings in the proto3 JSON mapping (JSON numbers can't hold them all), but
+/// plain numbers are accepted too.
+fn json_i64(value: &Json) -> Option<i64> {
+    match *value {
+        Json::String(ref string) => string.parse().ok(),
+        Json::F64(number) if number.fract() == 0.0 => Some(number as i64),
+        _ => value.as_i64(),
+    }
+}
+
+fn json_u64(value: &Json) -> Option<u64> {
+    match *value {
+        Json::String(ref string) => string.parse().ok(),
+        Json::F64(number) if number.fract() == 0.0 && number >= 0.0 => Some(number as u64),
+        Json::I64(number) if number >= 0 => Some(number as u64),
+        _ => value.as_u64(),
+    }
+}
+
+fn json_f64(value: &Json) -> Option<f64> {
+    match *value {
+        Json::String(ref string) => string.parse().ok(),
+        _ => value.as_f64(),
+    }
+}
+
+fn write_varint(mut value: u64, buffer: &mut Vec<u8>) {
+    while value & !0x7f != 0 {
+        buffer.push(((value & 0x7f) | 0x80) as u8);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+fn write_key(number: u64, wire_type: u64, buffer: &mut Vec<u8>) {
+    write_varint((number << 3) | wire_type, buffer);
+}
+
+fn write_little_endian(bits: u64, width: usize, buffer: &mut Vec<u8>) {
+    for index in 0..width {
+        buffer.push((bits >> (index * 8)) as u8);
+    }
+}
+
+/// The message types to encode each protobuf topic as.
+#[derive(Clone, Debug)]
+pub struct ProtobufTopics {
+    pool: DescriptorPool,
+    topics: BTreeMap<String, String>,
+}
+
+impl ProtobufTopics {
+    /// Loads the descriptor set, making sure it has every topic's message type.
+    ///
+    /// * `descriptor_set` - The path to the compiled `FileDescriptorSet`.
+    /// * `topics` - The fully qualified message type for each topic.
+    pub fn load(descriptor_set: &path::Path, topics: BTreeMap<String, String>) -> Result<ProtobufTopics, String> {
+        let pool = try!(DescriptorPool::load(descriptor_set));
+        for (topic, message) in topics.iter() {
+            if !pool.has_message(message) {
+                return Err(format!("The message type [ {} ] for [ {} ] isn't in [ {:?} ].", message, topic, descriptor_set));
+            }
+        }
+        Ok(ProtobufTopics {
+            pool: pool,
+            topics: topics,
+        })
+    }
+
+    /// Whether a topic is a protobuf topic.
+    pub fn applies_to(&self, topic: &str) -> bool {
+        self.topics.contains_key(topic)
+    }
+
+    /// Converts a JSON body to protobuf, if the topic is a protobuf topic.
+    /// Returns `None` for other topics, and the conversion error for bodies that don't fit.
+    pub fn encode(&self, topic: &str, body: &[u8]) -> Option<Result<Vec<u8>, String>> {
+        self.topics.get(topic).map(|message| {
+            try!(::utils::check_json_depth(body));
+            let text = try!(::std::str::from_utf8(body).map_err(|_| "The body isn't valid UTF-8.".to_owned()));
+            let value = try!(Json::from_str(text).map_err(|e| format!("The body isn't valid JSON: {}", e)));
+            self.pool.encode(message, &value)
+        })
+    }
+}
+
+/// Writes a length delimited field, for building descriptors in the tests.
+#[cfg(test)]
+fn test_field(number: u64, contents: &[u8]) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    write_key(number, WIRE_LENGTH_DELIMITED, &mut buffer);
+    write_varint(contents.len() as u64, &mut buffer);
+    buffer.extend_from_slice(contents);
+    buffer
+}
+
+/// Builds a `FieldDescriptorProto`, for the tests.
+#[cfg(test)]
+fn test_field_descriptor(name: &str, number: u64, label: u64, kind: u64, type_name: &str) -> Vec<u8> {
+    let mut field = test_field(1, name.as_bytes());
+    for &(field_number, value) in [(3, number), (4, label), (5, kind)].iter() {
+        write_key(field_number, WIRE_VARINT, &mut field);
+        write_varint(value, &mut field);
+    }
+    if !type_name.is_empty() {
+        field.extend(test_field(6, type_name.as_bytes()));
+    }
+    test_field(2, &field)
+}
+
+/// A descriptor set for:
+/// `syntax = "proto3"; package shop; enum Status { CREATED = 0; SHIPPED = 1; }
+///  message Order { int64 id = 1; string customer_name = 2; repeated int32 quantities = 3;
+///  Status status = 4; Item item = 5; map<string, int32> counts = 6; }
+///  message Item { string sku = 1; }`
+#[cfg(test)]
+fn test_pool() -> DescriptorPool {
+    let mut order = test_field(1, b"Order");
+    order.extend(test_field_descriptor("id", 1, 1, TYPE_INT64, ""));
+    order.extend(test_field_descriptor("customer_name", 2, 1, TYPE_STRING, ""));
+    order.extend(test_field_descriptor("quantities", 3, LABEL_REPEATED, TYPE_INT32, ""));
+    order.extend(test_field_descriptor("status", 4, 1, TYPE_ENUM, ".shop.Status"));
+    order.extend(test_field_descriptor("item", 5, 1, TYPE_MESSAGE, ".shop.Item"));
+    order.extend(test_field_descriptor("counts", 6, LABEL_REPEATED, TYPE_MESSAGE, ".shop.Order.CountsEntry"));
+    let mut counts_entry = test_field(1, b"CountsEntry");
+    counts_entry.extend(test_field_descriptor("key", 1, 1, TYPE_STRING, ""));
+    counts_entry.extend(test_field_descriptor("value", 2, 1, TYPE_INT32, ""));
+    counts_entry.extend(test_field(7, &[0x38, 0x01]));
+    order.extend(test_field(3, &counts_entry));
+
+    let mut item = test_field(1, b"Item");
+    item.extend(test_field_descriptor("sku", 1, 1, TYPE_STRING, ""));
+
+    let mut status = test_field(1, b"Status");
+    status.extend(test_field(2, &[test_field(1, b"CREATED"), vec![0x10, 0x00]].concat()));
+    status.extend(test_field(2, &[test_field(1, b"SHIPPED"), vec![0x10, 0x01]].concat()));
+
+    let mut file = test_field(2, b"shop");
+    file.extend(test_field(4, &order));
+    file.extend(test_field(4, &item));
+    file.extend(test_field(5, &status));
+    file.extend(test_field(12, b"proto3"));
+
+    DescriptorPool::parse(&test_field(1, &file)).unwrap()
+}
+
+#[test]
+fn test_protobuf_encoding() {
+    let pool = test_pool();
+    assert!(pool.has_message("shop.Order"));
+    assert!(pool.has_message(".shop.Order.CountsEntry"));
+
+    let encoded = pool.encode("shop.Order", &Json::from_str(
+        r#"{"id": "300", "customerName": "Al", "quantities": [1, 2], "status": "SHIPPED", "item": {"sku": "A"}, "counts": {"a": 1}}"#
+    ).unwrap()).unwrap();
+    assert!(encoded == vec![
+        0x08, 0xac, 0x02,                         // id: 300
+        0x12, 0x02, b'A', b'l',                   // customer_name: "Al"
+        0x1a, 0x02, 0x01, 0x02,                   // quantities: packed [1, 2]
+        0x20, 0x01,                               // status: SHIPPED
+        0x2a, 0x03, 0x0a, 0x01, b'A',             // item: { sku: "A" }
+        0x32, 0x05, 0x0a, 0x01, b'a', 0x10, 0x01, // counts: { "a": 1 }
+    ]);
+
+    // Original field names work as well as JSON names, and nulls are skipped.
+    assert!(pool.encode("shop.Order", &Json::from_str(r#"{"customer_name": "Al", "item": null}"#).unwrap()).unwrap()
+        == vec![0x12, 0x02, b'A', b'l']);
+    // Negative int32s are sign extended.
+    assert!(pool.encode("shop.Order", &Json::from_str(r#"{"quantities": [-1]}"#).unwrap()).unwrap()
+        == vec![0x1a, 0x0a, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01]);
+
+    assert!(pool.encode("shop.Order", &Json::from_str(r#"{"id": "three"}"#).unwrap()).is_err());
+    assert!(pool.encode("shop.Order", &Json::from_str(r#"{"status": "LOST"}"#).unwrap()).is_err());
+    assert!(pool.encode("shop.Order", &Json::from_str(r#"{"unknown": 1}"#).unwrap()).is_err());
+    assert!(pool.encode("shop.Missing", &Json::from_str(r#"{}"#).unwrap()).is_err());
+}
@@ -0,0 +1,382 @@
+use iron::prelude::*;
+use iron::{status, BeforeMiddleware};
+use router::Router;
+use rustc_serialize::json;
+use std::collections::{BTreeMap, HashMap};
+use std::error::Error;
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+use ::auth::Identity;
+use ::stats::Stat;
+use ::utils::split_topics;
+
+/// Once there are this many buckets, full ones are forgotten (a full bucket is the same as a new one).
+/// If that isn't enough, the least recently used are forgotten too, down to `EVICT_TO`, so memory is
+/// bounded and eviction only happens every so often.
+const MAX_BUCKETS: usize = 10000;
+const EVICT_TO: usize = MAX_BUCKETS * 9 / 10;
+
+/// A rate limit.
+/// `rate` - How many requests per second are allowed, on average.
+/// `burst` - How many requests can be made at once.
+#[derive(Clone, Debug, PartialEq, RustcDecodable)]
+pub struct Limit {
+    pub rate: f64,
+    pub burst: f64,
+}
+
+/// The rate limits. Loaded from a JSON file of the form:
+/// `{ "client": {"rate": 50, "burst": 100}, "topic": {"rate": 500, "burst": 1000},
+///    "clients": { "<identity>": {...} }, "topics": { "<topic>": {...} } }`
+/// Where every field is optional. "client", and "topic" are the defaults, and
+/// "clients", and "topics" override them for a specific client, or topic.
+#[derive(Clone, Debug, RustcDecodable)]
+pub struct RateLimits {
+    pub client: Option<Limit>,
+    pub topic: Option<Limit>,
+    pub clients: Option<BTreeMap<String, Limit>>,
+    pub topics: Option<BTreeMap<String, Limit>>,
+}
+
+impl RateLimits {
+    /// Loads the rate limits from a file, making sure every limit is positive.
+    pub fn load(path: &path::Path) -> Result<RateLimits, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read rate limits [ {:?} ]: {}", path, e)));
+        let limits: RateLimits = try!(json::decode(&contents)
+            .map_err(|e| format!("Failed to parse rate limits [ {:?} ]: {}", path, e)));
+
+        let mut all_limits: Vec<&Limit> = limits.client.iter().chain(limits.topic.iter()).collect();
+        all_limits.extend(limits.clients.iter().flat_map(|clients| clients.values()));
+        all_limits.extend(limits.topics.iter().flat_map(|topics| topics.values()));
+        if all_limits.iter().any(|limit| limit.rate <= 0.0 || limit.burst < 1.0) {
+            return Err(format!("Rate limits in [ {:?} ] must have a positive rate, and a burst of at least 1.", path));
+        }
+
+        Ok(limits)
+    }
+
+    fn client_limit(&self, client: &str) -> Option<&Limit> {
+        self.clients.as_ref().and_then(|clients| clients.get(client)).or(self.client.as_ref())
+    }
+
+    fn topic_limit(&self, topic: &str) -> Option<&Limit> {
+        self.topics.as_ref().and_then(|topics| topics.get(topic)).or(self.topic.as_ref())
+    }
+}
+
+/// A Token Bucket. Starts full, and refills at its limit's `rate` tokens a second up to `burst`.
+#[derive(Clone, Debug)]
+pub struct TokenBucket {
+    limit: Limit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(limit: &Limit, now: Instant) -> TokenBucket {
+        TokenBucket {
+            limit: limit.clone(),
+            tokens: limit.burst,
+            last_refill: now,
+        }
+    }
+
+    /// How many tokens the bucket would have at `now`.
+    fn tokens_at(&self, now: Instant) -> f64 {
+        if now > self.last_refill {
+            let elapsed = now.duration_since(self.last_refill);
+            let elapsed = elapsed.as_secs() as f64 + elapsed.subsec_nanos() as f64 / 1_000_000_000.0;
+            (self.tokens + elapsed * self.limit.rate).min(self.limit.burst)
+        } else {
+            self.tokens
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        self.tokens = self.tokens_at(now);
+        if now > self.last_refill {
+            self.last_refill = now;
+        }
+    }
+
+    /// Takes a token from the bucket. If the bucket is empty returns
+    /// how long until there will be a token to take.
+    pub fn take(&mut self, now: Instant) -> Result<(), Duration> {
+        self.refill(now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            let wait = (1.0 - self.tokens) / self.limit.rate;
+            Err(Duration::from_millis((wait * 1000.0).ceil() as u64))
+        }
+    }
+
+    /// Puts back a token taken by `take`.
+    pub fn refund(&mut self) {
+        self.tokens = (self.tokens + 1.0).min(self.limit.burst);
+    }
+
+    fn is_full(&self, now: Instant) -> bool {
+        self.tokens_at(now) >= self.limit.burst
+    }
+}
+
+/// The error returned when a request is rate limited.
+#[derive(Debug)]
+pub struct RateLimitError {
+    pub message: String,
+}
+
+impl fmt::Display for RateLimitError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for RateLimitError {
+    fn description(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Rate limits requests, by client and by topic. Limited requests get a 429, with a `Retry-After` header.
+/// Shared between the `RateLimitMiddleware`, and the handler, which limits routed topics once they're known.
+pub struct RateLimiter {
+    limits: RateLimits,
+    client_buckets: Mutex<HashMap<String, TokenBucket>>,
+    topic_buckets: Mutex<HashMap<String, TokenBucket>>,
+    stats: Option<Arc<Mutex<Sender<Stat>>>>,
+}
+
+impl RateLimiter {
+    /// Creates a new RateLimiter.
+    ///
+    /// * `limits` - The rate limits to apply.
+    /// * `stats` - Where to report limited requests, `None` to not report them.
+    pub fn new(limits: RateLimits, stats: Option<Arc<Mutex<Sender<Stat>>>>) -> RateLimiter {
+        RateLimiter {
+            limits: limits,
+            client_buckets: Mutex::new(HashMap::new()),
+            topic_buckets: Mutex::new(HashMap::new()),
+            stats: stats,
+        }
+    }
+
+    /// Forgets every full bucket, then the least recently used, until there are at most `EVICT_TO`.
+    fn evict(buckets: &mut HashMap<String, TokenBucket>, now: Instant) {
+        buckets.retain(|_, bucket| !bucket.is_full(now));
+        if buckets.len() > EVICT_TO {
+            let excess = buckets.len() - EVICT_TO;
+            let mut by_last_use: Vec<(Instant, String)> = buckets.iter()
+                .map(|(key, bucket)| (bucket.last_refill, key.clone()))
+                .collect();
+            by_last_use.sort();
+            for &(_, ref key) in by_last_use.iter().take(excess) {
+                buckets.remove(key);
+            }
+        }
+    }
+
+    /// Takes a token from the bucket for `key`, creating it if needed.
+    fn take(buckets: &Mutex<HashMap<String, TokenBucket>>, key: &str, limit: &Limit, now: Instant) -> Result<(), Duration> {
+        let mut buckets = buckets.lock().unwrap();
+        if buckets.len() >= MAX_BUCKETS && !buckets.contains_key(key) {
+            RateLimiter::evict(&mut buckets, now);
+        }
+        buckets.entry(key.to_owned())
+            .or_insert_with(|| TokenBucket::new(limit, now))
+            .take(now)
+    }
+
+    /// Checks the client's limit (when there's a `client`), and every topic's limit for a request, only taking
+    /// tokens if they all allow it. Requests without a topic (`/route`) only have the client limit.
+    /// Returns the topic that was limited (`None` for the client), and how long to wait.
+    pub fn check(&self, client: Option<&str>, topics: &[&str], now: Instant) -> Result<(), (Option<String>, Duration)> {
+        let client_limit = client.and_then(|client| self.limits.client_limit(client).map(|limit| (client, limit)));
+        if let Some((client, limit)) = client_limit {
+            if let Err(wait) = RateLimiter::take(&self.client_buckets, client, limit, now) {
+                return Err((None, wait));
+            }
+        }
+
+        let mut taken: Vec<&str> = Vec::new();
+        for &topic in topics.iter() {
+            if let Some(limit) = self.limits.topic_limit(topic) {
+                if let Err(wait) = RateLimiter::take(&self.topic_buckets, topic, limit, now) {
+                    let mut topic_buckets = self.topic_buckets.lock().unwrap();
+                    for taken_topic in taken.iter() {
+                        if let Some(bucket) = topic_buckets.get_mut(*taken_topic) {
+                            bucket.refund();
+                        }
+                    }
+                    if let Some((client, _)) = client_limit {
+                        if let Some(bucket) = self.client_buckets.lock().unwrap().get_mut(client) {
+                            bucket.refund();
+                        }
+                    }
+                    return Err((Some(topic.to_string()), wait));
+                }
+                taken.push(topic);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Like `check`, but reports limited requests, and turns them into the 429 to answer with.
+    pub fn limit(&self, client: Option<&str>, topics: &[&str], now: Instant) -> IronResult<()> {
+        match self.check(client, topics, now) {
+            Ok(()) => Ok(()),
+            Err((limited_topic, wait)) => {
+                let is_topic_limit = limited_topic.is_some();
+                if let Some(ref stats) = self.stats {
+                    let _ = stats.lock().unwrap().send(Stat::RateLimited { is_topic_limit: is_topic_limit });
+                }
+
+                let retry_after = wait.as_secs() + if wait.subsec_nanos() > 0 { 1 } else { 0 };
+                let message = match limited_topic {
+                    Some(topic) => format!("Too many requests for [ {} ], retry after {} seconds.", topic, retry_after),
+                    None => format!("Too many requests from [ {} ], retry after {} seconds.", client.unwrap_or(""), retry_after),
+                };
+                debug!("{}", message);
+
+                let mut response = Response::with((status::TooManyRequests, message.clone()));
+                response.headers.set_raw("Retry-After", vec![retry_after.to_string().into_bytes()]);
+                Err(IronError {
+                    error: Box::new(RateLimitError { message: message }),
+                    response: response,
+                })
+            }
+        }
+    }
+}
+
+/// An Iron Middleware that rate limits requests, by client and by the posted topics.
+/// Clients are identified by their authenticated identity (so the API Key, JWT subject,
+/// or client certificate) when there is one, otherwise by their IP. Must be linked after
+/// the Authenticator so the identity is known.
+pub struct RateLimitMiddleware {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitMiddleware {
+    /// Creates a new RateLimitMiddleware, sharing the limiter with the handler.
+    pub fn new(limiter: Arc<RateLimiter>) -> RateLimitMiddleware {
+        RateLimitMiddleware { limiter: limiter }
+    }
+}
+
+impl BeforeMiddleware for RateLimitMiddleware {
+    fn before(&self, req: &mut Request) -> IronResult<()> {
+        let client = match req.extensions.get::<Identity>() {
+            Some(identity) => identity.name.clone(),
+            None => req.remote_addr.ip().to_string(),
+        };
+        // A message posted to several topics takes a token from each of them.
+        let topics = req.extensions.get::<Router>().and_then(|params| params.find("topic")).map(split_topics).unwrap_or(Vec::new());
+        let topics: Vec<&str> = topics.iter().map(|topic| &topic[..]).collect();
+
+        self.limiter.limit(Some(&client), &topics, Instant::now())
+    }
+}
+
+#[test]
+fn test_token_bucket() {
+    let limit = Limit { rate: 2.0, burst: 2.0 };
+    let start = Instant::now();
+    let mut bucket = TokenBucket::new(&limit, start);
+
+    assert!(bucket.take(start).is_ok());
+    assert!(bucket.take(start).is_ok());
+    assert!(bucket.take(start) == Err(Duration::from_millis(500)));
+    assert!(bucket.take(start + Duration::from_millis(500)).is_ok());
+    // Refilling never goes past the burst.
+    assert!(bucket.take(start + Duration::from_secs(60)).is_ok());
+    assert!(bucket.take(start + Duration::from_secs(60)).is_ok());
+    assert!(bucket.take(start + Duration::from_secs(60)).is_err());
+}
+
+#[test]
+fn test_rate_limiter_check() {
+    let mut topics = BTreeMap::new();
+    topics.insert("audit".to_owned(), Limit { rate: 1.0, burst: 1.0 });
+    let limiter = RateLimiter::new(RateLimits {
+        client: Some(Limit { rate: 1.0, burst: 2.0 }),
+        topic: None,
+        clients: None,
+        topics: Some(topics),
+    }, None);
+    let now = Instant::now();
+
+    assert!(limiter.check(Some("billing"), &["audit"], now).is_ok());
+    // The topic is limited, and the client's token is given back.
+    assert!(limiter.check(Some("billing"), &["audit"], now).map_err(|(topic, _)| topic) == Err(Some("audit".to_owned())));
+    assert!(limiter.check(Some("billing"), &["orders"], now).is_ok());
+    assert!(limiter.check(Some("billing"), &["orders"], now).map_err(|(topic, _)| topic) == Err(None));
+    // Routed topics are checked without the client, as it was checked before routing.
+    assert!(limiter.check(None, &["orders"], now).is_ok());
+    assert!(limiter.check(None, &["audit"], now).map_err(|(topic, _)| topic) == Err(Some("audit".to_owned())));
+    // Other clients have their own bucket.
+    assert!(limiter.check(Some("search"), &["orders"], now).is_ok());
+    // Requests without a topic only take from the client's bucket.
+    assert!(limiter.check(Some("search"), &[], now).is_ok());
+    assert!(limiter.check(Some("search"), &[], now).map_err(|(topic, _)| topic) == Err(None));
+}
+
+#[test]
+fn test_rate_limiter_fan_out() {
+    let mut topics = BTreeMap::new();
+    topics.insert("audit".to_owned(), Limit { rate: 1.0, burst: 1.0 });
+    topics.insert("orders".to_owned(), Limit { rate: 1.0, burst: 2.0 });
+    let limiter = RateLimiter::new(RateLimits {
+        client: None,
+        topic: None,
+        clients: None,
+        topics: Some(topics),
+    }, None);
+    let now = Instant::now();
+
+    assert!(limiter.check(Some("billing"), &["orders", "audit"], now).is_ok());
+    // `audit` is limited, so the token taken from `orders` is given back.
+    assert!(limiter.check(Some("billing"), &["orders", "audit"], now).map_err(|(topic, _)| topic) == Err(Some("audit".to_owned())));
+    assert!(limiter.check(Some("billing"), &["orders"], now).is_ok());
+    assert!(limiter.check(Some("billing"), &["orders"], now).is_err());
+}
+
+#[test]
+fn test_rate_limiter_eviction() {
+    let mut clients = BTreeMap::new();
+    clients.insert("throttled".to_owned(), Limit { rate: 0.001, burst: 1.0 });
+    let limiter = RateLimiter::new(RateLimits {
+        client: Some(Limit { rate: 1000.0, burst: 1000.0 }),
+        topic: None,
+        clients: Some(clients),
+        topics: None,
+    }, None);
+    let now = Instant::now();
+
+    assert!(limiter.check(Some("throttled"), &[], now).is_ok());
+    for client in 1..MAX_BUCKETS {
+        assert!(limiter.check(Some(&client.to_string()), &[], now).is_ok());
+    }
+    // A new client evicts every full bucket, but the override bucket refills at its own rate, so isn't one.
+    let later = now + Duration::from_secs(10);
+    assert!(limiter.check(Some("new"), &[], later).is_ok());
+    assert!(limiter.client_buckets.lock().unwrap().len() == 2);
+    assert!(limiter.check(Some("throttled"), &[], later).is_err());
+
+    // When nothing is full the least recently used are forgotten, so memory stays bounded.
+    for client in 0..MAX_BUCKETS {
+        assert!(limiter.check(Some(&client.to_string()), &[], later).is_ok());
+    }
+    assert!(limiter.client_buckets.lock().unwrap().len() <= MAX_BUCKETS);
+}
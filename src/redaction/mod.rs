@@ -0,0 +1,385 @@
+use regex::Regex;
+use ring::{digest, hmac};
+use rustc_serialize::hex::ToHex;
+use rustc_serialize::json::{self, Json};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use ::auth::topic_matches;
+use ::transform::path::JsonPath;
+
+/// What to do with the values a rule finds.
+/// `Mask` - Replaces every character with `*`.
+/// `Hash` - Replaces the value with its SHA-256 (or HMAC-SHA256 with a `hash_key`) in hex.
+/// `Drop` - Removes the field (or for text bodies, the matching text).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Action {
+    Mask,
+    Hash,
+    Drop,
+}
+
+/// How a rule finds what to redact.
+/// `Pattern` - Every match of a regex, in any string, number, or key (or anywhere in text bodies).
+/// `Path` - The values at a JSONPath.
+#[derive(Clone, Debug)]
+pub enum Matcher {
+    Pattern(Regex),
+    Path(JsonPath),
+}
+
+/// A redaction rule. Applies to every topic, unless `topics` is set.
+#[derive(Clone, Debug)]
+pub struct Rule {
+    pub name: String,
+    pub matcher: Matcher,
+    pub action: Action,
+    pub topics: Option<Vec<String>>,
+}
+
+impl Rule {
+    fn applies_to(&self, topic: &str) -> bool {
+        match self.topics {
+            Some(ref topics) => topics.iter().any(|pattern| topic_matches(pattern, topic)),
+            None => true,
+        }
+    }
+}
+
+/// A body after redaction, and how many times each rule hit.
+#[derive(Debug, PartialEq)]
+pub struct Redacted {
+    pub body: Vec<u8>,
+    pub hits: Vec<(String, usize)>,
+}
+
+/// Every redaction rule, and the key to hash with.
+#[derive(Clone, Debug)]
+pub struct Redactor {
+    rules: Vec<Rule>,
+    hash_key: Option<Vec<u8>>,
+}
+
+fn parse_rule(rule: &Json) -> Result<Rule, String> {
+    let name = try!(rule.find("name").and_then(|name| name.as_string())
+        .ok_or(format!("Every rule needs a `name`, found: {}", rule))).to_owned();
+
+    let matcher = match (rule.find("pattern").and_then(|pattern| pattern.as_string()),
+                         rule.find("path").and_then(|path| path.as_string())) {
+        (Some(pattern), None) => Matcher::Pattern(try!(Regex::new(pattern)
+            .map_err(|e| format!("[ {} ]: Invalid pattern [ {} ]: {}", name, pattern, e)))),
+        (None, Some(raw_path)) => {
+            let parsed = try!(JsonPath::parse(raw_path).map_err(|e| format!("[ {} ]: {}", name, e)));
+            if parsed.is_root() {
+                return Err(format!("[ {} ]: A rule can't redact the whole body.", name));
+            }
+            Matcher::Path(parsed)
+        },
+        _ => return Err(format!("[ {} ]: A rule needs either a `pattern`, or a `path`.", name)),
+    };
+
+    let action = match rule.find("action").and_then(|action| action.as_string()) {
+        Some("mask") => Action::Mask,
+        Some("hash") => Action::Hash,
+        Some("drop") => Action::Drop,
+        _ => return Err(format!("[ {} ]: `action` must be one of `mask`, `hash`, or `drop`.", name)),
+    };
+
+    let topics = match rule.find("topics") {
+        Some(topics) => {
+            let topics = try!(topics.as_array().ok_or(format!("[ {} ]: `topics` must be a list.", name)));
+            Some(topics.iter().filter_map(|topic| topic.as_string()).map(|topic| topic.to_owned()).collect())
+        },
+        None => None,
+    };
+
+    Ok(Rule {
+        name: name,
+        matcher: matcher,
+        action: action,
+        topics: topics,
+    })
+}
+
+fn mask(text: &str) -> String {
+    text.chars().map(|_| '*').collect()
+}
+
+impl Redactor {
+    /// Loads the rules from a JSON file of `{"hash_key": "...", "rules": [...]}`.
+    pub fn load(path: &path::Path) -> Result<Redactor, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read the redaction rules [ {:?} ]: {}", path, e)));
+        Redactor::parse(&contents).map_err(|e| format!("Invalid redaction rules [ {:?} ]: {}", path, e))
+    }
+
+    /// Parses the rules.
+    pub fn parse(contents: &str) -> Result<Redactor, String> {
+        let parsed = try!(Json::from_str(contents).map_err(|e| e.to_string()));
+        let raw_rules = try!(parsed.find("rules").and_then(|rules| rules.as_array())
+            .ok_or("Expected a list of `rules`.".to_owned()));
+
+        let mut rules = Vec::new();
+        for rule in raw_rules.iter() {
+            rules.push(try!(parse_rule(rule)));
+        }
+
+        Ok(Redactor {
+            rules: rules,
+            hash_key: parsed.find("hash_key").and_then(|key| key.as_string()).map(|key| key.as_bytes().to_vec()),
+        })
+    }
+
+    /// Whether any rule applies to a topic.
+    pub fn applies_to(&self, topic: &str) -> bool {
+        self.rules.iter().any(|rule| rule.applies_to(topic))
+    }
+
+    fn hash(&self, value: &[u8]) -> String {
+        match self.hash_key {
+            Some(ref hash_key) => hmac::sign(&hmac::SigningKey::new(&digest::SHA256, hash_key), value).as_ref().to_hex(),
+            None => digest::digest(&digest::SHA256, value).as_ref().to_hex(),
+        }
+    }
+
+    /// Replaces every match of a pattern in `text`. Returns the new text, and how many matches there were.
+    fn redact_text(&self, pattern: &Regex, action: Action, text: &str) -> (String, usize) {
+        let hits = pattern.find_iter(text).count();
+        if hits == 0 {
+            return (text.to_owned(), 0);
+        }
+        let redacted = pattern.replace_all(text, |captures: &::regex::Captures| {
+            let matched = captures.get(0).map(|matched| matched.as_str()).unwrap_or("");
+            match action {
+                Action::Mask => mask(matched),
+                Action::Hash => self.hash(matched.as_bytes()),
+                Action::Drop => String::new(),
+            }
+        });
+        (redacted.into_owned(), hits)
+    }
+
+    /// Redacts every string, number, and object key in a JSON value matching a pattern. Numbers are
+    /// matched as they're written, and replaced with a string when redacted.
+    /// Returns whether `value` itself should be dropped, and adds to `hits`.
+    fn redact_strings(&self, pattern: &Regex, action: Action, value: &mut Json, hits: &mut usize) -> bool {
+        let redacted_number = match *value {
+            Json::String(ref mut string) => {
+                if action == Action::Drop {
+                    let found = pattern.find_iter(string).count();
+                    *hits += found;
+                    return found > 0;
+                }
+                let (redacted, found) = self.redact_text(pattern, action, string);
+                *hits += found;
+                *string = redacted;
+                None
+            },
+            Json::I64(_) | Json::U64(_) | Json::F64(_) => {
+                let (redacted, found) = self.redact_text(pattern, action, &json::encode(value).unwrap());
+                *hits += found;
+                if found > 0 && action == Action::Drop {
+                    return true;
+                }
+                if found > 0 { Some(redacted) } else { None }
+            },
+            Json::Object(ref mut object) => {
+                for (key, mut field) in ::std::mem::replace(object, BTreeMap::new()) {
+                    let (key, found) = self.redact_text(pattern, action, &key);
+                    *hits += found;
+                    if found > 0 && action == Action::Drop {
+                        continue;
+                    }
+                    if !self.redact_strings(pattern, action, &mut field, hits) {
+                        object.insert(key, field);
+                    }
+                }
+                None
+            },
+            Json::Array(ref mut array) => {
+                let mut kept = Vec::new();
+                for mut item in array.drain(..) {
+                    if !self.redact_strings(pattern, action, &mut item, hits) {
+                        kept.push(item);
+                    }
+                }
+                *array = kept;
+                None
+            },
+            _ => None,
+        };
+        if let Some(redacted) = redacted_number {
+            *value = Json::String(redacted);
+        }
+        false
+    }
+
+    /// Redacts the value at a path, returning how many values were redacted.
+    fn redact_path(&self, path: &JsonPath, action: Action, value: &mut Json) -> usize {
+        if action == Action::Drop {
+            return path.remove(value);
+        }
+
+        let mut hits = 0;
+        path.for_each_mut(value, &mut |field: &mut Json| {
+            let text = match *field {
+                Json::String(ref string) => string.clone(),
+                ref other => json::encode(other).unwrap(),
+            };
+            *field = Json::String(match action {
+                Action::Hash => self.hash(text.as_bytes()),
+                _ => mask(&text),
+            });
+            hits += 1;
+        });
+        hits
+    }
+
+    /// Runs every rule for a topic over a body. JSON bodies get every rule, and text bodies only
+    /// get the pattern rules. Bodies that can't be checked (binary bodies, or text bodies on a topic
+    /// with path rules) are refused rather than sent unredacted.
+    /// The body is only re-encoded when a rule hit, so bodies without PII are sent untouched.
+    pub fn redact(&self, topic: &str, body: Vec<u8>) -> Result<Redacted, String> {
+        let rules: Vec<&Rule> = self.rules.iter().filter(|rule| rule.applies_to(topic)).collect();
+        if rules.is_empty() {
+            return Ok(Redacted { body: body, hits: Vec::new() });
+        }
+
+        try!(::utils::check_json_depth(&body));
+        let text = try!(String::from_utf8(body)
+            .map_err(|_| format!("Bodies for [ {} ] must be text, so they can be checked for redaction.", topic)));
+
+        let mut hits = BTreeMap::new();
+        let body = match Json::from_str(&text) {
+            Ok(mut value) => {
+                for rule in rules.iter() {
+                    let found = match rule.matcher {
+                        Matcher::Pattern(ref pattern) => {
+                            let mut found = 0;
+                            if self.redact_strings(pattern, rule.action, &mut value, &mut found) {
+                                // The whole body was a string that matched, so there's nothing left.
+                                value = Json::Null;
+                            }
+                            found
+                        },
+                        Matcher::Path(ref path) => self.redact_path(path, rule.action, &mut value),
+                    };
+                    if found > 0 {
+                        *hits.entry(rule.name.clone()).or_insert(0) += found;
+                    }
+                }
+                if hits.is_empty() { text.into_bytes() } else { json::encode(&value).unwrap().into_bytes() }
+            },
+            Err(e) => {
+                // Paths can't be found outside of JSON, so bodies they'd apply to are refused rather than sent as they are.
+                if let Some(rule) = rules.iter().find(|rule| if let Matcher::Path(_) = rule.matcher { true } else { false }) {
+                    return Err(format!("Bodies for [ {} ] must be JSON, so [ {} ] can be redacted: {}", topic, rule.name, e));
+                }
+                let mut text = text;
+                for rule in rules.iter() {
+                    if let Matcher::Pattern(ref pattern) = rule.matcher {
+                        let (redacted, found) = self.redact_text(pattern, rule.action, &text);
+                        if found > 0 {
+                            *hits.entry(rule.name.clone()).or_insert(0) += found;
+                            text = redacted;
+                        }
+                    }
+                }
+                text.into_bytes()
+            },
+        };
+
+        Ok(Redacted {
+            body: body,
+            hits: hits.into_iter().collect(),
+        })
+    }
+}
+
+#[test]
+fn test_redaction_rules() {
+    let redactor = Redactor::parse(r#"{
+        "rules": [
+            {"name": "ssn", "pattern": "\\d{3}-\\d{2}-\\d{4}", "action": "mask"},
+            {"name": "email", "pattern": "[a-z]+@example\\.com", "action": "drop", "topics": ["orders*"]},
+            {"name": "card", "path": "$.card.number", "action": "hash", "topics": ["orders*"]}
+        ]
+    }"#).unwrap();
+    assert!(redactor.applies_to("audit"));
+
+    let redacted = redactor.redact("orders.created", br#"{"note": "ssn 123-45-6789", "email": "al@example.com", "card": {"number": "4111"}}"#.to_vec()).unwrap();
+    let body = Json::from_str(&String::from_utf8(redacted.body).unwrap()).unwrap();
+    assert!(body.find("note").and_then(|note| note.as_string()) == Some("ssn ***********"));
+    assert!(body.find("email").is_none());
+    assert!(body.find_path(&["card", "number"]).and_then(|number| number.as_string()).map(|hash| hash.len()) == Some(64));
+    assert!(redacted.hits == vec![("card".to_owned(), 1), ("email".to_owned(), 1), ("ssn".to_owned(), 1)]);
+
+    // Only the rules for the topic apply, and text bodies only get the patterns.
+    let text = redactor.redact("audit", b"al@example.com 123-45-6789".to_vec()).unwrap();
+    assert!(text.body == b"al@example.com ***********".to_vec());
+    assert!(text.hits == vec![("ssn".to_owned(), 1)]);
+
+    // Bodies without any hits are sent byte for byte.
+    let untouched = redactor.redact("audit", br#"{ "fine":  true }"#.to_vec()).unwrap();
+    assert!(untouched.body == br#"{ "fine":  true }"#.to_vec());
+    assert!(untouched.hits.is_empty());
+
+    // Text bodies can't have their paths redacted, so topics with path rules refuse them.
+    assert!(redactor.redact("orders", br#"{"card": {"number": "4111"}"#.to_vec()).is_err());
+    let patterns_only = Redactor::parse(r#"{"rules": [{"name": "ssn", "pattern": "\\d{3}-\\d{2}-\\d{4}", "action": "mask"}]}"#).unwrap();
+    assert!(patterns_only.redact("orders", br#"{"ssn": "123-45-6789""#.to_vec()).unwrap().body == br#"{"ssn": "***********""#.to_vec());
+
+    // Binary bodies can't be checked, so are refused, unless no rule applies to the topic.
+    assert!(redactor.redact("audit", vec![0xff, 0xfe]).is_err());
+    let scoped = Redactor::parse(r#"{"rules": [{"name": "email", "pattern": "@", "action": "mask", "topics": ["orders"]}]}"#).unwrap();
+    assert!(scoped.redact("audit", vec![0xff, 0xfe]).unwrap().body == vec![0xff, 0xfe]);
+}
+
+#[test]
+fn test_redaction_numbers_and_keys() {
+    let redactor = Redactor::parse(r#"{
+        "rules": [
+            {"name": "card", "pattern": "\\d{16}", "action": "mask"},
+            {"name": "email", "pattern": "[a-z]+@example\\.com", "action": "drop"}
+        ]
+    }"#).unwrap();
+
+    let redacted = redactor.redact("orders", br#"{"card": 4111111111111111, "total": 12, "al@example.com": "subscribed"}"#.to_vec()).unwrap();
+    let body = Json::from_str(&String::from_utf8(redacted.body).unwrap()).unwrap();
+    assert!(body.find("card").and_then(|card| card.as_string()) == Some("****************"));
+    assert!(body.find("total").and_then(|total| total.as_u64()) == Some(12));
+    assert!(body.find("al@example.com").is_none());
+    assert!(redacted.hits == vec![("card".to_owned(), 1), ("email".to_owned(), 1)]);
+
+    let masked_key = Redactor::parse(r#"{"rules": [{"name": "email", "pattern": "[a-z]+@example\\.com", "action": "mask"}]}"#).unwrap();
+    let redacted = masked_key.redact("orders", br#"{"al@example.com": {"ok": true}}"#.to_vec()).unwrap();
+    assert!(redacted.body == br#"{"**************":{"ok":true}}"#.to_vec());
+}
+
+#[test]
+fn test_redaction_hashing() {
+    let keyed = Redactor::parse(r#"{"hash_key": "secret", "rules": [{"name": "card", "path": "card", "action": "hash"}]}"#).unwrap();
+    let unkeyed = Redactor::parse(r#"{"rules": [{"name": "card", "path": "card", "action": "hash"}]}"#).unwrap();
+
+    assert!(unkeyed.redact("orders", br#"{"card": "abc"}"#.to_vec()).unwrap().body ==
+        br#"{"card":"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"}"#.to_vec());
+    assert!(keyed.redact("orders", br#"{"card": "abc"}"#.to_vec()).unwrap().body !=
+        unkeyed.redact("orders", br#"{"card": "abc"}"#.to_vec()).unwrap().body);
+
+    assert!(Redactor::parse(r#"{"rules": [{"name": "card", "path": "card", "action": "shred"}]}"#).is_err());
+    assert!(Redactor::parse(r#"{"rules": [{"name": "card", "action": "mask"}]}"#).is_err());
+    assert!(Redactor::parse(r#"{"rules": [{"name": "card", "pattern": "(", "action": "mask"}]}"#).is_err());
+}
+
+#[test]
+fn test_redaction_before_key_from() {
+    let redactor = Redactor::parse(r#"{"rules": [{"name": "email", "path": "$.email", "action": "mask"}]}"#).unwrap();
+    let pipeline = ::transform::Pipeline::parse(&Json::from_str(r#"[{"key_from": "$.email"}]"#).unwrap()).unwrap();
+
+    // Bodies are redacted before their transforms, so the key can only be the redacted value.
+    let redacted = redactor.redact("orders", br#"{"email": "al@example.com"}"#.to_vec()).unwrap();
+    let transformed = pipeline.apply(&redacted.body).unwrap();
+    assert!(transformed.key == Some(b"**************".to_vec()));
+}
@@ -2,13 +2,123 @@ use std::thread;
 use std::sync::mpsc::{Sender};
 use std::sync::{Arc, Mutex, mpsc};
 
+#[cfg(feature = "reporter-slack")]
+use handlebars::Handlebars;
+#[cfg(feature = "reporter-slack")]
+use rustc_serialize::json::{Json, ToJson};
+#[cfg(feature = "reporter-slack")]
+use std::collections::BTreeMap;
 #[cfg(feature = "reporter-slack")]
 use std::env;
+#[cfg(feature = "reporter-slack")]
+use std::fs::File;
+#[cfg(feature = "reporter-slack")]
+use std::io::Read;
+
+/// The default text posted to slack when no template has been configured.
+#[cfg(feature = "reporter-slack")]
+const DEFAULT_SLACK_TEMPLATE: &'static str = "Failed to Send to Kafka! :cry:";
+
+/// A Failure to report.
+/// Contains the topic the message was headed for, and the error
+/// that kafka gave back to us.
+#[derive(Clone, Debug)]
+pub struct Failure {
+    pub topic: String,
+    pub error: String,
+}
+
+impl Failure {
+    /// A Helper function to create a new "Failure" faster.
+    pub fn new(topic: &str, error: String) -> Failure {
+        Failure {
+            topic: topic.to_owned(),
+            error: error,
+        }
+    }
+}
+
+#[cfg(feature = "reporter-slack")]
+impl ToJson for Failure {
+    fn to_json(&self) -> Json {
+        let mut map = BTreeMap::new();
+        map.insert("topic".to_owned(), self.topic.to_json());
+        map.insert("error".to_owned(), self.error.to_json());
+        Json::Object(map)
+    }
+}
+
+/// The Slack Configuration.
+/// Read from the environment when the reporter is created, so that a
+/// misconfiguration is caught at boot rather than on the first failure.
+#[cfg(feature = "reporter-slack")]
+#[derive(Clone, Debug)]
+pub struct SlackConfig {
+    pub webhook: String,
+    pub channel: String,
+    pub topic_channels: BTreeMap<String, String>,
+    pub username: String,
+    pub icon_emoji: String,
+    pub template: String,
+}
+
+#[cfg(feature = "reporter-slack")]
+impl SlackConfig {
+    /// Reads the slack configuration from the environment.
+    /// "SLACK_WEBHOOK" is required, everything else has a default.
+    /// The template is read from "SLACK_TEMPLATE_PATH" if it is set,
+    /// otherwise from "SLACK_TEMPLATE".
+    pub fn from_env() -> Result<SlackConfig, String> {
+        let webhook = try!(env::var("SLACK_WEBHOOK")
+            .map_err(|_| "SLACK_WEBHOOK must be set when the slack reporter is enabled.".to_owned()));
 
+        let template = match env::var("SLACK_TEMPLATE_PATH") {
+            Ok(path) => {
+                let mut contents = String::new();
+                let read = File::open(&path).and_then(|mut file| file.read_to_string(&mut contents));
+                if let Err(e) = read {
+                    return Err(format!("Failed to read SLACK_TEMPLATE_PATH [ {} ]: {}", path, e));
+                }
+                contents
+            },
+            Err(_) => env::var("SLACK_TEMPLATE").unwrap_or(DEFAULT_SLACK_TEMPLATE.to_owned()),
+        };
+
+        let topic_channels = match env::var("SLACK_TOPIC_CHANNELS") {
+            Ok(raw) => try!(parse_topic_channels(&raw)),
+            Err(_) => BTreeMap::new(),
+        };
+
+        Ok(SlackConfig {
+            webhook: webhook,
+            channel: env::var("SLACK_CHANNEL").unwrap_or("#general".to_owned()),
+            topic_channels: topic_channels,
+            username: env::var("SLACK_USERNAME").unwrap_or("Kafka Reporter".to_owned()),
+            icon_emoji: env::var("SLACK_ICON_EMOJI").unwrap_or(":apache-kafka:".to_owned()),
+            template: template,
+        })
+    }
+
+    /// Returns the channel a failure for `topic` should be posted to.
+    pub fn channel_for(&self, topic: &str) -> &str {
+        self.topic_channels.get(topic).unwrap_or(&self.channel)
+    }
+}
+
+/// Parses a comma seperated list of `topic=#channel` pairs.
 #[cfg(feature = "reporter-slack")]
-lazy_static! {
-    static ref SLACK_WEBHOOK: String = env::var("SLACK_WEBHOOK").unwrap();
-    static ref SLACK_CHANNEL: String = env::var("SLACK_CHANNEL").unwrap_or("#general".to_string());
+pub fn parse_topic_channels(raw: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut channels = BTreeMap::new();
+    for pair in raw.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let mut splitter = pair.splitn(2, '=');
+        let topic = splitter.next().unwrap().trim();
+        let channel = splitter.next().map(|channel| channel.trim()).unwrap_or("");
+        if topic.is_empty() || channel.is_empty() {
+            return Err(format!("Invalid SLACK_TOPIC_CHANNELS entry: [ {} ], expected `topic=#channel`.", pair));
+        }
+        channels.insert(topic.to_owned(), channel.to_owned());
+    }
+    Ok(channels)
 }
 
 /// A Failure Reporter.
@@ -16,37 +126,65 @@ lazy_static! {
 /// that are enabled at build time. Right now the only two reporters are the
 /// "NoOp" reporter which is the default, and only logs to STDOUT, and the
 /// "reporter-slack", which will post to Slack when a message fails to send.
-/// Simply create a reporter instance, and call the function "start_reporting".
-/// That will return a mpsc Sender which has been wrapped with a mutex + arc so
-/// it can be cloned, and is thread safe.
-pub struct Reporter;
+/// Create a reporter with "new" (which validates its configuration), and call
+/// the function "start_reporting". That will return a mpsc Sender which has been
+/// wrapped with a mutex + arc so it can be cloned, and is thread safe.
+pub struct Reporter {
+    #[cfg(feature = "reporter-slack")]
+    config: SlackConfig,
+}
 
 #[cfg(feature = "reporter-slack")]
 impl Reporter {
+    /// Creates the Slack Reporter. Reads the configuration from the environment
+    /// and makes sure the message template compiles.
+    pub fn new() -> Result<Reporter, String> {
+        let config = try!(SlackConfig::from_env());
+        let mut handlebars = Handlebars::new();
+        if let Err(e) = handlebars.register_template_string("slack_message", config.template.clone()) {
+            return Err(format!("Failed to compile the slack template: {}", e));
+        }
+
+        Ok(Reporter {
+            config: config
+        })
+    }
+
     /// Starts the Slack Reporter Thread. Creates a mpsc channel,
-    /// reads from the environment variable "SLACK_WEBHOOK",
-    /// and "SLACK_CHANNEL" (which defaults to "#general").
+    /// and a slack client for the configured webhook.
     /// Then spawns a thread, and returns an Arc<Mutex<Sender>>.
-    pub fn start_reporting(&self) -> Arc<Mutex<Sender<()>>> {
+    pub fn start_reporting(&self) -> Arc<Mutex<Sender<Failure>>> {
         use slack_hook::{Slack, AttachmentBuilder, PayloadBuilder};
 
-        let (tx, rx) = mpsc::channel::<()>();
-        let slack = Slack::new(&SLACK_WEBHOOK[..]);
+        let (tx, rx) = mpsc::channel::<Failure>();
+        let config = self.config.clone();
+        let slack = Slack::new(&config.webhook[..]);
         if slack.is_err() {
             panic!("Failed to setup slack client.");
         }
         let slack = slack.unwrap();
+        let mut handlebars = Handlebars::new();
+        handlebars.register_template_string("slack_message", config.template.clone()).unwrap();
         info!("Starting Slack Reporter...");
         thread::spawn(move || {
             loop {
                 let possible_failure = rx.try_recv();
                 if possible_failure.is_ok() {
+                    let failure = possible_failure.unwrap();
+                    let text = match handlebars.render("slack_message", &failure) {
+                        Ok(text) => text,
+                        Err(e) => {
+                            error!("Failed to render the slack template: {:?}", e);
+                            DEFAULT_SLACK_TEMPLATE.to_owned()
+                        }
+                    };
+
                     let p = PayloadBuilder::new()
-                        .channel((*SLACK_CHANNEL).clone())
-                        .username("Kafka Reporter")
-                        .icon_emoji(":apache-kafka:")
+                        .channel(config.channel_for(&failure.topic).to_owned())
+                        .username(config.username.clone())
+                        .icon_emoji(config.icon_emoji.clone())
                         .attachments(vec![
-                            AttachmentBuilder::new("Failed to Send to Kafka! :cry:")
+                            AttachmentBuilder::new(text)
                                 .color("danger").build().unwrap()
                         ])
                         .build()
@@ -65,11 +203,16 @@ impl Reporter {
 
 #[cfg(all(not(feature = "reporter-slack")))]
 impl Reporter {
+    /// Creates the NoOp Reporter. This has nothing to configure.
+    pub fn new() -> Result<Reporter, String> {
+        Ok(Reporter {})
+    }
+
     /// Starts the NoOp Reporter.
     /// Creates an mpsc Channel, spins up a thread, and
     /// returns the Sender wrapped in a mutex + arc.
-    pub fn start_reporting(&self) -> Arc<Mutex<Sender<()>>> {
-        let (tx, rx) = mpsc::channel::<()>();
+    pub fn start_reporting(&self) -> Arc<Mutex<Sender<Failure>>> {
+        let (tx, rx) = mpsc::channel::<Failure>();
         info!("Starting NoOp Reporter...");
         thread::spawn(move || {
             loop {
@@ -82,3 +225,14 @@ impl Reporter {
         Arc::new(Mutex::new(tx))
     }
 }
+
+#[cfg(feature = "reporter-slack")]
+#[test]
+fn test_topic_channel_parsing() {
+    let channels = parse_topic_channels("orders=#orders-alerts, audit=#audit").unwrap();
+
+    assert!(channels.get("orders") == Some(&"#orders-alerts".to_string()));
+    assert!(channels.get("audit") == Some(&"#audit".to_string()));
+    assert!(parse_topic_channels("orders").is_err());
+    assert!(parse_topic_channels("=#general").is_err());
+}
@@ -0,0 +1,261 @@
+use regex::Regex;
+use rustc_serialize::json::{self, Json};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use ::auth::topic_matches;
+use ::transform::path::JsonPath;
+
+/// Where a route looks to decide whether a message matches.
+/// `Field` - A value in the JSON body.
+/// `Header` - A request header (by its lowercased name).
+#[derive(Clone, Debug)]
+pub enum Source {
+    Field(JsonPath),
+    Header(String),
+}
+
+/// How a route compares what it finds.
+/// `Equals` - The value is exactly this (for headers, this string).
+/// `Pattern` - The value (as text) matches this regex.
+/// `Exists` - There's a value at all.
+#[derive(Clone, Debug)]
+pub enum Comparison {
+    Equals(Json),
+    Pattern(Regex),
+    Exists,
+}
+
+/// A route. Messages it matches go to every one of its `topics`.
+/// Routes without `from` apply to `POST /route`, routes with `from` apply to
+/// `POST /kafka/<topic>` for the topics it matches (a `*` matches anything).
+#[derive(Clone, Debug)]
+pub struct Route {
+    pub from: Option<Vec<String>>,
+    pub source: Source,
+    pub comparison: Comparison,
+    pub topics: Vec<String>,
+}
+
+/// The routing table. Every route a message matches adds its topics, so a message
+/// can be rewritten to one topic, or fanned out to several.
+#[derive(Clone, Debug)]
+pub struct RoutingTable {
+    routes: Vec<Route>,
+    default: Option<String>,
+}
+
+fn parse_route(route: &Json) -> Result<Route, String> {
+    let source = match (route.find("field").and_then(|field| field.as_string()),
+                        route.find("header").and_then(|header| header.as_string())) {
+        (Some(field), None) => Source::Field(try!(JsonPath::parse(field))),
+        (None, Some(header)) => Source::Header(header.to_lowercase()),
+        _ => return Err(format!("A route needs either a `field`, or a `header`, found: {}", route)),
+    };
+
+    let comparison = match (route.find("equals"), route.find("pattern").and_then(|pattern| pattern.as_string())) {
+        (Some(equals), None) => Comparison::Equals(equals.clone()),
+        (None, Some(pattern)) => Comparison::Pattern(try!(Regex::new(pattern)
+            .map_err(|e| format!("Invalid route pattern [ {} ]: {}", pattern, e)))),
+        (None, None) => Comparison::Exists,
+        _ => return Err(format!("A route can't have both `equals`, and a `pattern`, found: {}", route)),
+    };
+
+    let topics = match (route.find("topic").and_then(|topic| topic.as_string()), route.find("topics")) {
+        (Some(topic), None) => vec![topic.to_owned()],
+        (None, Some(&Json::Array(ref topics))) if !topics.is_empty() => {
+            try!(topics.iter().map(|topic| topic.as_string().map(|topic| topic.to_owned())).collect::<Option<Vec<String>>>()
+                .ok_or(format!("`topics` must be a list of topic names, found: {}", route)))
+        },
+        _ => return Err(format!("A route needs a `topic`, or a list of `topics`, found: {}", route)),
+    };
+
+    let from = match route.find("from") {
+        Some(&Json::Array(ref from)) => Some(try!(from.iter().map(|topic| topic.as_string().map(|topic| topic.to_owned()))
+            .collect::<Option<Vec<String>>>().ok_or(format!("`from` must be a list of topics, found: {}", route)))),
+        Some(_) => return Err(format!("`from` must be a list of topics, found: {}", route)),
+        None => None,
+    };
+
+    Ok(Route {
+        from: from,
+        source: source,
+        comparison: comparison,
+        topics: topics,
+    })
+}
+
+impl Route {
+    /// Whether the route applies to a message posted to `posted` (`None` for `/route`).
+    fn applies_to(&self, posted: Option<&str>) -> bool {
+        match (self.from.as_ref(), posted) {
+            (None, None) => true,
+            (Some(from), Some(posted)) => from.iter().any(|pattern| topic_matches(pattern, posted)),
+            _ => false,
+        }
+    }
+
+    fn matches(&self, body: Option<&Json>, headers: &HashMap<String, String>) -> bool {
+        match self.source {
+            Source::Field(ref path) => {
+                let values = body.map(|body| path.select(body)).unwrap_or(Vec::new());
+                values.iter().any(|value| match self.comparison {
+                    Comparison::Equals(ref expected) => *value == expected,
+                    Comparison::Pattern(ref pattern) => match **value {
+                        Json::String(ref text) => pattern.is_match(text),
+                        ref other => pattern.is_match(&json::encode(other).unwrap()),
+                    },
+                    Comparison::Exists => **value != Json::Null,
+                })
+            },
+            Source::Header(ref name) => match (headers.get(name), &self.comparison) {
+                (Some(value), &Comparison::Equals(ref expected)) => expected.as_string() == Some(&value[..]),
+                (Some(value), &Comparison::Pattern(ref pattern)) => pattern.is_match(value),
+                (Some(_), &Comparison::Exists) => true,
+                (None, _) => false,
+            },
+        }
+    }
+}
+
+impl RoutingTable {
+    /// Loads the routing table from a JSON file of `{"default": "<topic>", "routes": [...]}`.
+    pub fn load(path: &path::Path) -> Result<RoutingTable, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read the routing table [ {:?} ]: {}", path, e)));
+        RoutingTable::parse(&contents).map_err(|e| format!("Invalid routing table [ {:?} ]: {}", path, e))
+    }
+
+    /// Parses the routing table.
+    pub fn parse(contents: &str) -> Result<RoutingTable, String> {
+        let parsed = try!(Json::from_str(contents).map_err(|e| e.to_string()));
+        let raw_routes = try!(parsed.find("routes").and_then(|routes| routes.as_array())
+            .ok_or("Expected a list of `routes`.".to_owned()));
+
+        let mut routes = Vec::new();
+        for route in raw_routes.iter() {
+            routes.push(try!(parse_route(route)));
+        }
+
+        Ok(RoutingTable {
+            routes: routes,
+            default: parsed.find("default").and_then(|default| default.as_string()).map(|default| default.to_owned()),
+        })
+    }
+
+    /// Whether any route applies to a message posted to `posted` (`None` for `/route`, which always routes).
+    pub fn applies_to(&self, posted: Option<&str>) -> bool {
+        posted.is_none() || self.routes.iter().any(|route| route.applies_to(posted))
+    }
+
+    /// The (lowercased) names of every header a route looks at.
+    pub fn header_names(&self) -> Vec<String> {
+        let mut names: Vec<String> = self.routes.iter().filter_map(|route| match route.source {
+            Source::Header(ref name) => Some(name.clone()),
+            _ => None,
+        }).collect();
+        names.sort();
+        names.dedup();
+        names
+    }
+
+    /// The topics a message goes to.
+    ///
+    /// * `posted` - The topic it was posted to, `None` for `/route`.
+    /// * `body` - The body, if it's JSON.
+    /// * `headers` - The values of the headers from `header_names`, by lowercased name.
+    ///
+    /// Messages no route matches stay on the topic they were posted to, or for `/route`
+    /// go to the default topic. Returns an error if there's no default.
+    pub fn route(&self, posted: Option<&str>, body: Option<&Json>, headers: &HashMap<String, String>) -> Result<Vec<String>, String> {
+        let mut topics: Vec<String> = Vec::new();
+        for route in self.routes.iter().filter(|route| route.applies_to(posted)) {
+            if route.matches(body, headers) {
+                for topic in route.topics.iter() {
+                    if !topics.contains(topic) {
+                        topics.push(topic.clone());
+                    }
+                }
+            }
+        }
+
+        if !topics.is_empty() {
+            return Ok(topics);
+        }
+        match (posted, self.default.as_ref()) {
+            (Some(posted), _) => Ok(vec![posted.to_owned()]),
+            (None, Some(default)) => Ok(vec![default.clone()]),
+            (None, None) => Err("No route matched the message, and there's no default topic.".to_owned()),
+        }
+    }
+}
+
+/// Adds the mirrors of every topic, after the topics themselves, without repeats.
+/// Mirrors aren't mirrored again.
+///
+/// * `topics` - The topics a message is going to.
+/// * `mirrors` - `(topic, mirror)` pairs, where the topic may have a `*`.
+pub fn mirror(topics: Vec<String>, mirrors: &[(String, String)]) -> Vec<String> {
+    let mut mirrored = topics.clone();
+    for topic in topics.iter() {
+        for &(ref pattern, ref mirror) in mirrors.iter() {
+            if topic_matches(pattern, topic) && !mirrored.contains(mirror) {
+                mirrored.push(mirror.clone());
+            }
+        }
+    }
+    mirrored
+}
+
+#[test]
+fn test_routing() {
+    let table = RoutingTable::parse(r#"{
+        "default": "events.unrouted",
+        "routes": [
+            {"field": "$.event_type", "equals": "signup", "topic": "users.signup"},
+            {"field": "$.event_type", "pattern": "^order\\.", "topics": ["orders", "audit"]},
+            {"header": "X-Source", "equals": "billing", "topic": "audit"},
+            {"from": ["legacy.*"], "field": "$.type", "topic": "events.typed"}
+        ]
+    }"#).unwrap();
+    let no_headers = HashMap::new();
+    let body = |text: &str| Json::from_str(text).unwrap();
+
+    assert!(table.header_names() == vec!["x-source".to_owned()]);
+    assert!(table.route(None, Some(&body(r#"{"event_type": "signup"}"#)), &no_headers).unwrap() == vec!["users.signup".to_owned()]);
+
+    // Every route that matches adds its topics, without repeats.
+    let mut headers = HashMap::new();
+    headers.insert("x-source".to_owned(), "billing".to_owned());
+    assert!(table.route(None, Some(&body(r#"{"event_type": "order.created"}"#)), &headers).unwrap() ==
+        vec!["orders".to_owned(), "audit".to_owned()]);
+
+    assert!(table.route(None, Some(&body(r#"{"event_type": "refund"}"#)), &no_headers).unwrap() == vec!["events.unrouted".to_owned()]);
+    assert!(table.route(None, None, &no_headers).unwrap() == vec!["events.unrouted".to_owned()]);
+
+    // Routes with `from` only apply to those topics, and unmatched messages stay where they were posted.
+    assert!(table.applies_to(Some("legacy.clicks")));
+    assert!(!table.applies_to(Some("orders")));
+    assert!(table.route(Some("legacy.clicks"), Some(&body(r#"{"type": "click"}"#)), &no_headers).unwrap() == vec!["events.typed".to_owned()]);
+    assert!(table.route(Some("legacy.clicks"), Some(&body(r#"{"event_type": "signup"}"#)), &no_headers).unwrap() == vec!["legacy.clicks".to_owned()]);
+
+    let without_default = RoutingTable::parse(r#"{"routes": []}"#).unwrap();
+    assert!(without_default.route(None, None, &no_headers).is_err());
+
+    assert!(RoutingTable::parse(r#"{"routes": [{"field": "$.a", "equals": 1}]}"#).is_err());
+    assert!(RoutingTable::parse(r#"{"routes": [{"equals": 1, "topic": "a"}]}"#).is_err());
+    assert!(RoutingTable::parse(r#"{"routes": [{"field": "$.a", "equals": 1, "pattern": "x", "topic": "a"}]}"#).is_err());
+}
+
+#[test]
+fn test_mirroring() {
+    let mirrors = vec![("orders.*".to_owned(), "audit".to_owned()), ("audit".to_owned(), "archive".to_owned()),
+        ("payments".to_owned(), "ledger".to_owned())];
+
+    assert!(mirror(vec!["orders.created".to_owned()], &mirrors) == vec!["orders.created".to_owned(), "audit".to_owned()]);
+    assert!(mirror(vec!["orders.created".to_owned(), "audit".to_owned()], &mirrors) ==
+        vec!["orders.created".to_owned(), "audit".to_owned(), "archive".to_owned()]);
+    assert!(mirror(vec!["search".to_owned()], &mirrors) == vec!["search".to_owned()]);
+}
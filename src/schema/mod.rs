@@ -0,0 +1,497 @@
+use regex::Regex;
+use rustc_serialize::json::Json;
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File};
+use std::io::Read;
+use std::path;
+
+/// The keywords we know how to validate. A schema using any other validation keyword
+/// is refused at startup, rather than silently accepting bodies it should reject.
+/// Annotations (`title`, `description`, `format`, etc.) are ignored.
+const VALIDATION_KEYWORDS: &'static [&'static str] = &[
+    "type", "enum", "const", "properties", "required", "additionalProperties", "minProperties",
+    "maxProperties", "items", "minItems", "maxItems", "uniqueItems", "minLength", "maxLength",
+    "pattern", "minimum", "maximum", "exclusiveMinimum", "exclusiveMaximum", "allOf", "anyOf",
+    "oneOf", "not", "$ref", "definitions",
+];
+const UNSUPPORTED_KEYWORDS: &'static [&'static str] = &[
+    "patternProperties", "dependencies", "contains", "propertyNames", "if", "then", "else",
+    "multipleOf", "additionalItems",
+];
+
+/// A JSON Schema (the draft 4/6 keywords in `VALIDATION_KEYWORDS`).
+/// Only local references (`#/definitions/...`) are supported.
+#[derive(Clone, Debug)]
+pub struct Schema {
+    root: Json,
+    patterns: HashMap<String, Regex>,
+}
+
+impl Schema {
+    /// Loads a schema from a file.
+    pub fn load(path: &path::Path) -> Result<Schema, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read schema [ {:?} ]: {}", path, e)));
+        let root = try!(Json::from_str(&contents).map_err(|e| format!("Failed to parse schema [ {:?} ]: {}", path, e)));
+        Schema::new(root).map_err(|e| format!("Invalid schema [ {:?} ]: {}", path, e))
+    }
+
+    /// Creates a schema, making sure every keyword in it is supported, and every pattern compiles.
+    pub fn new(root: Json) -> Result<Schema, String> {
+        let mut patterns = HashMap::new();
+        try!(check_schema(&root, &root, &mut patterns));
+        Ok(Schema {
+            root: root,
+            patterns: patterns,
+        })
+    }
+
+    /// Validates an instance against the schema.
+    /// Returns every error found, each prefixed with the JSON Pointer to where it was.
+    pub fn validate(&self, instance: &Json) -> Result<(), Vec<String>> {
+        let mut errors = Vec::new();
+        self.validate_at(&self.root, instance, "", &mut errors);
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn validate_at(&self, schema: &Json, instance: &Json, pointer: &str, errors: &mut Vec<String>) {
+        let schema = match *schema {
+            Json::Boolean(true) => return,
+            Json::Boolean(false) => return errors.push(format!("{}: no value is allowed here", display(pointer))),
+            Json::Object(ref schema) => schema,
+            _ => return,
+        };
+
+        if let Some(reference) = schema.get("$ref").and_then(|reference| reference.as_string()) {
+            match resolve(&self.root, reference) {
+                Some(resolved) => self.validate_at(resolved, instance, pointer, errors),
+                None => errors.push(format!("{}: unresolvable reference [ {} ]", display(pointer), reference)),
+            }
+            return;
+        }
+
+        if let Some(types) = schema.get("type") {
+            let allowed: Vec<&str> = match *types {
+                Json::String(ref single) => vec![&single[..]],
+                Json::Array(ref many) => many.iter().filter_map(|kind| kind.as_string()).collect(),
+                _ => Vec::new(),
+            };
+            if !allowed.iter().any(|kind| is_type(instance, kind)) {
+                errors.push(format!("{}: expected {}, found {}", display(pointer), allowed.join(" or "), type_name(instance)));
+                return;
+            }
+        }
+
+        if let Some(&Json::Array(ref options)) = schema.get("enum") {
+            if !options.iter().any(|option| json_equal(option, instance)) {
+                errors.push(format!("{}: must be one of {}", display(pointer), Json::Array(options.clone())));
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if !json_equal(constant, instance) {
+                errors.push(format!("{}: must be {}", display(pointer), constant));
+            }
+        }
+
+        match *instance {
+            Json::Object(ref object) => self.validate_object(schema, object, pointer, errors),
+            Json::Array(ref array) => self.validate_array(schema, array, pointer, errors),
+            Json::String(ref string) => self.validate_string(schema, string, pointer, errors),
+            Json::I64(_) | Json::U64(_) | Json::F64(_) => {
+                validate_number(schema, instance.as_f64().unwrap(), pointer, errors)
+            },
+            _ => {},
+        }
+
+        if let Some(&Json::Array(ref all)) = schema.get("allOf") {
+            for sub_schema in all.iter() {
+                self.validate_at(sub_schema, instance, pointer, errors);
+            }
+        }
+        if let Some(&Json::Array(ref any)) = schema.get("anyOf") {
+            if !any.iter().any(|sub_schema| self.is_valid(sub_schema, instance)) {
+                errors.push(format!("{}: doesn't match any of the allowed schemas", display(pointer)));
+            }
+        }
+        if let Some(&Json::Array(ref one)) = schema.get("oneOf") {
+            let matches = one.iter().filter(|sub_schema| self.is_valid(sub_schema, instance)).count();
+            if matches != 1 {
+                errors.push(format!("{}: must match exactly one schema, matched {}", display(pointer), matches));
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.is_valid(not, instance) {
+                errors.push(format!("{}: matches a schema it must not", display(pointer)));
+            }
+        }
+    }
+
+    fn is_valid(&self, schema: &Json, instance: &Json) -> bool {
+        let mut errors = Vec::new();
+        self.validate_at(schema, instance, "", &mut errors);
+        errors.is_empty()
+    }
+
+    fn validate_object(&self, schema: &BTreeMap<String, Json>, object: &BTreeMap<String, Json>,
+                       pointer: &str, errors: &mut Vec<String>) {
+        if let Some(&Json::Array(ref required)) = schema.get("required") {
+            for name in required.iter().filter_map(|name| name.as_string()) {
+                if !object.contains_key(name) {
+                    errors.push(format!("{}: missing required property [ {} ]", display(pointer), name));
+                }
+            }
+        }
+        if let Some(minimum) = schema.get("minProperties").and_then(|minimum| minimum.as_u64()) {
+            if (object.len() as u64) < minimum {
+                errors.push(format!("{}: must have at least {} properties", display(pointer), minimum));
+            }
+        }
+        if let Some(maximum) = schema.get("maxProperties").and_then(|maximum| maximum.as_u64()) {
+            if object.len() as u64 > maximum {
+                errors.push(format!("{}: must have at most {} properties", display(pointer), maximum));
+            }
+        }
+
+        let properties = schema.get("properties").and_then(|properties| properties.as_object());
+        for (name, value) in object.iter() {
+            let child = format!("{}/{}", pointer, name.replace("~", "~0").replace("/", "~1"));
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(property_schema) => self.validate_at(property_schema, value, &child, errors),
+                None => match schema.get("additionalProperties") {
+                    Some(&Json::Boolean(false)) => {
+                        errors.push(format!("{}: unexpected property [ {} ]", display(pointer), name))
+                    },
+                    Some(additional) => self.validate_at(additional, value, &child, errors),
+                    None => {},
+                },
+            }
+        }
+    }
+
+    fn validate_array(&self, schema: &BTreeMap<String, Json>, array: &[Json], pointer: &str, errors: &mut Vec<String>) {
+        if let Some(minimum) = schema.get("minItems").and_then(|minimum| minimum.as_u64()) {
+            if (array.len() as u64) < minimum {
+                errors.push(format!("{}: must have at least {} items", display(pointer), minimum));
+            }
+        }
+        if let Some(maximum) = schema.get("maxItems").and_then(|maximum| maximum.as_u64()) {
+            if array.len() as u64 > maximum {
+                errors.push(format!("{}: must have at most {} items", display(pointer), maximum));
+            }
+        }
+        if schema.get("uniqueItems").and_then(|unique| unique.as_boolean()) == Some(true) {
+            let duplicated = array.iter().enumerate()
+                .any(|(index, item)| array[index + 1..].iter().any(|other| json_equal(item, other)));
+            if duplicated {
+                errors.push(format!("{}: items must be unique", display(pointer)));
+            }
+        }
+
+        match schema.get("items") {
+            Some(&Json::Array(ref tuple)) => {
+                for (index, (item_schema, item)) in tuple.iter().zip(array.iter()).enumerate() {
+                    self.validate_at(item_schema, item, &format!("{}/{}", pointer, index), errors);
+                }
+            },
+            Some(item_schema) => {
+                for (index, item) in array.iter().enumerate() {
+                    self.validate_at(item_schema, item, &format!("{}/{}", pointer, index), errors);
+                }
+            },
+            None => {},
+        }
+    }
+
+    fn validate_string(&self, schema: &BTreeMap<String, Json>, string: &str, pointer: &str, errors: &mut Vec<String>) {
+        let length = string.chars().count() as u64;
+        if let Some(minimum) = schema.get("minLength").and_then(|minimum| minimum.as_u64()) {
+            if length < minimum {
+                errors.push(format!("{}: must be at least {} characters", display(pointer), minimum));
+            }
+        }
+        if let Some(maximum) = schema.get("maxLength").and_then(|maximum| maximum.as_u64()) {
+            if length > maximum {
+                errors.push(format!("{}: must be at most {} characters", display(pointer), maximum));
+            }
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(|pattern| pattern.as_string()) {
+            if let Some(regex) = self.patterns.get(pattern) {
+                if !regex.is_match(string) {
+                    errors.push(format!("{}: must match [ {} ]", display(pointer), pattern));
+                }
+            }
+        }
+    }
+}
+
+fn validate_number(schema: &BTreeMap<String, Json>, number: f64, pointer: &str, errors: &mut Vec<String>) {
+    // Draft 4 makes exclusiveMinimum/Maximum booleans modifying minimum/maximum, draft 6 makes them numbers.
+    let exclusive = |name: &str| schema.get(name).and_then(|value| value.as_boolean()).unwrap_or(false);
+
+    if let Some(minimum) = schema.get("minimum").and_then(|minimum| minimum.as_f64()) {
+        if number < minimum || (exclusive("exclusiveMinimum") && number == minimum) {
+            errors.push(format!("{}: must be at least {}", display(pointer), minimum));
+        }
+    }
+    if let Some(maximum) = schema.get("maximum").and_then(|maximum| maximum.as_f64()) {
+        if number > maximum || (exclusive("exclusiveMaximum") && number == maximum) {
+            errors.push(format!("{}: must be at most {}", display(pointer), maximum));
+        }
+    }
+    if let Some(minimum) = schema.get("exclusiveMinimum").and_then(|minimum| minimum.as_f64()) {
+        if number <= minimum {
+            errors.push(format!("{}: must be greater than {}", display(pointer), minimum));
+        }
+    }
+    if let Some(maximum) = schema.get("exclusiveMaximum").and_then(|maximum| maximum.as_f64()) {
+        if number >= maximum {
+            errors.push(format!("{}: must be less than {}", display(pointer), maximum));
+        }
+    }
+}
+
+/// Finds what a local reference refers to in the root schema.
+fn resolve<'a>(root: &'a Json, reference: &str) -> Option<&'a Json> {
+    if !reference.starts_with('#') {
+        return None;
+    }
+    let mut current = root;
+    for part in reference[1..].split('/').filter(|part| !part.is_empty()) {
+        let part = part.replace("~1", "/").replace("~0", "~");
+        current = match *current {
+            Json::Object(ref object) => match object.get(&part) {
+                Some(value) => value,
+                None => return None,
+            },
+            _ => return None,
+        };
+    }
+    Some(current)
+}
+
+/// Follows the references a schema validates the same value with (through `$ref`, `allOf`, `anyOf`,
+/// `oneOf`, and `not`), making sure none of them lead back to one already followed. Those would
+/// recurse forever, where references under `properties` or `items` stop at the end of the body.
+fn check_references(root: &Json, schema: &Json, followed: &mut Vec<String>) -> Result<(), String> {
+    let object = match *schema {
+        Json::Object(ref object) => object,
+        _ => return Ok(()),
+    };
+
+    if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_string()) {
+        if followed.iter().any(|followed| followed == reference) {
+            return Err(format!("the reference [ {} ] refers back to itself", reference));
+        }
+        let resolved = try!(resolve(root, reference).ok_or(format!("unresolvable reference [ {} ]", reference)));
+        followed.push(reference.to_owned());
+        try!(check_references(root, resolved, followed));
+        followed.pop();
+        // Everything else next to a `$ref` is ignored.
+        return Ok(());
+    }
+
+    for keyword in ["allOf", "anyOf", "oneOf"].iter() {
+        if let Some(&Json::Array(ref children)) = object.get(*keyword) {
+            for child in children.iter() {
+                try!(check_references(root, child, followed));
+            }
+        }
+    }
+    if let Some(not) = object.get("not") {
+        try!(check_references(root, not, followed));
+    }
+    Ok(())
+}
+
+/// Walks a schema making sure every keyword is supported, every pattern compiles,
+/// and every reference resolves without a cycle.
+fn check_schema(root: &Json, schema: &Json, patterns: &mut HashMap<String, Regex>) -> Result<(), String> {
+    let object = match *schema {
+        Json::Object(ref object) => object,
+        Json::Boolean(_) => return Ok(()),
+        _ => return Err("a schema must be an object, or a boolean".to_owned()),
+    };
+
+    for keyword in object.keys() {
+        if UNSUPPORTED_KEYWORDS.contains(&&keyword[..]) {
+            return Err(format!("the [ {} ] keyword isn't supported", keyword));
+        }
+    }
+    if let Some(reference) = object.get("$ref").and_then(|reference| reference.as_string()) {
+        if !reference.starts_with('#') {
+            return Err(format!("only local references are supported, found [ {} ]", reference));
+        }
+    }
+    try!(check_references(root, schema, &mut Vec::new()));
+    if let Some(pattern) = object.get("pattern").and_then(|pattern| pattern.as_string()) {
+        let regex = try!(Regex::new(pattern).map_err(|e| format!("invalid pattern [ {} ]: {}", pattern, e)));
+        patterns.insert(pattern.to_owned(), regex);
+    }
+
+    for keyword in VALIDATION_KEYWORDS.iter() {
+        match (*keyword, object.get(*keyword)) {
+            ("properties", Some(&Json::Object(ref children))) | ("definitions", Some(&Json::Object(ref children))) => {
+                for child in children.values() {
+                    try!(check_schema(root, child, patterns));
+                }
+            },
+            ("items", Some(&Json::Array(ref children))) | ("allOf", Some(&Json::Array(ref children))) |
+            ("anyOf", Some(&Json::Array(ref children))) | ("oneOf", Some(&Json::Array(ref children))) => {
+                for child in children.iter() {
+                    try!(check_schema(root, child, patterns));
+                }
+            },
+            ("items", Some(child)) | ("not", Some(child)) | ("additionalProperties", Some(child)) => {
+                try!(check_schema(root, child, patterns));
+            },
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+fn is_type(instance: &Json, kind: &str) -> bool {
+    match (kind, instance) {
+        ("null", &Json::Null) | ("boolean", &Json::Boolean(_)) | ("string", &Json::String(_)) |
+        ("array", &Json::Array(_)) | ("object", &Json::Object(_)) => true,
+        ("number", _) => instance.is_number(),
+        ("integer", &Json::I64(_)) | ("integer", &Json::U64(_)) => true,
+        ("integer", &Json::F64(number)) => number.fract() == 0.0,
+        _ => false,
+    }
+}
+
+fn type_name(instance: &Json) -> &'static str {
+    match *instance {
+        Json::Null => "null",
+        Json::Boolean(_) => "boolean",
+        Json::String(_) => "string",
+        Json::Array(_) => "array",
+        Json::Object(_) => "object",
+        Json::I64(_) | Json::U64(_) => "integer",
+        Json::F64(_) => "number",
+    }
+}
+
+/// Compares JSON values, treating numbers as equal by value (so `1` equals `1.0`).
+fn json_equal(a: &Json, b: &Json) -> bool {
+    if a.is_number() && b.is_number() {
+        return a.as_f64() == b.as_f64();
+    }
+    match (a, b) {
+        (&Json::Array(ref a), &Json::Array(ref b)) => {
+            a.len() == b.len() && a.iter().zip(b.iter()).all(|(a, b)| json_equal(a, b))
+        },
+        (&Json::Object(ref a), &Json::Object(ref b)) => {
+            a.len() == b.len() && a.iter().all(|(key, value)| b.get(key).map_or(false, |other| json_equal(value, other)))
+        },
+        _ => a == b,
+    }
+}
+
+fn display(pointer: &str) -> &str {
+    if pointer.is_empty() { "/" } else { pointer }
+}
+
+/// The schemas for every topic that has one.
+#[derive(Clone, Debug)]
+pub struct SchemaStore {
+    schemas: HashMap<String, Schema>,
+}
+
+impl SchemaStore {
+    /// Loads every `<topic>.json` file in a directory as the schema for `<topic>`.
+    pub fn load(dir: &path::Path) -> Result<SchemaStore, String> {
+        let entries = try!(fs::read_dir(dir).map_err(|e| format!("Failed to read schema directory [ {:?} ]: {}", dir, e)));
+        let mut schemas = HashMap::new();
+        for entry in entries {
+            let path = try!(entry.map_err(|e| format!("Failed to read schema directory [ {:?} ]: {}", dir, e))).path();
+            if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+                continue;
+            }
+            let topic = match path.file_stem().and_then(|stem| stem.to_str()) {
+                Some(topic) => topic.to_owned(),
+                None => continue,
+            };
+            schemas.insert(topic, try!(Schema::load(&path)));
+        }
+        Ok(SchemaStore { schemas: schemas })
+    }
+
+    /// The schema for a topic, if it has one.
+    pub fn get(&self, topic: &str) -> Option<&Schema> {
+        self.schemas.get(topic)
+    }
+}
+
+/// Validates a body against a schema, returning every reason it's invalid.
+pub fn validate_body(schema: &Schema, body: &[u8]) -> Result<(), Vec<String>> {
+    try!(::utils::check_json_depth(body).map_err(|e| vec![e]));
+    let text = try!(::std::str::from_utf8(body).map_err(|_| vec!["The body isn't valid UTF-8.".to_owned()]));
+    let instance = try!(Json::from_str(text).map_err(|e| vec![format!("The body isn't valid JSON: {}", e)]));
+    schema.validate(&instance)
+}
+
+#[cfg(test)]
+fn test_schema() -> Schema {
+    Schema::new(Json::from_str(r##"{
+        "type": "object",
+        "required": ["id", "items"],
+        "additionalProperties": false,
+        "properties": {
+            "id": { "type": "integer", "minimum": 1 },
+            "status": { "enum": ["created", "shipped"] },
+            "items": { "type": "array", "minItems": 1, "items": { "$ref": "#/definitions/item" } }
+        },
+        "definitions": {
+            "item": { "type": "object", "required": ["sku"], "properties": { "sku": { "type": "string", "maxLength": 8 } } }
+        }
+    }"##).unwrap()).unwrap()
+}
+
+#[test]
+fn test_schema_validation() {
+    let schema = test_schema();
+
+    assert!(validate_body(&schema, br#"{"id": 1, "status": "created", "items": [{"sku": "A1"}]}"#).is_ok());
+
+    let errors = validate_body(&schema, br#"{"id": 0, "status": "lost", "items": [{"sku": 7}], "extra": true}"#).unwrap_err();
+    assert!(errors.contains(&"/id: must be at least 1".to_owned()));
+    assert!(errors.contains(&"/: unexpected property [ extra ]".to_owned()));
+    assert!(errors.contains(&"/items/0/sku: expected string, found integer".to_owned()));
+    assert!(errors.len() == 4);
+
+    assert!(validate_body(&schema, br#"{"items": []}"#).unwrap_err().len() == 2);
+    assert!(validate_body(&schema, b"not json").is_err());
+}
+
+#[test]
+fn test_unsupported_schema() {
+    assert!(Schema::new(Json::from_str(r#"{"patternProperties": {}}"#).unwrap()).is_err());
+    assert!(Schema::new(Json::from_str(r#"{"$ref": "http://example.com/schema.json"}"#).unwrap()).is_err());
+    assert!(Schema::new(Json::from_str(r#"[]"#).unwrap()).is_err());
+}
+
+#[test]
+fn test_schema_references() {
+    // References that validate the same value again would never stop.
+    assert!(Schema::new(Json::from_str(r##"{"$ref": "#"}"##).unwrap()).is_err());
+    assert!(Schema::new(Json::from_str(r##"{
+        "properties": { "a": { "$ref": "#/definitions/a" } },
+        "definitions": { "a": { "allOf": [{ "$ref": "#/definitions/b" }] }, "b": { "not": { "$ref": "#/definitions/a" } } }
+    }"##).unwrap()).is_err());
+    assert!(Schema::new(Json::from_str(r##"{"$ref": "#/definitions/missing"}"##).unwrap()).is_err());
+
+    // Recursive schemas are fine, as long as each reference is for a value inside the last.
+    let tree = Schema::new(Json::from_str(r##"{
+        "$ref": "#/definitions/node",
+        "definitions": { "node": { "type": "object", "properties": { "children": { "type": "array", "items": { "$ref": "#/definitions/node" } } } } }
+    }"##).unwrap()).unwrap();
+    assert!(tree.validate(&Json::from_str(r#"{"children": [{"children": []}]}"#).unwrap()).is_ok());
+    assert!(tree.validate(&Json::from_str(r#"{"children": [1]}"#).unwrap()).is_err());
+}
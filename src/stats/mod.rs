@@ -1,9 +1,11 @@
+use iron::prelude::*;
+use iron::status;
 use std::thread;
 use std::sync::mpsc::{Sender};
 use std::sync::{Arc, Mutex, mpsc};
 
 #[cfg(feature = "stats-prometheus")]
-use prometheus::Counter;
+use prometheus::{self, Counter, CounterVec, Encoder, Gauge, TextEncoder};
 
 #[cfg(feature = "stats-statsd")]
 use std::env;
@@ -53,6 +55,71 @@ lazy_static! {
                     "type" => "kafka",}
         )
     ).unwrap();
+
+    static ref HTTP_UNAUTHORIZED_COUNTER: Counter = register_counter!(
+        opts!(
+            "request_http_unauthorized",
+            "Total number of HTTP requests rejected for missing, or invalid credentials.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "http",}
+        )
+    ).unwrap();
+
+    static ref HTTP_FORBIDDEN_COUNTER: Counter = register_counter!(
+        opts!(
+            "request_http_forbidden",
+            "Total number of HTTP requests rejected for writing to a topic they aren't allowed to.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "http",}
+        )
+    ).unwrap();
+
+    static ref HTTP_CLIENT_RATE_LIMITED_COUNTER: Counter = register_counter!(
+        opts!(
+            "request_http_rate_limited_client",
+            "Total number of HTTP requests rejected for going over their client's rate limit.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "http",}
+        )
+    ).unwrap();
+
+    static ref HTTP_TOPIC_RATE_LIMITED_COUNTER: Counter = register_counter!(
+        opts!(
+            "request_http_rate_limited_topic",
+            "Total number of HTTP requests rejected for going over their topic's rate limit.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "http",}
+        )
+    ).unwrap();
+
+    static ref HTTP_VALIDATION_FAILURE_COUNTER: CounterVec = register_counter_vec!(
+        opts!(
+            "request_http_validation_failure",
+            "Total number of HTTP requests rejected for not matching their topic's schema.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "http",}
+        ),
+        &["topic"]
+    ).unwrap();
+
+    static ref HTTP_REDACTION_COUNTER: CounterVec = register_counter_vec!(
+        opts!(
+            "request_http_redactions",
+            "Total number of values redacted from HTTP request bodies.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "http",}
+        ),
+        &["topic", "rule"]
+    ).unwrap();
+
+    static ref CERTIFICATE_EXPIRY_GAUGE: Gauge = register_gauge!(
+        opts!(
+            "kafka_certificate_expiry_seconds",
+            "Seconds until the kafka client certificate expires.",
+            labels!{"service" => "kafka-proxy",
+                    "type" => "kafka",}
+        )
+    ).unwrap();
 }
 
 #[cfg(feature = "stats-statsd")]
@@ -62,25 +129,76 @@ lazy_static! {
             (Ipv4Addr::from_str(&env::var("GRAPHITE_HOST").unwrap()).unwrap(), DEFAULT_PORT)).unwrap();
 }
 
-/// A Stat struct to check. contains two fields:
-/// `is_http_request` - Whether it was an http request (true), or a kafka request (false).
-/// `was_successful` - Whether the http request/kafka request was successful.
+/// A Stat to report. Either:
+/// `Request` - An http request, or kafka request.
+///   `is_http_request` - Whether it was an http request (true), or a kafka request (false).
+///   `was_successful` - Whether the http request/kafka request was successful.
+/// `CertificateExpiry` - The number of seconds until the kafka client certificate expires.
+/// `AuthFailure` - An http request that was rejected by authentication.
+///   `was_authenticated` - Whether the credentials were valid, but not allowed to write to the topic.
+/// `RateLimited` - An http request that was rejected by rate limiting.
+///   `is_topic_limit` - Whether it went over the topic's limit (true), or the client's limit (false).
+/// `ValidationFailure` - An http request whose body didn't match the topic's schema.
+///   `topic` - The topic it was sent to.
+/// `Redaction` - Values a redaction rule redacted from an http request's body.
+///   `topic` - The topic it was sent to.
+///   `rule` - The name of the rule.
+///   `hits` - How many values it redacted.
 #[derive(Debug)]
-pub struct Stat {
-    pub is_http_request: bool,
-    pub was_successful: bool,
+pub enum Stat {
+    Request {
+        is_http_request: bool,
+        was_successful: bool,
+    },
+    CertificateExpiry(i64),
+    AuthFailure {
+        was_authenticated: bool,
+    },
+    RateLimited {
+        is_topic_limit: bool,
+    },
+    ValidationFailure {
+        topic: String,
+    },
+    Redaction {
+        topic: String,
+        rule: String,
+        hits: usize,
+    },
 }
 
 impl Stat {
-    /// A Helper function to create a new "Stat" faster.
+    /// A Helper function to create a new "Request" Stat faster.
     pub fn new(is_http_request: bool, was_successful: bool) -> Stat {
-        Stat {
+        Stat::Request {
             is_http_request: is_http_request,
             was_successful: was_successful
         }
     }
 }
 
+/// Serves the prometheus metrics, for listeners with the "metrics" role.
+#[cfg(feature = "stats-prometheus")]
+pub fn metrics_handler(_: &mut Request) -> IronResult<Response> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    if let Err(e) = encoder.encode(&prometheus::gather(), &mut buffer) {
+        error!("Failed to encode metrics: {:?}", e);
+        return Ok(Response::with(status::InternalServerError));
+    }
+
+    let mut response = Response::with((status::Ok, buffer));
+    response.headers.set_raw("Content-Type", vec![encoder.format_type().as_bytes().to_vec()]);
+    Ok(response)
+}
+
+/// Serves the metrics, for listeners with the "metrics" role.
+/// Only prometheus is scraped, every other reporter pushes its stats.
+#[cfg(not(feature = "stats-prometheus"))]
+pub fn metrics_handler(_: &mut Request) -> IronResult<Response> {
+    Ok(Response::with((status::NotFound, "Metrics are only served with the stats-prometheus feature.")))
+}
+
 /// A Statistic Reporter.
 /// This is a base struct that multiple reporters can implement.
 /// Based on features enabled at build time. The following reporters
@@ -97,7 +215,9 @@ impl Reporter {
     /// Starts the prometheus reporter.
     /// Creates an mpsc channel.
     /// Spawns a thread with an HTTP_SUCCESS_COUNTER, HTTP_FAILURE_COUNTER,
-    /// KAFKA_SUCCESS_COUNTER, and KAFKA_FAILURE_COUNTER.
+    /// KAFKA_SUCCESS_COUNTER, KAFKA_FAILURE_COUNTER, HTTP_UNAUTHORIZED_COUNTER,
+    /// HTTP_FORBIDDEN_COUNTER, HTTP_CLIENT_RATE_LIMITED_COUNTER, HTTP_TOPIC_RATE_LIMITED_COUNTER,
+    /// HTTP_VALIDATION_FAILURE_COUNTER, HTTP_REDACTION_COUNTER, and CERTIFICATE_EXPIRY_GAUGE.
     /// Returns the Sender wrapped in an Arc + Mutex.
     pub fn start_reporting(&self) -> Arc<Mutex<Sender<Stat>>> {
         let (tx, rx) = mpsc::channel::<Stat>();
@@ -106,19 +226,20 @@ impl Reporter {
             loop {
                 let possible_stat = rx.try_recv();
                 if possible_stat.is_ok() {
-                    let stat = possible_stat.unwrap();
-                    if stat.is_http_request {
-                        if stat.was_successful {
-                            HTTP_SUCCESS_COUNTER.inc();
-                        } else {
-                            HTTP_FAILURE_COUNTER.inc();
-                        }
-                    } else {
-                        if stat.was_successful {
-                            KAFKA_SUCCESS_COUNTER.inc();
-                        } else {
-                            KAFKA_FAILURE_COUNTER.inc();
-                        }
+                    match possible_stat.unwrap() {
+                        Stat::Request { is_http_request: true, was_successful: true } => HTTP_SUCCESS_COUNTER.inc(),
+                        Stat::Request { is_http_request: true, was_successful: false } => HTTP_FAILURE_COUNTER.inc(),
+                        Stat::Request { is_http_request: false, was_successful: true } => KAFKA_SUCCESS_COUNTER.inc(),
+                        Stat::Request { is_http_request: false, was_successful: false } => KAFKA_FAILURE_COUNTER.inc(),
+                        Stat::CertificateExpiry(seconds) => CERTIFICATE_EXPIRY_GAUGE.set(seconds as f64),
+                        Stat::AuthFailure { was_authenticated: false } => HTTP_UNAUTHORIZED_COUNTER.inc(),
+                        Stat::AuthFailure { was_authenticated: true } => HTTP_FORBIDDEN_COUNTER.inc(),
+                        Stat::RateLimited { is_topic_limit: false } => HTTP_CLIENT_RATE_LIMITED_COUNTER.inc(),
+                        Stat::RateLimited { is_topic_limit: true } => HTTP_TOPIC_RATE_LIMITED_COUNTER.inc(),
+                        Stat::ValidationFailure { topic } => HTTP_VALIDATION_FAILURE_COUNTER.with_label_values(&[&topic]).inc(),
+                        Stat::Redaction { topic, rule, hits } => {
+                            let _ = HTTP_REDACTION_COUNTER.with_label_values(&[&topic, &rule]).inc_by(hits as f64);
+                        },
                     }
                 }
             }
@@ -141,19 +262,41 @@ impl Reporter {
             loop {
                 let possible_stat = rx.try_recv();
                 if possible_stat.is_ok() {
-                    let stat = possible_stat.unwrap();
-                    if stat.is_http_request {
-                        if stat.was_successful {
+                    match possible_stat.unwrap() {
+                        Stat::Request { is_http_request: true, was_successful: true } => {
                             let _ = GRAPIHTE_CLIENT.incr("http.success");
-                        } else {
+                        },
+                        Stat::Request { is_http_request: true, was_successful: false } => {
                             let _ = GRAPIHTE_CLIENT.incr("http.failure");
-                        }
-                    } else {
-                        if stat.was_successful {
+                        },
+                        Stat::Request { is_http_request: false, was_successful: true } => {
                             let _ = GRAPIHTE_CLIENT.incr("kafka.success");
-                        } else {
+                        },
+                        Stat::Request { is_http_request: false, was_successful: false } => {
                             let _ = GRAPIHTE_CLIENT.incr("kafka.failure");
-                        }
+                        },
+                        Stat::CertificateExpiry(seconds) => {
+                            // StatsD gauges can't be negative, an expired certificate reports as 0.
+                            let _ = GRAPIHTE_CLIENT.gauge("kafka.certificate.expiry_seconds", seconds.max(0) as u64);
+                        },
+                        Stat::AuthFailure { was_authenticated: false } => {
+                            let _ = GRAPIHTE_CLIENT.incr("http.unauthorized");
+                        },
+                        Stat::AuthFailure { was_authenticated: true } => {
+                            let _ = GRAPIHTE_CLIENT.incr("http.forbidden");
+                        },
+                        Stat::RateLimited { is_topic_limit: false } => {
+                            let _ = GRAPIHTE_CLIENT.incr("http.rate_limited.client");
+                        },
+                        Stat::RateLimited { is_topic_limit: true } => {
+                            let _ = GRAPIHTE_CLIENT.incr("http.rate_limited.topic");
+                        },
+                        Stat::ValidationFailure { topic } => {
+                            let _ = GRAPIHTE_CLIENT.incr(&format!("http.validation_failure.{}", topic));
+                        },
+                        Stat::Redaction { topic, rule, hits } => {
+                            let _ = GRAPIHTE_CLIENT.count(&format!("http.redactions.{}.{}", topic, rule), hits as i64);
+                        },
                     }
                 }
             }
@@ -0,0 +1,453 @@
+use ::models::{Configuration, HttpsConfig, SecurityMode, TlsConfig, TlsVersion, VerifyMode};
+use openssl::nid::Nid;
+use openssl::ssl::{SslContext, SslMethod, SslStream, SSL_VERIFY_FAIL_IF_NO_PEER_CERT, SSL_VERIFY_NONE, SSL_VERIFY_PEER};
+use openssl::ssl::{SSL_OP_NO_SSLV2, SSL_OP_NO_SSLV3, SSL_OP_NO_TLSV1, SSL_OP_NO_TLSV1_1};
+use openssl::x509::{X509, X509FileType, X509StoreContext};
+use rustc_serialize::base64::FromBase64;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use ::utils::DEFAULT_CIPHERS;
+
+/// Builds the SslContext used to talk to kafka from the configuration.
+///
+/// * `config` - The parsed configuration.
+///
+/// Returns the SslContext, or a description of the option that couldn't be applied.
+pub fn build_context(config: &Configuration) -> Result<SslContext, String> {
+    let tls = &config.tls;
+    let mut context = try!(SslContext::new(SslMethod::Sslv23)
+        .map_err(|e| format!("Failed to create an SslContext: {}", e)));
+
+    match tls.min_version {
+        TlsVersion::Tlsv1_2 => {
+            context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_TLSV1 | SSL_OP_NO_TLSV1_1);
+        },
+    }
+
+    try!(context.set_cipher_list(&tls.ciphers)
+        .map_err(|e| format!("Invalid cipher list [ {} ]: {}", tls.ciphers, e)));
+
+    if config.security == SecurityMode::MutualTls {
+        if let (&Some(ref cert_path), &Some(ref key_path)) = (&config.cert_path, &config.key_path) {
+            try!(context.set_certificate_file(cert_path, X509FileType::PEM)
+                .map_err(|e| format!("Failed to load certificate [ {:?} ]: {}", cert_path, e)));
+            try!(context.set_private_key_file(key_path, X509FileType::PEM)
+                .map_err(|e| format!("Failed to load key [ {:?} ]: {}", key_path, e)));
+            try!(context.check_private_key()
+                .map_err(|e| format!("The key [ {:?} ] doesn't match the certificate [ {:?} ]: {}", key_path, cert_path, e)));
+        }
+    }
+
+    try!(load_trust_roots(&mut context, tls));
+
+    match tls.verify {
+        VerifyMode::None => {
+            warn!("Kafka broker certificates will not be verified.");
+            context.set_verify(SSL_VERIFY_NONE, None);
+        },
+        VerifyMode::Peer if tls.verify_hostname => {
+            let hostnames = match tls.hostname {
+                Some(ref hostname) => vec![hostname.clone()],
+                None => config.brokers.iter()
+                    .map(|broker| broker.split(':').next().unwrap().to_owned())
+                    .collect(),
+            };
+            context.set_verify_callback(SSL_VERIFY_PEER, move |preverify_ok: bool, x509_ctx: &X509StoreContext| {
+                verify_hostname(preverify_ok, x509_ctx, &hostnames)
+            });
+        },
+        VerifyMode::Peer => {
+            context.set_verify(SSL_VERIFY_PEER, None);
+        }
+    }
+
+    Ok(context)
+}
+
+/// Builds the SslContext used to serve HTTPS.
+/// Always requires at least TLS 1.2, and uses the default cipher list.
+///
+/// * `https` - The HTTPS configuration.
+///
+/// Returns the SslContext, or a description of the option that couldn't be applied.
+pub fn build_server_context(https: &HttpsConfig) -> Result<SslContext, String> {
+    let mut context = try!(SslContext::new(SslMethod::Sslv23)
+        .map_err(|e| format!("Failed to create an SslContext: {}", e)));
+    context.set_options(SSL_OP_NO_SSLV2 | SSL_OP_NO_SSLV3 | SSL_OP_NO_TLSV1 | SSL_OP_NO_TLSV1_1);
+
+    try!(context.set_cipher_list(DEFAULT_CIPHERS)
+        .map_err(|e| format!("Invalid cipher list [ {} ]: {}", DEFAULT_CIPHERS, e)));
+    try!(context.set_certificate_chain_file(&https.cert_path, X509FileType::PEM)
+        .map_err(|e| format!("Failed to load HTTPS certificate [ {:?} ]: {}", https.cert_path, e)));
+    try!(context.set_private_key_file(&https.key_path, X509FileType::PEM)
+        .map_err(|e| format!("Failed to load HTTPS key [ {:?} ]: {}", https.key_path, e)));
+    try!(context.check_private_key()
+        .map_err(|e| format!("The HTTPS key [ {:?} ] doesn't match the certificate [ {:?} ]: {}",
+            https.key_path, https.cert_path, e)));
+
+    if let Some(ref client_ca) = https.client_ca {
+        try!(context.set_CA_file(client_ca)
+            .map_err(|e| format!("Failed to load client CA file [ {:?} ]: {}", client_ca, e)));
+        if https.require_client_cert {
+            context.set_verify(SSL_VERIFY_PEER | SSL_VERIFY_FAIL_IF_NO_PEER_CERT, None);
+        } else {
+            context.set_verify(SSL_VERIFY_PEER, None);
+        }
+    }
+
+    Ok(context)
+}
+
+/// Returns the common name of a certificate's subject, if it has one.
+pub fn subject_common_name(certificate: &X509) -> Option<String> {
+    certificate.subject_name().text_by_nid(Nid::CN).map(|common_name| common_name.to_string())
+}
+
+/// Loads the CAs used to verify the kafka brokers into the context.
+/// If neither a CA file, nor CA dir is configured the system defaults are used.
+fn load_trust_roots(context: &mut SslContext, tls: &TlsConfig) -> Result<(), String> {
+    if tls.ca_file.is_none() && tls.ca_dir.is_none() {
+        return context.set_default_verify_paths()
+            .map_err(|e| format!("Failed to load the default CA paths: {}", e));
+    }
+
+    if let Some(ref ca_file) = tls.ca_file {
+        try!(context.set_CA_file(ca_file)
+            .map_err(|e| format!("Failed to load CA file [ {:?} ]: {}", ca_file, e)));
+    }
+
+    if let Some(ref ca_dir) = tls.ca_dir {
+        let entries = try!(fs::read_dir(ca_dir)
+            .map_err(|e| format!("Failed to read CA dir [ {:?} ]: {}", ca_dir, e)));
+        for entry in entries {
+            let path = try!(entry.map_err(|e| format!("Failed to read CA dir [ {:?} ]: {}", ca_dir, e))).path();
+            let is_certificate = path.extension()
+                .map(|extension| extension == "pem" || extension == "crt")
+                .unwrap_or(false);
+            if is_certificate {
+                try!(context.set_CA_file(&path)
+                    .map_err(|e| format!("Failed to load CA file [ {:?} ]: {}", path, e)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The verification callback used when hostname verification is on.
+/// Only the leaf certificate is checked against the hostnames, the rest of
+/// the chain is left to OpenSSL. Since kafka-rust opens the connections itself
+/// we can't tell which broker we're talking to, so the certificate must be
+/// valid for one of the configured hostnames.
+fn verify_hostname(preverify_ok: bool, x509_ctx: &X509StoreContext, hostnames: &[String]) -> bool {
+    if !preverify_ok || x509_ctx.error_depth() != 0 {
+        return preverify_ok;
+    }
+
+    let certificate = match x509_ctx.get_current_cert() {
+        Some(certificate) => certificate,
+        None => return false,
+    };
+
+    let names = certificate_names(&certificate);
+    let matched = hostnames.iter().any(|hostname| {
+        names.iter().any(|name| hostname_matches(name, hostname))
+    });
+    if !matched {
+        error!("Kafka broker certificate names {:?} don't match any of {:?}.", names, hostnames);
+    }
+    matched
+}
+
+/// Returns the DNS names a certificate is valid for.
+/// The subject alt names if there are any, otherwise the common name.
+fn certificate_names(certificate: &X509) -> Vec<String> {
+    let alt_names: Vec<String> = match certificate.subject_alt_names() {
+        Some(alt_names) => alt_names.iter()
+            .filter_map(|name| name.dns().map(|dns| dns.to_owned()))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    if !alt_names.is_empty() {
+        return alt_names;
+    }
+
+    subject_common_name(certificate).map(|common_name| vec![common_name]).unwrap_or(Vec::new())
+}
+
+/// Checks whether a certificate name matches a hostname.
+/// Supports a single wildcard as the entire left-most label (`*.example.com`),
+/// which matches exactly one label.
+pub fn hostname_matches(name: &str, hostname: &str) -> bool {
+    let name = name.to_lowercase();
+    let hostname = hostname.to_lowercase();
+
+    if name.starts_with("*.") {
+        let mut labels = hostname.splitn(2, '.');
+        let first_label = labels.next().unwrap_or("");
+        match labels.next() {
+            Some(rest) => !first_label.is_empty() && rest == &name[2..],
+            None => false,
+        }
+    } else {
+        name == hostname
+    }
+}
+
+/// How long to wait on a broker when logging the TLS parameters, so one that's unreachable can't hang startup.
+const NEGOTIATE_TIMEOUT_SECS: u64 = 5;
+
+/// Connects to the first address of a broker that answers within `timeout`,
+/// with reads, and writes limited to `timeout` as well.
+fn connect_timeout(broker: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_error = io::Error::new(io::ErrorKind::NotFound, "no addresses found");
+    for addr in try!(broker.to_socket_addrs()) {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                try!(stream.set_read_timeout(Some(timeout)));
+                try!(stream.set_write_timeout(Some(timeout)));
+                return Ok(stream);
+            },
+            Err(e) => last_error = e,
+        }
+    }
+    Err(last_error)
+}
+
+/// Opens a connection to a broker to log the TLS parameters it negotiates.
+/// This is purely informational, failing to connect here isn't fatal since
+/// kafka-rust will try every broker on its own.
+pub fn log_negotiated(context: &SslContext, broker: &str) {
+    let stream = match connect_timeout(broker, Duration::from_secs(NEGOTIATE_TIMEOUT_SECS)) {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Failed to connect to [ {} ] to check TLS parameters: {}", broker, e);
+            return;
+        }
+    };
+
+    match SslStream::connect(context, stream) {
+        Ok(ssl_stream) => {
+            let ssl = ssl_stream.ssl();
+            let cipher = ssl.get_current_cipher();
+            info!("Negotiated [ {} ] with [ {} ] using cipher [ {} ] ({} bits).",
+                ssl.version(), broker,
+                cipher.as_ref().map(|cipher| cipher.name()).unwrap_or("unknown"),
+                cipher.as_ref().map(|cipher| cipher.bits().secret).unwrap_or(0));
+        },
+        Err(e) => {
+            error!("Failed to negotiate TLS with [ {} ]: {}", broker, e);
+        }
+    }
+}
+
+/// Watches the client certificate, and key for changes.
+/// Used to pick up rotated certificates without restarting the proxy.
+pub struct CertWatcher {
+    cert_path: path::PathBuf,
+    key_path: path::PathBuf,
+    last_modified: Option<(SystemTime, SystemTime)>,
+    seen_modified: Option<(SystemTime, SystemTime)>,
+}
+
+impl CertWatcher {
+    /// Creates a new watcher, remembering when the files were last modified.
+    pub fn new(cert_path: path::PathBuf, key_path: path::PathBuf) -> CertWatcher {
+        let mut watcher = CertWatcher {
+            cert_path: cert_path,
+            key_path: key_path,
+            last_modified: None,
+            seen_modified: None,
+        };
+        watcher.last_modified = watcher.modified();
+        watcher
+    }
+
+    /// Returns true if either the certificate or key has been modified since
+    /// they were last loaded. If the files can't be read (for example because
+    /// they're in the middle of being replaced) they're treated as unchanged.
+    /// They keep counting as changed until `mark_loaded`, so failed reloads are retried.
+    pub fn changed(&mut self) -> bool {
+        let modified = self.modified();
+        if modified.is_some() && modified != self.last_modified {
+            self.seen_modified = modified;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Records the files `changed` last saw as loaded. Only call it once they've been swapped in.
+    pub fn mark_loaded(&mut self) {
+        if self.seen_modified.is_some() {
+            self.last_modified = self.seen_modified.take();
+        }
+    }
+
+    /// Returns the number of seconds until the certificate expires.
+    /// Negative if it already has.
+    pub fn seconds_until_expiry(&self) -> Result<i64, String> {
+        let mut pem = String::new();
+        try!(File::open(&self.cert_path).and_then(|mut file| file.read_to_string(&mut pem))
+            .map_err(|e| format!("Failed to read certificate [ {:?} ]: {}", self.cert_path, e)));
+        let not_after = try!(certificate_not_after(&pem));
+        let now = try!(SystemTime::now().duration_since(UNIX_EPOCH)
+            .map_err(|e| format!("The system clock is before the unix epoch: {}", e)));
+        Ok(not_after - now.as_secs() as i64)
+    }
+
+    fn modified(&self) -> Option<(SystemTime, SystemTime)> {
+        let cert_modified = fs::metadata(&self.cert_path).and_then(|metadata| metadata.modified());
+        let key_modified = fs::metadata(&self.key_path).and_then(|metadata| metadata.modified());
+        match (cert_modified, key_modified) {
+            (Ok(cert_modified), Ok(key_modified)) => Some((cert_modified, key_modified)),
+            _ => None,
+        }
+    }
+}
+
+/// Reads the first certificate out of a PEM file, and returns when it
+/// expires as seconds since the unix epoch.
+pub fn certificate_not_after(pem: &str) -> Result<i64, String> {
+    let begin = "-----BEGIN CERTIFICATE-----";
+    let end = "-----END CERTIFICATE-----";
+    let start = try!(pem.find(begin).ok_or("No certificate found in PEM.".to_owned())) + begin.len();
+    let stop = try!(pem[start..].find(end).ok_or("Unterminated certificate in PEM.".to_owned())) + start;
+    let der = try!(pem[start..stop].from_base64().map_err(|e| format!("Invalid certificate PEM: {}", e)));
+
+    let (_, certificate, _) = try!(read_der(&der));
+    let (_, mut tbs_certificate, _) = try!(read_der(certificate));
+    // The version is an optional explicitly tagged field.
+    if tbs_certificate.first() == Some(&0xa0) {
+        tbs_certificate = try!(read_der(tbs_certificate)).2;
+    }
+    // Skip the serial number, signature algorithm, and issuer.
+    for _ in 0..3 {
+        tbs_certificate = try!(read_der(tbs_certificate)).2;
+    }
+    let (_, validity, _) = try!(read_der(tbs_certificate));
+    let (_, _, validity) = try!(read_der(validity));
+    let (tag, not_after, _) = try!(read_der(validity));
+
+    let not_after = try!(String::from_utf8(not_after.to_vec())
+        .map_err(|_| "Invalid certificate expiry time.".to_owned()));
+    match tag {
+        0x17 => parse_asn1_time(&not_after, false),
+        0x18 => parse_asn1_time(&not_after, true),
+        _ => Err(format!("Unknown certificate time type: {}", tag)),
+    }
+}
+
+/// Reads a single DER value. Returns the tag, the contents, and whatever follows it.
+fn read_der(data: &[u8]) -> Result<(u8, &[u8], &[u8]), String> {
+    if data.len() < 2 {
+        return Err("Truncated certificate.".to_owned());
+    }
+    let tag = data[0];
+    let (length, header) = if data[1] < 0x80 {
+        (data[1] as usize, 2)
+    } else {
+        let length_bytes = (data[1] & 0x7f) as usize;
+        if length_bytes == 0 || length_bytes > 4 || data.len() < 2 + length_bytes {
+            return Err("Invalid certificate length.".to_owned());
+        }
+        let length = data[2..2 + length_bytes].iter().fold(0usize, |length, byte| (length << 8) | *byte as usize);
+        (length, 2 + length_bytes)
+    };
+    if data.len() < header + length {
+        return Err("Truncated certificate.".to_owned());
+    }
+    Ok((tag, &data[header..header + length], &data[header + length..]))
+}
+
+/// Parses an ASN.1 UTCTime (`YYMMDDHHMMSSZ`), or GeneralizedTime (`YYYYMMDDHHMMSSZ`)
+/// into seconds since the unix epoch.
+fn parse_asn1_time(time: &str, generalized: bool) -> Result<i64, String> {
+    let invalid = || format!("Invalid certificate time: [ {} ]", time);
+    let year_digits = if generalized { 4 } else { 2 };
+    if !time.ends_with('Z') || time.len() != year_digits + 11 || !time[..time.len() - 1].chars().all(|c| c.is_digit(10)) {
+        return Err(invalid());
+    }
+    let field = |start: usize, length: usize| time[start..start + length].parse::<i64>().unwrap();
+
+    let mut year = field(0, year_digits);
+    if !generalized {
+        year += if year >= 50 { 1900 } else { 2000 };
+    }
+    let month = field(year_digits, 2);
+    let day = field(year_digits + 2, 2);
+    if month < 1 || month > 12 || day < 1 || day > 31 {
+        return Err(invalid());
+    }
+
+    Ok(days_from_civil(year, month, day) * 86400 +
+        field(year_digits + 4, 2) * 3600 + field(year_digits + 6, 2) * 60 + field(year_digits + 8, 2))
+}
+
+/// The number of days between the unix epoch and a date in the proleptic gregorian calendar.
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[test]
+fn test_hostname_matching() {
+    assert!(hostname_matches("kafka.internal", "kafka.internal"));
+    assert!(hostname_matches("Kafka.Internal", "kafka.internal"));
+    assert!(hostname_matches("*.kafka.internal", "broker-1.kafka.internal"));
+    assert!(!hostname_matches("*.kafka.internal", "kafka.internal"));
+    assert!(!hostname_matches("*.kafka.internal", "a.broker-1.kafka.internal"));
+    assert!(!hostname_matches("kafka.internal", "broker-1.kafka.internal"));
+}
+
+#[test]
+fn test_certificate_not_after() {
+    let pem = "-----BEGIN CERTIFICATE-----
+MIIBizCCATGgAwIBAgIUCpmERH4vDaQWO1V1/K0akSi8e40wCgYIKoZIzj0EAwIw
+GzEZMBcGA1UEAwwQa2Fma2EtcHJveHktdGVzdDAeFw0yNjEwMTgyMzA4MTlaFw0z
+NjEwMTUyMzA4MTlaMBsxGTAXBgNVBAMMEGthZmthLXByb3h5LXRlc3QwWTATBgcq
+hkjOPQIBBggqhkjOPQMBBwNCAASoMMKMma30ZEym2ts67i2Wsll+g6BIvn4Ovtm1
+mzHcHfQb5Rf1zbCsxFqQSOixwgd4BI/+1mcl5NuMV29qBIIko1MwUTAdBgNVHQ4E
+FgQU9RMns/4xsV7DRYP/8Z+62XEZ26kwHwYDVR0jBBgwFoAU9RMns/4xsV7DRYP/
+8Z+62XEZ26kwDwYDVR0TAQH/BAUwAwEB/zAKBggqhkjOPQQDAgNIADBFAiB2Tgk2
+zYp8Fd4MsSCuOpkh2z1swP1GLNmlm9fZMas2XwIhAIXqCv6mMz2dJtBsgvmC8FYo
+75MDOJpcoeqOMz5NvgaM
+-----END CERTIFICATE-----
+";
+
+    // notAfter=Oct 15 23:08:19 2036 GMT
+    assert!(certificate_not_after(pem) == Ok(2107724899));
+    assert!(certificate_not_after("not a certificate").is_err());
+    assert!(parse_asn1_time("20500101000000Z", true) == Ok(2524608000));
+    assert!(parse_asn1_time("700101000000Z", false) == Ok(0));
+}
+
+#[test]
+fn test_cert_watcher_retries_failed_reloads() {
+    let dir = ::std::env::temp_dir().join("kafka_proxy_test_cert_watcher");
+    let _ = fs::create_dir_all(&dir);
+    let (cert_path, key_path) = (dir.join("client.crt"), dir.join("client.key"));
+    File::create(&cert_path).unwrap();
+    File::create(&key_path).unwrap();
+
+    let mut watcher = CertWatcher::new(cert_path, key_path);
+    assert!(!watcher.changed());
+
+    // As if the files were replaced after they were loaded.
+    watcher.last_modified = Some((UNIX_EPOCH, UNIX_EPOCH));
+    assert!(watcher.changed());
+    // The reload failed, so they're still changed next time.
+    assert!(watcher.changed());
+    watcher.mark_loaded();
+    assert!(!watcher.changed());
+
+    let _ = fs::remove_dir_all(&dir);
+}
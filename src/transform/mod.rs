@@ -0,0 +1,258 @@
+pub mod path;
+
+use rustc_serialize::json::{self, Json};
+use self::path::{JsonPath, Segment};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path as fs_path;
+
+/// A built-in transform step.
+/// `Drop` - Removes the fields at the paths.
+/// `Rename` - Renames the field at each path, keeping it in the same object.
+/// `Redact` - Replaces the values at the paths with `replacement`.
+/// `Add` - Adds static fields to the top level object, replacing any already there.
+/// `LowercaseKeys` - Lowercases every key, at every level.
+/// `KeyFrom` - Uses the value at the path as the kafka key.
+#[derive(Clone, Debug)]
+pub enum Step {
+    Drop(Vec<JsonPath>),
+    Rename(Vec<(JsonPath, String)>),
+    Redact { paths: Vec<JsonPath>, replacement: Json },
+    Add(BTreeMap<String, Json>),
+    LowercaseKeys,
+    KeyFrom(JsonPath),
+}
+
+/// A body after its topic's transforms, and the kafka key they picked, if any.
+#[derive(Debug, PartialEq)]
+pub struct Transformed {
+    pub body: Vec<u8>,
+    pub key: Option<Vec<u8>>,
+}
+
+/// The steps a topic's bodies go through, in order.
+#[derive(Clone, Debug)]
+pub struct Pipeline {
+    steps: Vec<Step>,
+}
+
+fn parse_paths(value: &Json, step: &str) -> Result<Vec<JsonPath>, String> {
+    let paths = try!(value.as_array().ok_or(format!("`{}` must be a list of JSONPaths.", step)));
+    let mut parsed = Vec::new();
+    for raw in paths.iter() {
+        let raw = try!(raw.as_string().ok_or(format!("`{}` must be a list of JSONPaths.", step)));
+        parsed.push(try!(JsonPath::parse(raw)));
+    }
+    Ok(parsed)
+}
+
+fn parse_step(step: &Json) -> Result<Step, String> {
+    let object = try!(step.as_object().ok_or(format!("Every step must be an object, found: {}", step)));
+    let name = try!(object.keys().find(|key| *key != "replacement")
+        .ok_or(format!("A step must name what it does, found: {}", step)));
+    let value = &object[name];
+
+    let parsed = match &name[..] {
+        "drop" => Step::Drop(try!(parse_paths(value, name))),
+        "rename" => {
+            let fields = try!(value.as_object().ok_or("`rename` must be an object of `JSONPath: new name`.".to_owned()));
+            let mut renames = Vec::new();
+            for (raw, new_name) in fields.iter() {
+                let path = try!(JsonPath::parse(raw));
+                let new_name = try!(new_name.as_string().ok_or(format!("The new name for [ {} ] must be a string.", raw)));
+                renames.push((path, new_name.to_owned()));
+            }
+            Step::Rename(renames)
+        },
+        "redact" => Step::Redact {
+            paths: try!(parse_paths(value, name)),
+            replacement: object.get("replacement").cloned().unwrap_or(Json::String("[REDACTED]".to_owned())),
+        },
+        "add" => Step::Add(try!(value.as_object().cloned().ok_or("`add` must be an object of fields.".to_owned()))),
+        "lowercase_keys" => Step::LowercaseKeys,
+        "key_from" => Step::KeyFrom(try!(JsonPath::parse(try!(value.as_string()
+            .ok_or("`key_from` must be a JSONPath.".to_owned()))))),
+        other => return Err(format!("Unknown transform step: [ {} ].", other)),
+    };
+
+    let fields_allowed = if let Step::Redact { .. } = parsed { 2 } else { 1 };
+    if object.len() > fields_allowed {
+        return Err(format!("A step can only do one thing, found: {}", step));
+    }
+    if let Step::Drop(ref paths) = parsed {
+        if paths.iter().any(|path| path.is_root()) {
+            return Err("`drop` can't drop the whole body.".to_owned());
+        }
+    }
+    if let Step::Rename(ref renames) = parsed {
+        if renames.iter().any(|&(ref path, _)| path.is_root()) {
+            return Err("`rename` can't rename the whole body.".to_owned());
+        }
+    }
+    Ok(parsed)
+}
+
+fn lowercase_keys(value: &mut Json) {
+    match *value {
+        Json::Object(ref mut object) => {
+            let fields = ::std::mem::replace(object, BTreeMap::new());
+            for (key, mut field) in fields {
+                lowercase_keys(&mut field);
+                object.insert(key.to_lowercase(), field);
+            }
+        },
+        Json::Array(ref mut array) => {
+            for item in array.iter_mut() {
+                lowercase_keys(item);
+            }
+        },
+        _ => {},
+    }
+}
+
+impl Pipeline {
+    /// Parses a topic's list of steps.
+    pub fn parse(steps: &Json) -> Result<Pipeline, String> {
+        let raw_steps = try!(steps.as_array().ok_or("Transforms must be a list of steps.".to_owned()));
+        let mut parsed = Vec::new();
+        for step in raw_steps.iter() {
+            parsed.push(try!(parse_step(step)));
+        }
+        Ok(Pipeline { steps: parsed })
+    }
+
+    /// Runs a JSON body through every step.
+    pub fn apply(&self, body: &[u8]) -> Result<Transformed, String> {
+        try!(::utils::check_json_depth(body));
+        let text = try!(::std::str::from_utf8(body).map_err(|_| "The body isn't valid UTF-8.".to_owned()));
+        let mut value = try!(Json::from_str(text).map_err(|e| format!("The body isn't valid JSON: {}", e)));
+        let mut key = None;
+
+        for step in self.steps.iter() {
+            match *step {
+                Step::Drop(ref paths) => {
+                    for path in paths.iter() {
+                        path.remove(&mut value);
+                    }
+                },
+                Step::Rename(ref renames) => {
+                    for &(ref path, ref new_name) in renames.iter() {
+                        path.for_each_parent(&mut value, &mut |parent: &mut Json, last: &Segment| {
+                            if let (&mut Json::Object(ref mut object), &Segment::Key(ref key)) = (parent, last) {
+                                if let Some(field) = object.remove(key) {
+                                    object.insert(new_name.clone(), field);
+                                }
+                            }
+                        });
+                    }
+                },
+                Step::Redact { ref paths, ref replacement } => {
+                    for path in paths.iter() {
+                        path.for_each_mut(&mut value, &mut |field: &mut Json| *field = replacement.clone());
+                    }
+                },
+                Step::Add(ref fields) => {
+                    let object = try!(value.as_object_mut().ok_or("Fields can only be added to an object.".to_owned()));
+                    for (name, field) in fields.iter() {
+                        object.insert(name.clone(), field.clone());
+                    }
+                },
+                Step::LowercaseKeys => lowercase_keys(&mut value),
+                Step::KeyFrom(ref path) => {
+                    key = match path.select(&value).first() {
+                        None | Some(&&Json::Null) => None,
+                        Some(&&Json::String(ref string)) => Some(string.clone().into_bytes()),
+                        Some(other) => Some(json::encode(other).unwrap().into_bytes()),
+                    };
+                },
+            }
+        }
+
+        Ok(Transformed {
+            body: json::encode(&value).unwrap().into_bytes(),
+            key: key,
+        })
+    }
+}
+
+/// The transform pipelines for every topic that has one.
+#[derive(Clone, Debug)]
+pub struct Transforms {
+    topics: BTreeMap<String, Pipeline>,
+}
+
+impl Transforms {
+    /// Loads the transforms from a JSON file of `{"<topic>": [<steps>]}`.
+    pub fn load(path: &fs_path::Path) -> Result<Transforms, String> {
+        let mut contents = String::new();
+        try!(File::open(path).and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|e| format!("Failed to read the transforms [ {:?} ]: {}", path, e)));
+        Transforms::parse(&contents).map_err(|e| format!("Invalid transforms [ {:?} ]: {}", path, e))
+    }
+
+    /// Parses the transforms.
+    pub fn parse(contents: &str) -> Result<Transforms, String> {
+        let parsed = try!(Json::from_str(contents).map_err(|e| e.to_string()));
+        let topics = try!(parsed.as_object().ok_or("Expected an object of topics.".to_owned()));
+
+        let mut pipelines = BTreeMap::new();
+        for (topic, steps) in topics.iter() {
+            let pipeline = try!(Pipeline::parse(steps).map_err(|e| format!("[ {} ]: {}", topic, e)));
+            pipelines.insert(topic.clone(), pipeline);
+        }
+        Ok(Transforms { topics: pipelines })
+    }
+
+    /// The pipeline for a topic, if it has one.
+    pub fn get(&self, topic: &str) -> Option<&Pipeline> {
+        self.topics.get(topic)
+    }
+}
+
+#[test]
+fn test_transform_pipeline() {
+    let transforms = Transforms::parse(r#"{
+        "orders": [
+            {"drop": ["$.debug", "$.items[*].internal_id"]},
+            {"rename": {"$.userId": "user_id"}},
+            {"redact": ["$.card.number"], "replacement": "****"},
+            {"add": {"source": "web"}},
+            {"lowercase_keys": true},
+            {"key_from": "$.Order_Id"}
+        ]
+    }"#).unwrap();
+    let pipeline = transforms.get("orders").unwrap();
+    assert!(transforms.get("audit").is_none());
+
+    let transformed = pipeline.apply(br#"{
+        "Order_Id": 42, "userId": "u1", "debug": true,
+        "card": {"number": "4111111111111111", "Expiry": "01/20"},
+        "items": [{"sku": "A", "internal_id": 1}]
+    }"#).unwrap();
+    let expected = Json::from_str(r#"{
+        "order_id": 42, "user_id": "u1", "source": "web",
+        "card": {"number": "****", "expiry": "01/20"},
+        "items": [{"sku": "A"}]
+    }"#).unwrap();
+    assert!(Json::from_str(&String::from_utf8(transformed.body).unwrap()).unwrap() == expected);
+    // The key was picked after the keys were lowercased, so `Order_Id` is gone.
+    assert!(transformed.key == None);
+
+    let keyed = Pipeline::parse(&Json::from_str(r#"[{"key_from": "customer.id"}]"#).unwrap()).unwrap();
+    assert!(keyed.apply(br#"{"customer": {"id": "c-7"}}"#).unwrap().key == Some(b"c-7".to_vec()));
+    assert!(keyed.apply(br#"{"customer": {"id": 7}}"#).unwrap().key == Some(b"7".to_vec()));
+    assert!(keyed.apply(b"not json").is_err());
+
+    let add = Pipeline::parse(&Json::from_str(r#"[{"add": {"source": "web"}}]"#).unwrap()).unwrap();
+    assert!(add.apply(b"[1, 2]").is_err());
+}
+
+#[test]
+fn test_invalid_transforms() {
+    assert!(Transforms::parse(r#"{"orders": [{"uppercase_keys": true}]}"#).is_err());
+    assert!(Transforms::parse(r#"{"orders": [{"drop": ["$"]}]}"#).is_err());
+    assert!(Transforms::parse(r#"{"orders": [{"drop": ["$.a"], "add": {"b": 1}}]}"#).is_err());
+    assert!(Transforms::parse(r#"{"orders": [{"rename": {"$.a": 1}}]}"#).is_err());
+    assert!(Transforms::parse(r#"{"orders": {"drop": ["$.a"]}}"#).is_err());
+}
@@ -0,0 +1,205 @@
+use rustc_serialize::json::Json;
+use std::fmt;
+
+/// A step of a JSONPath.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Segment {
+    Key(String),
+    Index(usize),
+    Wildcard,
+}
+
+/// A JSONPath, limited to the parts that pick out fields: `$`, `.name`, `['name']`,
+/// `[0]`, and `*` / `[*]` for every member of an object, or array.
+#[derive(Clone, PartialEq)]
+pub struct JsonPath {
+    raw: String,
+    segments: Vec<Segment>,
+}
+
+impl JsonPath {
+    /// Parses a JSONPath. The leading `$` is optional, so `user.email` works as well as `$.user.email`.
+    pub fn parse(raw: &str) -> Result<JsonPath, String> {
+        let invalid = |reason: &str| format!("Invalid JSONPath [ {} ]: {}", raw, reason);
+        let chars: Vec<char> = raw.trim().chars().collect();
+        let mut segments = Vec::new();
+        let mut position = if chars.first() == Some(&'$') { 1 } else { 0 };
+        // Without the `$` the path starts with a name, as if there was a `$.` before it.
+        let mut expect_name = position == 0;
+
+        while position < chars.len() || expect_name {
+            if expect_name {
+                let start = position;
+                while position < chars.len() && chars[position] != '.' && chars[position] != '[' {
+                    position += 1;
+                }
+                let name: String = chars[start..position].iter().cloned().collect();
+                if name.is_empty() {
+                    return Err(invalid("expected a field name"));
+                }
+                segments.push(if name == "*" { Segment::Wildcard } else { Segment::Key(name) });
+                expect_name = false;
+                continue;
+            }
+
+            match chars[position] {
+                '.' => {
+                    position += 1;
+                    expect_name = true;
+                },
+                '[' => {
+                    let end = try!(chars[position..].iter().position(|&c| c == ']')
+                        .map(|offset| position + offset).ok_or(invalid("unclosed [")));
+                    let inner: String = chars[position + 1..end].iter().cloned().collect();
+                    let inner = inner.trim();
+                    let quoted = inner.len() >= 2 && ((inner.starts_with('\'') && inner.ends_with('\'')) ||
+                        (inner.starts_with('"') && inner.ends_with('"')));
+                    segments.push(if inner == "*" {
+                        Segment::Wildcard
+                    } else if quoted {
+                        Segment::Key(inner[1..inner.len() - 1].to_owned())
+                    } else {
+                        Segment::Index(try!(inner.parse::<usize>().map_err(|_| invalid("expected an index, `*`, or a quoted name"))))
+                    });
+                    position = end + 1;
+                },
+                _ => return Err(invalid("expected `.`, or `[`")),
+            }
+        }
+
+        Ok(JsonPath {
+            raw: raw.trim().to_owned(),
+            segments: segments,
+        })
+    }
+
+    /// Whether the path is just `$`, the whole document.
+    pub fn is_root(&self) -> bool {
+        self.segments.is_empty()
+    }
+
+    /// Every value the path matches.
+    pub fn select<'a>(&self, root: &'a Json) -> Vec<&'a Json> {
+        let mut current = vec![root];
+        for segment in self.segments.iter() {
+            let mut next = Vec::new();
+            for value in current {
+                match (segment, value) {
+                    (&Segment::Key(ref key), &Json::Object(ref object)) => next.extend(object.get(key)),
+                    (&Segment::Index(index), &Json::Array(ref array)) => next.extend(array.get(index)),
+                    (&Segment::Wildcard, &Json::Object(ref object)) => next.extend(object.values()),
+                    (&Segment::Wildcard, &Json::Array(ref array)) => next.extend(array.iter()),
+                    _ => {},
+                }
+            }
+            current = next;
+        }
+        current
+    }
+
+    /// Calls `f` with the parent of every value the path matches, and the last segment of the path,
+    /// so the value can be changed, removed, or renamed. Does nothing for the root path.
+    pub fn for_each_parent<F>(&self, root: &mut Json, f: &mut F) where F: FnMut(&mut Json, &Segment) {
+        if let Some((last, parents)) = self.segments.split_last() {
+            visit(root, parents, &mut |parent: &mut Json| f(parent, last));
+        }
+    }
+
+    /// Removes every value the path matches, returning how many were removed.
+    /// Does nothing for the root path.
+    pub fn remove(&self, root: &mut Json) -> usize {
+        let mut removed = 0;
+        self.for_each_parent(root, &mut |parent: &mut Json, last: &Segment| {
+            match (parent, last) {
+                (&mut Json::Object(ref mut object), &Segment::Key(ref key)) => {
+                    removed += object.remove(key).map(|_| 1).unwrap_or(0);
+                },
+                (&mut Json::Object(ref mut object), &Segment::Wildcard) => {
+                    removed += object.len();
+                    object.clear();
+                },
+                (&mut Json::Array(ref mut array), &Segment::Index(index)) if index < array.len() => {
+                    array.remove(index);
+                    removed += 1;
+                },
+                (&mut Json::Array(ref mut array), &Segment::Wildcard) => {
+                    removed += array.len();
+                    array.clear();
+                },
+                _ => {},
+            }
+        });
+        removed
+    }
+
+    /// Calls `f` with every value the path matches.
+    pub fn for_each_mut<F>(&self, root: &mut Json, f: &mut F) where F: FnMut(&mut Json) {
+        visit(root, &self.segments, f);
+    }
+}
+
+fn visit<F>(value: &mut Json, segments: &[Segment], f: &mut F) where F: FnMut(&mut Json) {
+    let (segment, rest) = match segments.split_first() {
+        Some(split) => split,
+        None => return f(value),
+    };
+    match (segment, value) {
+        (&Segment::Key(ref key), &mut Json::Object(ref mut object)) => {
+            if let Some(child) = object.get_mut(key) {
+                visit(child, rest, f);
+            }
+        },
+        (&Segment::Index(index), &mut Json::Array(ref mut array)) => {
+            if let Some(child) = array.get_mut(index) {
+                visit(child, rest, f);
+            }
+        },
+        (&Segment::Wildcard, &mut Json::Object(ref mut object)) => {
+            for child in object.values_mut() {
+                visit(child, rest, f);
+            }
+        },
+        (&Segment::Wildcard, &mut Json::Array(ref mut array)) => {
+            for child in array.iter_mut() {
+                visit(child, rest, f);
+            }
+        },
+        _ => {},
+    }
+}
+
+impl fmt::Debug for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.raw)
+    }
+}
+
+#[test]
+fn test_json_path() {
+    assert!(JsonPath::parse("$.user.email").unwrap().segments ==
+        vec![Segment::Key("user".to_owned()), Segment::Key("email".to_owned())]);
+    assert!(JsonPath::parse("user.email").unwrap() == JsonPath::parse("user.email").unwrap());
+    assert!(JsonPath::parse("$.items[*]['card number'][0]").unwrap().segments ==
+        vec![Segment::Key("items".to_owned()), Segment::Wildcard, Segment::Key("card number".to_owned()), Segment::Index(0)]);
+    assert!(JsonPath::parse("$").unwrap().is_root());
+    assert!(JsonPath::parse("$.").is_err());
+    assert!(JsonPath::parse("$.items[").is_err());
+    assert!(JsonPath::parse("$.items[first]").is_err());
+
+    let document = Json::from_str(r#"{"items": [{"sku": "A"}, {"sku": "B"}, {"name": "C"}]}"#).unwrap();
+    let skus: Vec<&str> = JsonPath::parse("$.items[*].sku").unwrap().select(&document).iter()
+        .filter_map(|value| value.as_string()).collect();
+    assert!(skus == vec!["A", "B"]);
+    assert!(JsonPath::parse("$.items[5]").unwrap().select(&document).is_empty());
+
+    let mut document = document;
+    assert!(JsonPath::parse("$.items[*].sku").unwrap().remove(&mut document) == 2);
+    assert!(JsonPath::parse("$.items[0]").unwrap().remove(&mut document) == 1);
+    assert!(document == Json::from_str(r#"{"items": [{}, {"name": "C"}]}"#).unwrap());
+}
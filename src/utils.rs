@@ -1,10 +1,20 @@
 use clap::{App, Arg, ArgMatches};
-use jfs::Store;
+use kafka::error::{Error as KafkaError, KafkaCode};
 use kafka::producer::{Producer, Record};
-use ::models::{Configuration, MessagePayload};
-use std::{env, path};
+use ::backup::{BackupStore, Replayed};
+use ::deadletter::DeadLetters;
+use ::models::{BackupBackend, BackupConfig, Configuration, DeadLetterConfig, FsyncPolicy, HttpsConfig, JwtConfig,
+    ListenerConfig, ListenerRole, MessagePayload, PayloadLimits, ProtobufConfig, SchemaRegistryConfig, SecurityMode,
+    TlsConfig, TlsVersion, VerifyMode};
+use std::{env, io, path};
 use std::collections::BTreeMap;
+use std::io::Read;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// The default OpenSSL cipher list for talking to kafka.
+/// Only forward secret AEAD ciphers.
+pub const DEFAULT_CIPHERS: &'static str = "ECDHE+AESGCM:ECDHE+CHACHA20:DHE+AESGCM:!aNULL:!eNULL:!MD5";
 
 /// Initializze the Clap Application.
 /// Basically a sole entry point to the CLI Option Parsing.
@@ -20,10 +30,319 @@ pub fn initialize_app() -> App<'static, 'static> {
                 .help("The full path to the key to use for kafka.").takes_value(true))
         .arg(Arg::with_name("port").short("p")
                 .help("The port to listen on for HTTP Posts.").takes_value(true))
+        .arg(Arg::with_name("listen").short("l").long("listen")
+                .help("A comma seperated list of role=IP:PORT, or role=unix:PATH listeners, where role is any of 'ingest', 'admin', 'metrics' joined by '+'.")
+                .takes_value(true))
+        .arg(Arg::with_name("unix_socket_mode").long("unix-socket-mode")
+                .help("The octal permissions to give unix socket listeners. Defaults to 660.").takes_value(true))
         .arg(Arg::with_name("panic_on_backup").short("P").long("panic")
                 .help("Whether or not to panic on backup."))
         .arg(Arg::with_name("dry_run").short("d").long("dryrun")
                 .help("Enabled 'dry run' aka only logging to STDOUT."))
+        .arg(Arg::with_name("cert_reload_interval").long("cert-reload-interval")
+                .help("How often (in seconds) to check the certificate, and key for changes. 0 disables reloading.").takes_value(true))
+        .arg(Arg::with_name("security").long("security")
+                .help("How to connect to kafka. Either 'plaintext', 'tls', or 'mtls' (the default).").takes_value(true))
+        .arg(Arg::with_name("keystore_path").long("keystore")
+                .help("The full path to the API Keystore. When set every POST requires an API Key.").takes_value(true))
+        .arg(Arg::with_name("rate_limits_path").long("rate-limits")
+                .help("The full path to the rate limits file. When set POSTs are rate limited by client, and topic.").takes_value(true))
+        .arg(Arg::with_name("max_payload_size").long("max-payload-size")
+                .help("The largest payload (in bytes) to accept. Defaults to kafka's default of 1000012.").takes_value(true))
+        .arg(Arg::with_name("topic_max_payload_sizes").long("topic-max-payload-sizes")
+                .help("A comma seperated list of `topic=bytes` overriding the largest payload for a topic.").takes_value(true))
+        .arg(Arg::with_name("compressed_passthrough").long("compressed-passthrough")
+                .help("Send compressed bodies to kafka as they are, rather than decompressing them."))
+        .arg(Arg::with_name("schema_dir").long("schema-dir")
+                .help("The full path to a directory of `<topic>.json` JSON Schemas to validate bodies with.").takes_value(true))
+        .arg(Arg::with_name("schema_registry_url").long("schema-registry")
+                .help("The URL of the Schema Registry to encode Avro topics with.").takes_value(true))
+        .arg(Arg::with_name("avro_topics").long("avro-topics")
+                .help("A comma seperated list of topics to encode as Avro. A `*` matches anything.").takes_value(true))
+        .arg(Arg::with_name("avro_schema_dir").long("avro-schema-dir")
+                .help("The full path to a directory of `<topic>.avsc` schemas to register with the Schema Registry.").takes_value(true))
+        .arg(Arg::with_name("schema_registry_cache_seconds").long("schema-registry-cache")
+                .help("How long (in seconds) to cache schemas from the Schema Registry. Defaults to 300.").takes_value(true))
+        .arg(Arg::with_name("protobuf_descriptor_set").long("protobuf-descriptor-set")
+                .help("The full path to a compiled FileDescriptorSet with the message types of protobuf topics.").takes_value(true))
+        .arg(Arg::with_name("protobuf_topics").long("protobuf-topics")
+                .help("A comma seperated list of `topic=package.Message` to convert from JSON to protobuf.").takes_value(true))
+        .arg(Arg::with_name("envelope_topics").long("envelope-topics")
+                .help("A comma seperated list of topics to wrap in an envelope with the message metadata. A `*` matches anything.").takes_value(true))
+        .arg(Arg::with_name("backup_store").long("backup-store")
+                .help("How to back up messages kafka failed on. Either 'jfs' (a file per message, the default), or 'wal'.").takes_value(true))
+        .arg(Arg::with_name("backup_dir").long("backup-dir")
+                .help("The directory to back up messages in. Defaults to 'kafka_rust'.").takes_value(true))
+        .arg(Arg::with_name("backup_fsync").long("backup-fsync")
+                .help("When the 'wal' backup store syncs to disk. Either 'always' (the default), 'never', or a number of milliseconds.").takes_value(true))
+        .arg(Arg::with_name("backup_segment_size").long("backup-segment-size")
+                .help("How large (in bytes) the 'wal' backup store's segments get. Defaults to 67108864 (64MiB).").takes_value(true))
+        .arg(Arg::with_name("dead_letter_topic").long("dead-letter-topic")
+                .help("The topic to send backups to once kafka has failed on them too many times.").takes_value(true))
+        .arg(Arg::with_name("quarantine_dir").long("quarantine-dir")
+                .help("The directory to move backups to once kafka has failed on them too many times.").takes_value(true))
+        .arg(Arg::with_name("max_send_attempts").long("max-send-attempts")
+                .help("How many times kafka may fail on a message before it's dead-lettered. Defaults to 5.").takes_value(true))
+        .arg(Arg::with_name("mirror_topics").long("mirror-topics")
+                .help("A comma seperated list of `topic=mirror` pairs. Messages sent to the topic are also sent to the mirror.").takes_value(true))
+        .arg(Arg::with_name("transforms_path").long("transforms")
+                .help("The full path to the transforms file, listing the steps to reshape each topic's bodies with.").takes_value(true))
+        .arg(Arg::with_name("redaction_rules_path").long("redaction-rules")
+                .help("The full path to the redaction rules file. Matching values are masked, hashed, or dropped before sending.").takes_value(true))
+        .arg(Arg::with_name("routes_path").long("routes")
+                .help("The full path to the routing table, picking topics from the body, or headers. Enables `POST /route`.").takes_value(true))
+        .arg(Arg::with_name("jwks_path").long("jwks")
+                .help("The full path to a JWKS file. When set every POST may use a JWT signed by one of its keys.").takes_value(true))
+        .arg(Arg::with_name("jwt_audience").long("jwt-audience")
+                .help("The audience JWTs must be issued for. Required with --jwks.").takes_value(true))
+        .arg(Arg::with_name("jwt_issuer").long("jwt-issuer")
+                .help("The issuer JWTs must come from.").takes_value(true))
+        .arg(Arg::with_name("jwt_topic_claim").long("jwt-topic-claim")
+                .help("The JWT claim listing the topics it may write to. Defaults to 'kafka_topics'.").takes_value(true))
+        .arg(Arg::with_name("jwt_leeway").long("jwt-leeway")
+                .help("How many seconds of clock skew to allow when checking JWT expiry. Defaults to 30.").takes_value(true))
+        .arg(Arg::with_name("https_cert_path").long("https-certificate")
+                .help("The full path to the certificate to serve HTTPS with. Serves HTTP if not set.").takes_value(true))
+        .arg(Arg::with_name("https_key_path").long("https-keypath")
+                .help("The full path to the key to serve HTTPS with.").takes_value(true))
+        .arg(Arg::with_name("https_client_ca").long("https-client-ca")
+                .help("The full path to a CA bundle to verify HTTP callers client certificates with.").takes_value(true))
+        .arg(Arg::with_name("https_require_client_cert").long("https-require-client-cert")
+                .help("Whether or not HTTP callers must present a client certificate."))
+        .arg(Arg::with_name("tls_min_version").long("tls-min-version")
+                .help("The minimum TLS version to use with kafka. Only '1.2' is supported.").takes_value(true))
+        .arg(Arg::with_name("tls_ca_file").long("ca-file")
+                .help("The full path to a CA bundle to verify the kafka brokers with.").takes_value(true))
+        .arg(Arg::with_name("tls_ca_dir").long("ca-dir")
+                .help("The full path to a directory of CA certificates to verify the kafka brokers with.").takes_value(true))
+        .arg(Arg::with_name("tls_verify").long("tls-verify")
+                .help("How to verify the kafka brokers certificate. Either 'peer', or 'none'.").takes_value(true))
+        .arg(Arg::with_name("tls_verify_hostname").long("tls-verify-hostname")
+                .help("Whether or not to verify the kafka brokers certificate matches their hostname."))
+        .arg(Arg::with_name("tls_hostname").long("tls-hostname")
+                .help("The hostname to expect on the kafka brokers certificate, rather than the broker addresses.").takes_value(true))
+        .arg(Arg::with_name("tls_ciphers").long("ciphers")
+                .help("The OpenSSL cipher list to use with kafka.").takes_value(true))
+}
+
+/// The default largest payload, the same as kafka's default `message.max.bytes`.
+pub const DEFAULT_MAX_PAYLOAD_SIZE: usize = 1000012;
+
+/// Reads the value of an option passed on the command line,
+/// falling back to the environment variable `env_var` if it wasn't.
+fn get_value(matches: &ArgMatches, name: &str, env_var: &str) -> Option<String> {
+    matches.value_of(name).map(|value| value.to_owned()).or(env::var(env_var).ok())
+}
+
+/// Parses the TLS Options from the command line, and env vars.
+/// Unlike the other options these all have secure defaults, so
+/// a missing option is never an error. An invalid one is.
+pub fn get_tls_args(matches: &ArgMatches) -> Result<TlsConfig, String> {
+    let min_version = match get_value(matches, "tls_min_version", "KAFKA_TLS_MIN_VERSION") {
+        Some(ref version) if version == "1.2" => TlsVersion::Tlsv1_2,
+        Some(ref version) if version == "1.3" => {
+            return Err("A minimum TLS version of 1.3 isn't supported by the OpenSSL bindings kafka-rust uses.".to_owned());
+        },
+        Some(version) => return Err(format!("Unknown minimum TLS version: [ {} ], expected '1.2'.", version)),
+        None => TlsVersion::Tlsv1_2,
+    };
+
+    let verify = match get_value(matches, "tls_verify", "KAFKA_TLS_VERIFY") {
+        Some(ref mode) if mode == "peer" => VerifyMode::Peer,
+        Some(ref mode) if mode == "none" => VerifyMode::None,
+        Some(mode) => return Err(format!("Unknown TLS verification mode: [ {} ], expected 'peer', or 'none'.", mode)),
+        None => VerifyMode::Peer,
+    };
+
+    let verify_hostname = matches.occurrences_of("tls_verify_hostname") > 0 ||
+        env::var("KAFKA_TLS_VERIFY_HOSTNAME").is_ok();
+    if verify_hostname && verify == VerifyMode::None {
+        return Err("Hostname verification requires the TLS verification mode to be 'peer'.".to_owned());
+    }
+
+    Ok(TlsConfig {
+        min_version: min_version,
+        ca_file: get_value(matches, "tls_ca_file", "KAFKA_TLS_CA_FILE").map(|path| path.into()),
+        ca_dir: get_value(matches, "tls_ca_dir", "KAFKA_TLS_CA_DIR").map(|path| path.into()),
+        verify: verify,
+        verify_hostname: verify_hostname,
+        hostname: get_value(matches, "tls_hostname", "KAFKA_TLS_HOSTNAME"),
+        ciphers: get_value(matches, "tls_ciphers", "KAFKA_TLS_CIPHERS").unwrap_or(DEFAULT_CIPHERS.to_owned()),
+    })
+}
+
+/// Parses the JWT Options from the command line, and env vars.
+/// JWTs are only accepted when a JWKS is configured, in which case
+/// an audience is also required.
+pub fn get_jwt_args(matches: &ArgMatches) -> Result<Option<JwtConfig>, String> {
+    let jwks_path = match get_value(matches, "jwks_path", "JWT_JWKS_PATH") {
+        Some(jwks_path) => jwks_path,
+        None => return Ok(None),
+    };
+
+    let audience = try!(get_value(matches, "jwt_audience", "JWT_AUDIENCE")
+        .ok_or("JWT_AUDIENCE is required when JWT_JWKS_PATH is set.".to_owned()));
+
+    let leeway = match get_value(matches, "jwt_leeway", "JWT_LEEWAY") {
+        Some(leeway) => try!(leeway.parse::<i64>()
+            .map_err(|_| format!("Invalid JWT leeway: [ {} ], expected a number of seconds.", leeway))),
+        None => 30,
+    };
+
+    Ok(Some(JwtConfig {
+        jwks_path: jwks_path.into(),
+        audience: audience,
+        issuer: get_value(matches, "jwt_issuer", "JWT_ISSUER"),
+        topic_claim: get_value(matches, "jwt_topic_claim", "JWT_TOPIC_CLAIM").unwrap_or("kafka_topics".to_owned()),
+        leeway: leeway,
+    }))
+}
+
+/// Parses the Schema Registry Options from the command line, and env vars.
+/// Avro encoding is only enabled when there are topics to encode.
+pub fn get_schema_registry_args(matches: &ArgMatches) -> Result<Option<SchemaRegistryConfig>, String> {
+    let avro_topics: Vec<String> = match get_value(matches, "avro_topics", "PROXY_AVRO_TOPICS") {
+        Some(topics) => topics.split(',').map(|topic| topic.trim().to_owned()).filter(|topic| !topic.is_empty()).collect(),
+        None => return Ok(None),
+    };
+
+    let url = try!(get_value(matches, "schema_registry_url", "PROXY_SCHEMA_REGISTRY_URL")
+        .ok_or("PROXY_SCHEMA_REGISTRY_URL is required when PROXY_AVRO_TOPICS is set.".to_owned()));
+
+    let cache_seconds = match get_value(matches, "schema_registry_cache_seconds", "PROXY_SCHEMA_REGISTRY_CACHE_SECONDS") {
+        Some(seconds) => try!(seconds.parse::<u64>()
+            .map_err(|_| format!("Invalid Schema Registry cache time: [ {} ], expected a number of seconds.", seconds))),
+        None => 300,
+    };
+
+    Ok(Some(SchemaRegistryConfig {
+        url: url,
+        avro_topics: avro_topics,
+        schema_dir: get_value(matches, "avro_schema_dir", "PROXY_AVRO_SCHEMA_DIR").map(|path| path.into()),
+        cache_seconds: cache_seconds,
+    }))
+}
+
+/// Parses the Backup Options from the command line, and env vars.
+pub fn get_backup_args(matches: &ArgMatches) -> Result<BackupConfig, String> {
+    let dir = get_value(matches, "backup_dir", "PROXY_BACKUP_DIR").unwrap_or("kafka_rust".to_owned());
+    let fsync = get_value(matches, "backup_fsync", "PROXY_BACKUP_FSYNC");
+    let segment_size = get_value(matches, "backup_segment_size", "PROXY_BACKUP_SEGMENT_SIZE");
+
+    let backend = match get_value(matches, "backup_store", "PROXY_BACKUP_STORE").as_ref().map(|store| store.trim()) {
+        None | Some("jfs") => {
+            if fsync.is_some() || segment_size.is_some() {
+                return Err("PROXY_BACKUP_FSYNC, and PROXY_BACKUP_SEGMENT_SIZE only apply to the 'wal' backup store.".to_owned());
+            }
+            BackupBackend::Jfs
+        },
+        Some("wal") => {
+            let fsync = match fsync.as_ref().map(|fsync| fsync.trim()) {
+                None | Some("always") => FsyncPolicy::Always,
+                Some("never") => FsyncPolicy::Never,
+                Some(interval) => FsyncPolicy::Interval(Duration::from_millis(try!(interval.parse::<u64>()
+                    .map_err(|_| format!("Invalid backup fsync: [ {} ], expected 'always', 'never', or milliseconds.", interval))))),
+            };
+            let segment_size = match segment_size {
+                Some(size) => match size.trim().parse::<u64>() {
+                    Ok(size) if size > 0 => size,
+                    _ => return Err(format!("Invalid backup segment size: [ {} ], expected a number of bytes.", size)),
+                },
+                None => 64 * 1024 * 1024,
+            };
+            BackupBackend::Wal { segment_size: segment_size, fsync: fsync }
+        },
+        Some(other) => return Err(format!("Unknown backup store: [ {} ], expected 'jfs', or 'wal'.", other)),
+    };
+
+    Ok(BackupConfig {
+        backend: backend,
+        dir: dir.into(),
+    })
+}
+
+/// Parses the Dead-Letter Options from the command line, and env vars.
+/// Backups are only dead-lettered when there's a topic, or quarantine directory for them,
+/// otherwise they're retried forever.
+pub fn get_dead_letter_args(matches: &ArgMatches) -> Result<Option<DeadLetterConfig>, String> {
+    let topic = get_value(matches, "dead_letter_topic", "PROXY_DEAD_LETTER_TOPIC");
+    let quarantine_dir = get_value(matches, "quarantine_dir", "PROXY_QUARANTINE_DIR");
+    let max_attempts = get_value(matches, "max_send_attempts", "PROXY_MAX_SEND_ATTEMPTS");
+
+    if topic.is_none() && quarantine_dir.is_none() {
+        if max_attempts.is_some() {
+            return Err("PROXY_MAX_SEND_ATTEMPTS requires PROXY_DEAD_LETTER_TOPIC, or PROXY_QUARANTINE_DIR.".to_owned());
+        }
+        return Ok(None);
+    }
+
+    // Records too large for kafka can't go to a topic either, so need somewhere else to go.
+    if topic.is_some() && quarantine_dir.is_none() {
+        return Err("PROXY_DEAD_LETTER_TOPIC requires PROXY_QUARANTINE_DIR, for messages too large for kafka.".to_owned());
+    }
+
+    let max_attempts = match max_attempts {
+        Some(attempts) => match attempts.parse::<u32>() {
+            Ok(attempts) if attempts > 0 => attempts,
+            _ => return Err(format!("Invalid max send attempts: [ {} ], expected a number above 0.", attempts)),
+        },
+        None => 5,
+    };
+
+    Ok(Some(DeadLetterConfig {
+        max_attempts: max_attempts,
+        topic: topic,
+        quarantine_dir: quarantine_dir.map(|dir| dir.into()),
+    }))
+}
+
+/// Parses the Protobuf Options from the command line, and env vars.
+/// Protobuf conversion is only enabled when there are topics to convert,
+/// in which case a descriptor set is also required.
+pub fn get_protobuf_args(matches: &ArgMatches) -> Result<Option<ProtobufConfig>, String> {
+    let topics = match get_value(matches, "protobuf_topics", "PROXY_PROTOBUF_TOPICS") {
+        Some(topics) => try!(split_protobuf_topics(&topics)),
+        None => return Ok(None),
+    };
+
+    let descriptor_set = try!(get_value(matches, "protobuf_descriptor_set", "PROXY_PROTOBUF_DESCRIPTOR_SET")
+        .ok_or("PROXY_PROTOBUF_DESCRIPTOR_SET is required when PROXY_PROTOBUF_TOPICS is set.".to_owned()));
+
+    Ok(Some(ProtobufConfig {
+        descriptor_set: descriptor_set.into(),
+        topics: topics,
+    }))
+}
+
+/// Parses the HTTPS Options from the command line, and env vars.
+/// HTTPS is only served when both a certificate, and key are configured.
+pub fn get_https_args(matches: &ArgMatches) -> Result<Option<HttpsConfig>, String> {
+    let cert_path = get_value(matches, "https_cert_path", "PROXY_HTTPS_CERT_PATH");
+    let key_path = get_value(matches, "https_key_path", "PROXY_HTTPS_KEY_PATH");
+    let client_ca = get_value(matches, "https_client_ca", "PROXY_HTTPS_CLIENT_CA");
+    let require_client_cert = matches.occurrences_of("https_require_client_cert") > 0 ||
+        env::var("PROXY_HTTPS_REQUIRE_CLIENT_CERT").is_ok();
+
+    match (cert_path, key_path) {
+        (Some(cert_path), Some(key_path)) => {
+            if require_client_cert && client_ca.is_none() {
+                return Err("PROXY_HTTPS_CLIENT_CA is required to require client certificates.".to_owned());
+            }
+            Ok(Some(HttpsConfig {
+                cert_path: cert_path.into(),
+                key_path: key_path.into(),
+                client_ca: client_ca.map(|path| path.into()),
+                require_client_cert: require_client_cert,
+            }))
+        },
+        (None, None) => {
+            if client_ca.is_some() || require_client_cert {
+                return Err("Client certificates require HTTPS, set PROXY_HTTPS_CERT_PATH, and PROXY_HTTPS_KEY_PATH.".to_owned());
+            }
+            Ok(None)
+        },
+        _ => Err("Both PROXY_HTTPS_CERT_PATH, and PROXY_HTTPS_KEY_PATH are required to serve HTTPS.".to_owned()),
+    }
 }
 
 /// Parses the arguments from the command line, and env
@@ -32,34 +351,48 @@ pub fn initialize_app() -> App<'static, 'static> {
 /// If a required option isn't passed through a command line option,
 /// then it is assumed that it is provided through environment variable.
 /// If it isn't passed through an environment variable then the program panics
-/// because it can't unwrap a value.
-pub fn get_args(matches: ArgMatches) -> Configuration {
-    let cert_path: path::PathBuf;
-    let key_path: path::PathBuf;
+/// because it can't unwrap a value. Options that can be invalid, rather than
+/// simply missing, are returned as an error.
+pub fn get_args(matches: ArgMatches) -> Result<Configuration, String> {
     let brokers: Vec<String>;
     let non_split_brokers: String;
-    let port: u64;
     let mut panic_on_backup: bool = false;
     let mut dry_run: bool = false;
 
+    debug!("Parsing Security Mode...");
+
+    let security = match get_value(&matches, "security", "KAFKA_SECURITY") {
+        Some(ref mode) if mode == "plaintext" => SecurityMode::Plaintext,
+        Some(ref mode) if mode == "tls" => SecurityMode::Tls,
+        Some(ref mode) if mode == "mtls" => SecurityMode::MutualTls,
+        Some(mode) => return Err(format!("Unknown security mode: [ {} ], expected 'plaintext', 'tls', or 'mtls'.", mode)),
+        None if env::var("NO_SSL").is_ok() => SecurityMode::Plaintext,
+        None => SecurityMode::MutualTls,
+    };
+
     debug!("Parsing Certificate Path...");
 
-    if matches.value_of("cert_path").is_some() {
-        cert_path = matches.value_of("cert_path").unwrap().into();
-    } else {
-        let env_var: String = env::var("KAFKA_PROXY_CERT_PATH").unwrap();
-        cert_path = env_var.into();
-    }
+    let cert_path: Option<path::PathBuf> = get_value(&matches, "cert_path", "KAFKA_PROXY_CERT_PATH")
+        .map(|path| path.into());
 
     debug!("Parsing Key Path...");
 
-    if matches.value_of("key_path").is_some() {
-        key_path = matches.value_of("key_path").unwrap().into();
-    } else {
-        let env_var: String = env::var("KAFKA_PROXY_KEY_PATH").unwrap();
-        key_path = env_var.into();
+    let key_path: Option<path::PathBuf> = get_value(&matches, "key_path", "KAFKA_PROXY_KEY_PATH")
+        .map(|path| path.into());
+
+    if security == SecurityMode::MutualTls && (cert_path.is_none() || key_path.is_none()) {
+        return Err("A certificate and key (KAFKA_PROXY_CERT_PATH, and KAFKA_PROXY_KEY_PATH) are required for \
+            'mtls'. Use the 'tls' security mode to connect without a client certificate.".to_owned());
     }
 
+    debug!("Parsing Certificate Reload Interval...");
+
+    let cert_reload_interval = match get_value(&matches, "cert_reload_interval", "KAFKA_CERT_RELOAD_INTERVAL") {
+        Some(interval) => try!(interval.parse::<u64>()
+            .map_err(|_| format!("Invalid certificate reload interval: [ {} ], expected a number of seconds.", interval))),
+        None => 60,
+    };
+
     debug!("Parsing Brokers...");
 
     if matches.value_of("brokers").is_some() {
@@ -72,11 +405,28 @@ pub fn get_args(matches: ArgMatches) -> Configuration {
 
     debug!("Parsing Port...");
 
-    if matches.value_of("port").is_some() {
-        port = matches.value_of("port").unwrap().parse::<_>().unwrap();
-    } else {
-        port = env::var("PROXY_PORT").unwrap().parse::<_>().unwrap();
-    }
+    let port = match get_value(&matches, "port", "PROXY_PORT") {
+        Some(port) => Some(try!(port.parse::<u64>().map_err(|_| format!("Invalid port: [ {} ].", port)))),
+        None => None,
+    };
+
+    debug!("Parsing Listeners...");
+
+    let listeners = match (get_value(&matches, "listen", "PROXY_LISTEN"), port) {
+        (Some(listen), _) => try!(split_listeners(&listen)),
+        // Metrics are opt-in, so they're never public by default.
+        (None, Some(port)) => vec![ListenerConfig {
+            addr: format!("0.0.0.0:{}", port),
+            roles: vec![ListenerRole::Ingest, ListenerRole::Admin],
+        }],
+        (None, None) => return Err("Either PROXY_PORT, or PROXY_LISTEN is required.".to_owned()),
+    };
+
+    let unix_socket_mode = match get_value(&matches, "unix_socket_mode", "PROXY_UNIX_SOCKET_MODE") {
+        Some(mode) => try!(u32::from_str_radix(&mode, 8)
+            .map_err(|_| format!("Invalid unix socket mode: [ {} ], expected octal permissions like 660.", mode))),
+        None => 0o660,
+    };
 
     debug!("Parsing Panic On Backup Flag");
 
@@ -93,16 +443,132 @@ pub fn get_args(matches: ArgMatches) -> Configuration {
         dry_run = true;
     }
 
+    debug!("Parsing TLS Options");
+
+    let tls = try!(get_tls_args(&matches));
+
+    debug!("Parsing Keystore Path");
+
+    let keystore_path = get_value(&matches, "keystore_path", "PROXY_KEYSTORE_PATH").map(|path| path.into());
+
+    debug!("Parsing Rate Limits Path");
+
+    let rate_limits_path = get_value(&matches, "rate_limits_path", "PROXY_RATE_LIMITS_PATH").map(|path| path.into());
+
+    debug!("Parsing Payload Limits");
+
+    let max_payload_size = match get_value(&matches, "max_payload_size", "PROXY_MAX_PAYLOAD_SIZE") {
+        Some(size) => try!(size.parse::<usize>()
+            .map_err(|_| format!("Invalid max payload size: [ {} ], expected a number of bytes.", size))),
+        None => DEFAULT_MAX_PAYLOAD_SIZE,
+    };
+    let topic_max_payload_sizes = match get_value(&matches, "topic_max_payload_sizes", "PROXY_TOPIC_MAX_PAYLOAD_SIZES") {
+        Some(sizes) => try!(split_topic_sizes(&sizes)),
+        None => BTreeMap::new(),
+    };
+
+    debug!("Parsing Compressed Passthrough Flag");
+
+    let compressed_passthrough = matches.occurrences_of("compressed_passthrough") > 0 ||
+        env::var("PROXY_COMPRESSED_PASSTHROUGH").is_ok();
+
+    debug!("Parsing Schema Directory");
+
+    let schema_dir = get_value(&matches, "schema_dir", "PROXY_SCHEMA_DIR").map(|path| path.into());
+
+    debug!("Parsing Schema Registry Options");
+
+    let schema_registry = try!(get_schema_registry_args(&matches));
+
+    debug!("Parsing Protobuf Options");
+
+    let protobuf = try!(get_protobuf_args(&matches));
+
+    // Avro's output isn't JSON, so a topic encoded as both would reject every message.
+    if let (Some(schema_registry), Some(protobuf)) = (schema_registry.as_ref(), protobuf.as_ref()) {
+        for topic in protobuf.topics.keys() {
+            if let Some(pattern) = schema_registry.avro_topics.iter().find(|pattern| ::auth::topic_matches(pattern, topic)) {
+                return Err(format!("[ {} ] is in both PROXY_PROTOBUF_TOPICS, and PROXY_AVRO_TOPICS (as [ {} ]), but can only be encoded one way.",
+                                   topic, pattern));
+            }
+        }
+    }
+
+    debug!("Parsing Envelope Topics");
+
+    let envelope_topics = get_value(&matches, "envelope_topics", "PROXY_ENVELOPE_TOPICS")
+        .map(|topics| topics.split(',').map(|topic| topic.trim().to_owned()).filter(|topic| !topic.is_empty()).collect())
+        .unwrap_or(Vec::new());
+
+    debug!("Parsing Backup Options");
+
+    let backup = try!(get_backup_args(&matches));
+
+    debug!("Parsing Dead-Letter Options");
+
+    let dead_letter = try!(get_dead_letter_args(&matches));
+
+    debug!("Parsing Mirror Topics");
+
+    let mirror_topics = match get_value(&matches, "mirror_topics", "PROXY_MIRROR_TOPICS") {
+        Some(mirrors) => try!(split_mirror_topics(&mirrors)),
+        None => Vec::new(),
+    };
+
+    debug!("Parsing Transforms Path");
+
+    let transforms_path = get_value(&matches, "transforms_path", "PROXY_TRANSFORMS_PATH").map(|path| path.into());
+
+    debug!("Parsing Redaction Rules Path");
+
+    let redaction_rules_path = get_value(&matches, "redaction_rules_path", "PROXY_REDACTION_RULES_PATH").map(|path| path.into());
+
+    debug!("Parsing Routes Path");
+
+    let routes_path = get_value(&matches, "routes_path", "PROXY_ROUTES_PATH").map(|path| path.into());
+
+    debug!("Parsing JWT Options");
+
+    let jwt = try!(get_jwt_args(&matches));
+
+    debug!("Parsing HTTPS Options");
+
+    let https = try!(get_https_args(&matches));
+
     debug!("Parsed Config");
 
-    Configuration {
+    Ok(Configuration {
+        security: security,
         cert_path: cert_path,
         key_path: key_path,
+        cert_reload_interval: cert_reload_interval,
         brokers: brokers,
         port: port,
+        listeners: listeners,
+        unix_socket_mode: unix_socket_mode,
         panic_on_backup: panic_on_backup,
-        dry_run: dry_run
-    }
+        dry_run: dry_run,
+        tls: tls,
+        keystore_path: keystore_path,
+        jwt: jwt,
+        https: https,
+        rate_limits_path: rate_limits_path,
+        payload_limits: PayloadLimits {
+            max_size: max_payload_size,
+            topics: topic_max_payload_sizes,
+        },
+        compressed_passthrough: compressed_passthrough,
+        schema_dir: schema_dir,
+        schema_registry: schema_registry,
+        protobuf: protobuf,
+        envelope_topics: envelope_topics,
+        mirror_topics: mirror_topics,
+        dead_letter: dead_letter,
+        backup: backup,
+        transforms_path: transforms_path,
+        redaction_rules_path: redaction_rules_path,
+        routes_path: routes_path,
+    })
 }
 
 /// Takes in a String of comma seperated brokers,
@@ -138,34 +604,241 @@ pub fn split_brokers(to_split: String) -> Vec<String> {
         .collect()
 }
 
-/// A function to resend all failed messages in JFS "DB".
-/// This will only be called in a non-dry run state, and only when
-/// the program is first booting.
-pub fn resend_failed_messages(db: &Store, producer: Option<Arc<Mutex<Producer>>>) {
-    let failed_to_sends = db.get_all::<MessagePayload>();
-
-    if failed_to_sends.is_err() {
-        error!("Failed to get all failed to sends. Continuing.")
-    }else {
-        let failed_to_sends = failed_to_sends.unwrap();
-        let producer = producer.unwrap();
-        for (id, message_payload) in failed_to_sends.iter() {
-            let cloned = message_payload.clone();
-
-            let attempt_to_send = producer.lock().unwrap().send(&Record {
-                topic: &cloned.topic,
-                partition: -1,
-                key: (),
-                value: cloned.payload,
+/// Takes in a String of comma seperated `topic=bytes` pairs,
+/// and returns the largest payload for each topic.
+pub fn split_topic_sizes(to_split: &str) -> Result<BTreeMap<String, usize>, String> {
+    let mut sizes = BTreeMap::new();
+    for pair in to_split.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let mut splitter = pair.splitn(2, '=');
+        let topic = splitter.next().unwrap().trim();
+        let size = splitter.next().and_then(|size| size.trim().parse::<usize>().ok());
+        match size {
+            Some(size) if !topic.is_empty() => { sizes.insert(topic.to_owned(), size); },
+            _ => return Err(format!("Invalid topic max payload size: [ {} ], expected `topic=bytes`.", pair)),
+        }
+    }
+    Ok(sizes)
+}
+
+/// Takes in the topics a message was posted to, e.g. `orders,audit`,
+/// and returns each of them once.
+pub fn split_topics(to_split: &str) -> Vec<String> {
+    let mut topics: Vec<String> = Vec::new();
+    for topic in to_split.split(',').map(|topic| topic.trim()).filter(|topic| !topic.is_empty()) {
+        if !topics.iter().any(|existing| existing == topic) {
+            topics.push(topic.to_owned());
+        }
+    }
+    topics
+}
+
+/// Takes in a String of comma seperated `topic=mirror` pairs, and returns them in order.
+/// A topic may be mirrored to more than one topic by repeating it.
+pub fn split_mirror_topics(to_split: &str) -> Result<Vec<(String, String)>, String> {
+    let mut mirrors = Vec::new();
+    for pair in to_split.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let mut splitter = pair.splitn(2, '=');
+        let topic = splitter.next().unwrap().trim();
+        match splitter.next().map(|mirror| mirror.trim()) {
+            Some(mirror) if !topic.is_empty() && !mirror.is_empty() && !mirror.contains('*') => {
+                mirrors.push((topic.to_owned(), mirror.to_owned()));
+            },
+            _ => return Err(format!("Invalid mirror topic: [ {} ], expected `topic=mirror`.", pair)),
+        }
+    }
+    Ok(mirrors)
+}
+
+/// Takes in a String of comma seperated `topic=package.Message` pairs,
+/// and returns the message type for each topic.
+pub fn split_protobuf_topics(to_split: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut topics = BTreeMap::new();
+    for pair in to_split.split(',').filter(|pair| !pair.trim().is_empty()) {
+        let mut splitter = pair.splitn(2, '=');
+        let topic = splitter.next().unwrap().trim();
+        match splitter.next().map(|message| message.trim()) {
+            Some(message) if !topic.is_empty() && !message.is_empty() => {
+                topics.insert(topic.to_owned(), message.to_owned());
+            },
+            _ => return Err(format!("Invalid protobuf topic: [ {} ], expected `topic=package.Message`.", pair)),
+        }
+    }
+    Ok(topics)
+}
+
+/// Takes in a String of comma seperated listeners in the form `role=IP:PORT`,
+/// and returns the listener configurations. A listener may have more than one
+/// role by joining them with `+`, e.g. `ingest+admin=127.0.0.1:8080`.
+/// IPv6 addresses are written in brackets: `metrics=[::1]:9090`, and
+/// Unix Sockets are written as a path: `ingest=unix:/var/run/kafka-proxy.sock`.
+pub fn split_listeners(to_split: &str) -> Result<Vec<ListenerConfig>, String> {
+    let mut listeners = Vec::new();
+    for raw_listener in to_split.split(',').filter(|listener| !listener.trim().is_empty()) {
+        let mut splitter = raw_listener.trim().splitn(2, '=');
+        let raw_roles = splitter.next().unwrap();
+        let addr = match splitter.next() {
+            Some(addr) if !addr.is_empty() => addr,
+            _ => return Err(format!("Invalid listener: [ {} ], expected role=IP:PORT.", raw_listener)),
+        };
+
+        let mut roles = Vec::new();
+        for role in raw_roles.split('+') {
+            roles.push(match role {
+                "ingest" => ListenerRole::Ingest,
+                "admin" => ListenerRole::Admin,
+                "metrics" => ListenerRole::Metrics,
+                _ => return Err(format!("Unknown listener role: [ {} ], expected 'ingest', 'admin', or 'metrics'.", role)),
             });
+        }
+
+        listeners.push(ListenerConfig {
+            addr: addr.to_owned(),
+            roles: roles,
+        });
+    }
+
+    if listeners.is_empty() {
+        return Err("At least one listener is required.".to_owned());
+    }
+    Ok(listeners)
+}
+
+/// Reads a request body, as long as it's no larger than `limit` bytes.
+/// Returns `None` if the body is too large, without reading past the limit.
+pub fn read_limited<R: Read>(body: &mut R, limit: usize) -> io::Result<Option<Vec<u8>>> {
+    let mut buffer = Vec::new();
+    try!(body.take(limit as u64 + 1).read_to_end(&mut buffer));
+    if buffer.len() > limit {
+        Ok(None)
+    } else {
+        Ok(Some(buffer))
+    }
+}
+
+/// How deeply a JSON body may nest arrays and objects.
+pub const MAX_JSON_DEPTH: usize = 128;
 
-            if attempt_to_send.is_err() {
-                error!("Failed to resend backup message: [ {:?} ]", message_payload.clone());
-            } else {
-                let _ = db.delete(&id);
+/// Checks a body doesn't nest arrays and objects deeper than `MAX_JSON_DEPTH`, without parsing it.
+/// The JSON parser, schema validation, and JSONPath all recurse once per level, so deeper bodies would overflow the stack.
+pub fn check_json_depth(body: &[u8]) -> Result<(), String> {
+    let mut depth = 0usize;
+    let mut in_string = false;
+    let mut escaped = false;
+    for &byte in body {
+        if in_string {
+            if escaped {
+                escaped = false;
+            } else if byte == b'\\' {
+                escaped = true;
+            } else if byte == b'"' {
+                in_string = false;
             }
+            continue;
+        }
+        match byte {
+            b'"' => in_string = true,
+            b'[' | b'{' => {
+                depth += 1;
+                if depth > MAX_JSON_DEPTH {
+                    return Err(format!("The body is nested deeper than {} levels.", MAX_JSON_DEPTH));
+                }
+            },
+            b']' | b'}' => depth = depth.saturating_sub(1),
+            _ => (),
         }
-        info!("All done sending backup messages!");
+    }
+    Ok(())
+}
+
+/// Whether a kafka error will happen again no matter how many times the message is resent.
+/// Messages that fail like this aren't worth backing up.
+pub fn is_permanent_failure(error: &KafkaError) -> bool {
+    match *error {
+        KafkaError::Kafka(KafkaCode::MessageSizeTooLarge) => true,
+        _ => false,
+    }
+}
+
+/// What becomes of a message kafka failed on.
+/// `DeadLettered` - It was sent to the dead-letter topic, or quarantined.
+/// `Dropped` - Kafka will never accept it, and it couldn't be dead-lettered, so it isn't worth keeping.
+/// `Retry` - It should be backed up, and resent later.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FailureOutcome {
+    DeadLettered,
+    Dropped,
+    Retry,
+}
+
+/// Counts a failed send against a message, and dead-letters it if it's run out of attempts,
+/// or kafka will never accept it. Shared by the producer, and the resend on boot, so they
+/// always agree on what's kept. Messages kafka will never accept are dropped if they can't be
+/// dead-lettered, as keeping them would only mean failing on them every boot.
+/// Returns the message with the failure counted, and what became of it.
+pub fn handle_send_failure(message: MessagePayload, error: &KafkaError, producer: Option<&Arc<Mutex<Producer>>>,
+                           dead_letters: Option<&DeadLetters>) -> (MessagePayload, FailureOutcome) {
+    let permanent = is_permanent_failure(error);
+    let failed = message.failed(format!("{:?}", error));
+    let outcome = match dead_letters {
+        Some(dead_letters) if permanent || dead_letters.is_exhausted(&failed) => {
+            match dead_letters.dead_letter(producer, &failed, permanent) {
+                Ok(()) => FailureOutcome::DeadLettered,
+                Err(e) if permanent => {
+                    error!("Failed to dead-letter a message kafka will never accept, so it's been DROPPED: [ {:?} ]: {}", failed, e);
+                    FailureOutcome::Dropped
+                },
+                Err(e) => {
+                    error!("Failed to dead-letter: [ {:?} ]: {}", failed, e);
+                    FailureOutcome::Retry
+                },
+            }
+        },
+        None if permanent => FailureOutcome::Dropped,
+        _ => FailureOutcome::Retry,
+    };
+    (failed, outcome)
+}
+
+/// A function to resend all failed messages in the backup store.
+/// This will only be called in a non-dry run state, and only when
+/// the program is first booting. Backups kafka keeps failing on are dead-lettered once they run out of
+/// attempts, or straight away when kafka will never accept them, when `dead_letters` is set.
+pub fn resend_failed_messages(db: &mut BackupStore, producer: Option<Arc<Mutex<Producer>>>, dead_letters: Option<&DeadLetters>) {
+    let producer = producer.unwrap();
+    let replayed = db.replay(&mut |message_payload: MessagePayload| {
+        let cloned = message_payload.clone();
+
+        // kafka sends an empty key as no key at all.
+        let attempt_to_send = producer.lock().unwrap().send(&Record {
+            topic: &cloned.topic,
+            partition: -1,
+            key: cloned.key.unwrap_or(Vec::new()),
+            value: cloned.payload,
+        });
+
+        if let Err(send_error) = attempt_to_send {
+            match handle_send_failure(message_payload, &send_error, Some(&producer), dead_letters) {
+                (failed, FailureOutcome::DeadLettered) => {
+                    error!("Dead-lettered backup message after {} attempts: [ {:?} ]", failed.attempts, failed);
+                    Replayed::Remove
+                },
+                (failed, FailureOutcome::Dropped) => {
+                    error!("Dropping backup message kafka will never accept: [ {:?} ]: {:?}", failed, send_error);
+                    Replayed::Remove
+                },
+                (failed, FailureOutcome::Retry) => {
+                    error!("Failed to resend backup message: [ {:?} ]", failed);
+                    Replayed::Keep(failed)
+                },
+            }
+        } else {
+            Replayed::Remove
+        }
+    });
+
+    match replayed {
+        Ok(()) => info!("All done sending backup messages!"),
+        Err(e) => error!("Failed to resend the backups: {}. Continuing.", e),
     }
 }
 
@@ -175,6 +848,7 @@ pub fn get_mem_templates() -> BTreeMap<String, String> {
     <form id="ActionForm">
         <input id="KafkaTopic" placeholder="The Topic" type="text" />
         <input id="KafkaMessage" placeholder="Your Message" type="text" />
+        <input id="ApiKey" placeholder="API Key (Optional)" type="password" />
         <button onclick="doSubmit()">Submit</button>
     </form>
     <script>
@@ -182,7 +856,11 @@ pub fn get_mem_templates() -> BTreeMap<String, String> {
             var xhr = new XMLHttpRequest();
             var topic = document.getElementById("KafkaTopic").value;
             var message = document.getElementById("KafkaMessage").value;
+            var apiKey = document.getElementById("ApiKey").value;
             xhr.open('POST', '/kafka/' + encodeURIComponent(topic), true);
+            if (apiKey) {
+                xhr.setRequestHeader('Authorization', 'Bearer ' + apiKey);
+            }
             xhr.send(message);
         }
     </script>
@@ -201,15 +879,18 @@ fn test_configuration_parsing() {
         "-p3000"
     ]);
 
-    let config = get_args(matches);
+    let config = get_args(matches).unwrap();
 
     let test_cert_path: path::PathBuf = "/opt/place".to_string().into();
     let test_key_path: path::PathBuf = "/opt/place2".to_string().into();
 
     assert!(config.brokers == vec!["10.0.0.1:9092".to_string(), "10.0.0.2:9093".to_string()]);
-    assert!(config.cert_path == test_cert_path);
-    assert!(config.key_path == test_key_path);
-    assert!(config.port == 3000);
+    assert!(config.security == SecurityMode::MutualTls);
+    assert!(config.cert_path == Some(test_cert_path));
+    assert!(config.key_path == Some(test_key_path));
+    assert!(config.port == Some(3000));
+    assert!(config.listeners[0].addr == "0.0.0.0:3000".to_string());
+    assert!(config.listeners[0].roles == vec![ListenerRole::Ingest, ListenerRole::Admin]);
     assert!(config.panic_on_backup == false);
     assert!(config.dry_run == false);
 }
@@ -226,15 +907,17 @@ fn test_flag_parsing() {
         "-d"
     ]);
 
-    let config = get_args(matches);
+    let config = get_args(matches).unwrap();
 
     let test_cert_path: path::PathBuf = "/opt/place".to_string().into();
     let test_key_path: path::PathBuf = "/opt/place2".to_string().into();
 
     assert!(config.brokers == vec!["10.0.0.1:9092".to_string(), "10.0.0.2:9093".to_string()]);
-    assert!(config.cert_path == test_cert_path);
-    assert!(config.key_path == test_key_path);
-    assert!(config.port == 3000);
+    assert!(config.security == SecurityMode::MutualTls);
+    assert!(config.cert_path == Some(test_cert_path));
+    assert!(config.key_path == Some(test_key_path));
+    assert!(config.port == Some(3000));
+    assert!(config.listeners[0].addr == "0.0.0.0:3000".to_string());
     assert!(config.panic_on_backup == true);
     assert!(config.dry_run == true);
 }
@@ -253,3 +936,370 @@ fn test_invalid_broker_split() {
     let bad_brokers = "10.0.0.1,10.0.0.2".to_string();
     split_brokers(bad_brokers);
 }
+
+#[test]
+fn test_tls_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--tls-min-version=1.2",
+        "--ca-file=/opt/ca.pem",
+        "--tls-verify-hostname",
+        "--tls-hostname=kafka.internal",
+        "--ciphers=HIGH"
+    ]);
+
+    let config = get_args(matches).unwrap();
+
+    let test_ca_file: path::PathBuf = "/opt/ca.pem".to_string().into();
+
+    assert!(config.tls.min_version == TlsVersion::Tlsv1_2);
+    assert!(config.tls.ca_file == Some(test_ca_file));
+    assert!(config.tls.ca_dir == None);
+    assert!(config.tls.verify == VerifyMode::Peer);
+    assert!(config.tls.verify_hostname == true);
+    assert!(config.tls.hostname == Some("kafka.internal".to_string()));
+    assert!(config.tls.ciphers == "HIGH".to_string());
+}
+
+#[test]
+fn test_invalid_tls_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--tls-verify=none",
+        "--tls-verify-hostname"
+    ]);
+
+    assert!(get_args(matches).is_err());
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--tls-min-version=1.3"
+    ]);
+
+    assert!(get_args(matches).unwrap_err().contains("isn't supported by the OpenSSL bindings"));
+}
+
+#[test]
+fn test_security_mode_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-p3000",
+        "--security=tls"
+    ]);
+
+    let config = get_args(matches).unwrap();
+
+    assert!(config.security == SecurityMode::Tls);
+    assert!(config.cert_path == None);
+    assert!(config.key_path == None);
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-p3000",
+        "--security=mtls"
+    ]);
+
+    assert!(get_args(matches).is_err());
+}
+
+#[test]
+fn test_jwt_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--jwks=/opt/jwks.json",
+        "--jwt-audience=kafka-proxy"
+    ]);
+
+    let jwt = get_args(matches).unwrap().jwt.unwrap();
+
+    let test_jwks_path: path::PathBuf = "/opt/jwks.json".to_string().into();
+
+    assert!(jwt.jwks_path == test_jwks_path);
+    assert!(jwt.audience == "kafka-proxy".to_string());
+    assert!(jwt.issuer == None);
+    assert!(jwt.topic_claim == "kafka_topics".to_string());
+    assert!(jwt.leeway == 30);
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--jwks=/opt/jwks.json"
+    ]);
+
+    assert!(get_args(matches).is_err());
+}
+
+#[test]
+fn test_https_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--https-certificate=/opt/https.pem",
+        "--https-keypath=/opt/https.key",
+        "--https-client-ca=/opt/clients.pem",
+        "--https-require-client-cert"
+    ]);
+
+    let https = get_args(matches).unwrap().https.unwrap();
+
+    let test_client_ca: path::PathBuf = "/opt/clients.pem".to_string().into();
+
+    assert!(https.client_ca == Some(test_client_ca));
+    assert!(https.require_client_cert == true);
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--https-certificate=/opt/https.pem"
+    ]);
+
+    assert!(get_args(matches).is_err());
+}
+
+#[test]
+fn test_listener_split() {
+    let listeners = split_listeners("ingest=0.0.0.0:8080,admin+metrics=[::1]:9090,ingest=unix:/tmp/proxy.sock").unwrap();
+
+    assert!(listeners.len() == 3);
+    assert!(listeners[0].addr == "0.0.0.0:8080".to_string());
+    assert!(listeners[0].roles == vec![ListenerRole::Ingest]);
+    assert!(listeners[1].addr == "[::1]:9090".to_string());
+    assert!(listeners[1].roles == vec![ListenerRole::Admin, ListenerRole::Metrics]);
+    assert!(listeners[2].addr == "unix:/tmp/proxy.sock".to_string());
+
+    assert!(split_listeners("public=0.0.0.0:8080").is_err());
+    assert!(split_listeners("0.0.0.0:8080").is_err());
+    assert!(split_listeners("").is_err());
+}
+
+#[test]
+fn test_topic_size_split() {
+    let sizes = split_topic_sizes("orders=2048, audit=512").unwrap();
+
+    assert!(sizes.get("orders") == Some(&2048));
+    assert!(sizes.get("audit") == Some(&512));
+    assert!(split_topic_sizes("orders").is_err());
+    assert!(split_topic_sizes("orders=big").is_err());
+
+    let limits = PayloadLimits { max_size: 1024, topics: sizes };
+    assert!(limits.for_topic("orders") == 2048);
+    assert!(limits.for_topic("anything-else") == 1024);
+}
+
+#[test]
+fn test_read_limited() {
+    assert!(read_limited(&mut &b"hello"[..], 5).unwrap() == Some(b"hello".to_vec()));
+    assert!(read_limited(&mut &b"hello"[..], 4).unwrap() == None);
+    assert!(read_limited(&mut &b""[..], 4).unwrap() == Some(Vec::new()));
+}
+
+#[test]
+fn test_check_json_depth() {
+    let repeat = |text: &str, times: usize| ::std::iter::repeat(text).take(times).collect::<String>();
+    let nested = |depth: usize| format!("{}{}", repeat("[", depth), repeat("]", depth)).into_bytes();
+    assert!(check_json_depth(&nested(MAX_JSON_DEPTH)).is_ok());
+    assert!(check_json_depth(&nested(MAX_JSON_DEPTH + 1)).is_err());
+    assert!(check_json_depth(&nested(200000)).is_err());
+    assert!(check_json_depth(r#"{"a": {"b": [1, {"c": 2}]}, "d": []}"#.as_bytes()).is_ok());
+
+    // Brackets in strings, even after escaped quotes, don't count.
+    let quoted = format!(r#"{{"text": "\"{}"}}"#, repeat("[", MAX_JSON_DEPTH * 2));
+    assert!(check_json_depth(quoted.as_bytes()).is_ok());
+    // Closing brackets only close levels that were opened.
+    let unbalanced = format!("{}{}", repeat("]", 10), repeat("[", MAX_JSON_DEPTH));
+    assert!(check_json_depth(unbalanced.as_bytes()).is_ok());
+}
+
+#[test]
+fn test_schema_registry_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--avro-topics=orders.*, audit",
+        "--schema-registry=http://registry:8081"
+    ]);
+
+    let schema_registry = get_args(matches).unwrap().schema_registry.unwrap();
+
+    assert!(schema_registry.url == "http://registry:8081".to_string());
+    assert!(schema_registry.avro_topics == vec!["orders.*".to_string(), "audit".to_string()]);
+    assert!(schema_registry.schema_dir == None);
+    assert!(schema_registry.cache_seconds == 300);
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--avro-topics=orders.*"
+    ]);
+
+    assert!(get_args(matches).is_err());
+}
+
+#[test]
+fn test_protobuf_topic_split() {
+    let topics = split_protobuf_topics("orders=shop.Order, audit=.audit.Event").unwrap();
+
+    assert!(topics.get("orders") == Some(&"shop.Order".to_string()));
+    assert!(topics.get("audit") == Some(&".audit.Event".to_string()));
+    assert!(split_protobuf_topics("orders").is_err());
+    assert!(split_protobuf_topics("orders=").is_err());
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--protobuf-topics=orders=shop.Order"
+    ]);
+
+    assert!(get_args(matches).is_err());
+
+    let both = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "-b10.0.0.1:9092",
+        "-c/opt/place",
+        "-k/opt/place2",
+        "-p3000",
+        "--protobuf-topics=orders.created=shop.Order",
+        "--protobuf-descriptor-set=/etc/kafka-proxy/messages.desc",
+        "--avro-topics=orders.*",
+        "--schema-registry=http://registry:8081"
+    ]);
+    assert!(get_args(both).is_err());
+}
+
+#[test]
+fn test_fan_out_topic_split() {
+    assert!(split_topics("orders, audit,,orders") == vec!["orders".to_owned(), "audit".to_owned()]);
+    assert!(split_topics(",").is_empty());
+
+    let mirrors = split_mirror_topics("orders.*=audit, payments=audit,payments=ledger").unwrap();
+    assert!(mirrors == vec![("orders.*".to_owned(), "audit".to_owned()), ("payments".to_owned(), "audit".to_owned()),
+        ("payments".to_owned(), "ledger".to_owned())]);
+    assert!(split_mirror_topics("orders").is_err());
+    assert!(split_mirror_topics("orders=audit.*").is_err());
+}
+
+#[test]
+fn test_dead_letter_parsing() {
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "--dead-letter-topic=dead-letters",
+        "--quarantine-dir=/var/lib/kafka-proxy/quarantine",
+    ]);
+    let dead_letter = get_dead_letter_args(&matches).unwrap().unwrap();
+    assert!(dead_letter.max_attempts == 5);
+    assert!(dead_letter.topic == Some("dead-letters".to_owned()));
+    assert!(dead_letter.quarantine_dir == Some(path::PathBuf::from("/var/lib/kafka-proxy/quarantine")));
+    let topic_only = initialize_app().get_matches_from(vec!["kafka-proxy", "--dead-letter-topic=dead-letters"]);
+    assert!(get_dead_letter_args(&topic_only).is_err());
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "--quarantine-dir=/var/lib/kafka-proxy/quarantine",
+        "--max-send-attempts=3",
+    ]);
+    assert!(get_dead_letter_args(&matches).unwrap().unwrap().max_attempts == 3);
+
+    let without_destination = initialize_app().get_matches_from(vec!["kafka-proxy", "--max-send-attempts=3"]);
+    assert!(get_dead_letter_args(&without_destination).is_err());
+    let zero = initialize_app().get_matches_from(vec!["kafka-proxy", "--quarantine-dir=/tmp/quarantine", "--max-send-attempts=0"]);
+    assert!(get_dead_letter_args(&zero).is_err());
+}
+
+#[test]
+fn test_handle_send_failure() {
+    let message = MessagePayload {
+        topic: "orders".to_owned(),
+        payload: b"{}".to_vec(),
+        key: None,
+        metadata: None,
+        attempts: 0,
+        error: None,
+    };
+    let too_large = KafkaError::Kafka(KafkaCode::MessageSizeTooLarge);
+    let timed_out = KafkaError::Kafka(KafkaCode::RequestTimedOut);
+
+    // Without dead-lettering, only messages kafka will never accept are dropped.
+    assert!(handle_send_failure(message.clone(), &too_large, None, None).1 == FailureOutcome::Dropped);
+    let (failed, outcome) = handle_send_failure(message.clone(), &timed_out, None, None);
+    assert!(outcome == FailureOutcome::Retry && failed.attempts == 1);
+
+    // A dead-letter topic can't take records too large for kafka, and they still aren't kept.
+    let topic_only = DeadLetters::new(&DeadLetterConfig {
+        max_attempts: 1,
+        topic: Some("dead-letters".to_owned()),
+        quarantine_dir: None,
+    }).unwrap();
+    assert!(handle_send_failure(message.clone(), &too_large, None, Some(&topic_only)).1 == FailureOutcome::Dropped);
+    assert!(handle_send_failure(message.clone(), &timed_out, None, Some(&topic_only)).1 == FailureOutcome::Retry);
+
+    let quarantine_dir = ::std::env::temp_dir().join("kafka_proxy_test_send_failure");
+    let quarantine = DeadLetters::new(&DeadLetterConfig {
+        max_attempts: 2,
+        topic: None,
+        quarantine_dir: Some(quarantine_dir.clone()),
+    }).unwrap();
+    assert!(handle_send_failure(message.clone(), &too_large, None, Some(&quarantine)).1 == FailureOutcome::DeadLettered);
+    let (failed, outcome) = handle_send_failure(message.clone(), &timed_out, None, Some(&quarantine));
+    assert!(outcome == FailureOutcome::Retry);
+    assert!(handle_send_failure(failed, &timed_out, None, Some(&quarantine)).1 == FailureOutcome::DeadLettered);
+    let _ = ::std::fs::remove_dir_all(&quarantine_dir);
+}
+
+#[test]
+fn test_backup_parsing() {
+    let matches = initialize_app().get_matches_from(vec!["kafka-proxy"]);
+    let backup = get_backup_args(&matches).unwrap();
+    assert!(backup.backend == BackupBackend::Jfs);
+    assert!(backup.dir == path::PathBuf::from("kafka_rust"));
+
+    let matches = initialize_app().get_matches_from(vec![
+        "kafka-proxy",
+        "--backup-store=wal",
+        "--backup-dir=/var/lib/kafka-proxy/wal",
+        "--backup-fsync=250",
+    ]);
+    let backup = get_backup_args(&matches).unwrap();
+    assert!(backup.backend == BackupBackend::Wal { segment_size: 64 * 1024 * 1024, fsync: FsyncPolicy::Interval(Duration::from_millis(250)) });
+
+    let jfs_with_fsync = initialize_app().get_matches_from(vec!["kafka-proxy", "--backup-fsync=never"]);
+    assert!(get_backup_args(&jfs_with_fsync).is_err());
+    let unknown = initialize_app().get_matches_from(vec!["kafka-proxy", "--backup-store=sqlite"]);
+    assert!(get_backup_args(&unknown).is_err());
+}